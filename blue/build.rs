@@ -1,3 +1,8 @@
 fn main() {
-    prost_build::compile_protos(&["src/ipc/messages.proto"], &["src/"]).unwrap();
+    tonic_build::configure()
+        .compile(
+            &["src/ipc/messages.proto", "src/ipc/service.proto"],
+            &["src/"],
+        )
+        .unwrap();
 }
@@ -1,33 +1,211 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
-use std::io::Write;
-use std::net::TcpStream;
+use std::io::{BufRead, ErrorKind, Write};
+use std::str::FromStr;
 
+use prost::Message;
 use structopt::StructOpt;
 
 extern crate blue;
 
 use blue::client::args;
+use blue::client::format::OutputFormat;
 use blue::client::handler::{parse_request, read_client_request};
+use blue::ipc::chunk::{read_chunks, send_chunks, split_set, split_sets};
+use blue::ipc::handshake::handshake;
 use blue::ipc::message;
 use blue::ipc::message::request::Command;
-use blue::ipc::receiver::read_message;
+use blue::ipc::receiver::{read_frame, read_message};
 use blue::ipc::sender::send_message;
+use blue::ipc::tls::{self, Connection, TlsConfig};
+
+fn print_event(event: &message::WatchEvent, output: OutputFormat) {
+    let key = output.format(&event.key);
+    match message::EventType::from_i32(event.event_type) {
+        Some(message::EventType::Set) => println!(
+            "#{} set {}={}",
+            event.sequence,
+            key,
+            output.format(&event.value)
+        ),
+        Some(message::EventType::Delete) => println!("#{} delete {}", event.sequence, key),
+        Some(message::EventType::Expire) => println!("#{} expire {}", event.sequence, key),
+        None => println!("#{} unknown event for {}", event.sequence, key),
+    }
+}
+
+fn print_page(page: &message::ScanResponse, output: OutputFormat, prefix: &str) {
+    for record in &page.records {
+        match record.value_size {
+            0 => println!(
+                "{}{}={}",
+                prefix,
+                output.format(&record.key),
+                output.format(&record.value)
+            ),
+            size => println!(
+                "{}{}=({} bytes, use get)",
+                prefix,
+                output.format(&record.key),
+                size
+            ),
+        }
+    }
+    if !page.next_cursor.is_empty() {
+        println!(
+            "{}(more records: after={})",
+            prefix,
+            output.format(&page.next_cursor)
+        );
+    }
+}
+
+/// Moves the values of a request's sets that are too large for it out, to be sent after it
+fn split_request(request: &mut message::Request) -> Vec<Vec<u8>> {
+    match &mut request.command {
+        Some(Command::Set(set)) => split_set(set).into_iter().collect(),
+        Some(Command::MultiSet(multi_set)) => split_sets(&mut multi_set.sets),
+        _ => Vec::new(),
+    }
+}
+
+fn send_request(mut request: message::Request, stream: &mut dyn Connection) -> io::Result<()> {
+    let values = split_request(&mut request);
+    send_message(request, stream)?;
+    for value in values {
+        send_chunks(&value, stream)?;
+    }
+    Ok(())
+}
+
+/// Reads the values that follow a response in chunks, in the order they belong in
+fn receive_values(response: &mut message::Response, stream: &mut dyn Connection) -> io::Result<()> {
+    if response.chunked {
+        response.value = read_chunks(stream, usize::MAX)?;
+    }
+    for result in response.results.iter_mut().filter(|r| r.chunked) {
+        result.value = read_chunks(stream, usize::MAX)?;
+    }
+    Ok(())
+}
+
+fn print_response(response: &message::Response, getting: bool, output: OutputFormat, prefix: &str) {
+    match getting && response.success && response.results.is_empty() {
+        true => println!("{}{}", prefix, output.format(&response.value)),
+        false => println!("{}{}", prefix, response.message),
+    }
+    for result in &response.results {
+        match result.status == message::Status::Ok as i32 {
+            true if getting => println!(
+                "{}{}={}",
+                prefix,
+                output.format(&result.key),
+                output.format(&result.value)
+            ),
+            true => {}
+            false => println!("{}{}", prefix, result.message),
+        }
+    }
+    if response.retry_after_ms > 0 {
+        println!("{}(retry after {}ms)", prefix, response.retry_after_ms);
+    }
+}
+
+/// What a pipelined request is waiting for
+enum Pending {
+    Response { getting: bool },
+    Scan,
+}
+
+/// Reads the response to whichever pipelined request the node finished next
+fn receive(
+    stream: &mut dyn Connection,
+    pending: &mut HashMap<u64, Pending>,
+    output: OutputFormat,
+) -> io::Result<()> {
+    let frame = read_frame(stream)?;
+    let request_id = message::ResponseHeader::decode(frame.as_slice())?.request_id;
+    let prefix = format!("[{}] ", request_id);
+    match pending.remove(&request_id) {
+        Some(Pending::Scan) => {
+            let page = message::ScanResponse::decode(frame.as_slice())?;
+            print_page(&page, output, &prefix);
+        }
+        Some(Pending::Response { getting }) => {
+            let mut response = message::Response::decode(frame.as_slice())?;
+            receive_values(&mut response, stream)?;
+            print_response(&response, getting, output, &prefix);
+        }
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Response to unknown request #{}", request_id),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Sends every command on stdin, keeping up to `window` of them in flight. Each is numbered by its
+/// line, and its output is labelled with that number as responses can arrive in any order
+fn pipeline(stream: &mut dyn Connection, window: usize, output: OutputFormat) -> io::Result<()> {
+    let mut pending = HashMap::new();
+    for (line, request_id) in io::stdin().lock().lines().zip(1u64..) {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut request = match parse_request(line) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("[{}] {}", request_id, e);
+                continue;
+            }
+        };
+        let kind = match &request.command {
+            Some(Command::Watch(_)) => {
+                println!("[{}] Watches can't be pipelined", request_id);
+                continue;
+            }
+            Some(Command::Scan(_)) => Pending::Scan,
+            Some(Command::Get(get)) => Pending::Response {
+                getting: !get.key.is_empty(),
+            },
+            Some(Command::MultiGet(_)) => Pending::Response { getting: true },
+            _ => Pending::Response { getting: false },
+        };
+        request.request_id = request_id;
+        send_request(request, stream)?;
+        pending.insert(request_id, kind);
+        while pending.len() >= window {
+            receive(stream, &mut pending, output)?;
+        }
+    }
+    while !pending.is_empty() {
+        receive(stream, &mut pending, output)?;
+    }
+    Ok(())
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = args::Opt::from_args();
+    let output = OutputFormat::from_str(opt.output.as_str())
+        .map_err(|_| format!("Unknown output format '{}'", opt.output))?;
     let addr = format!("{}:{}", opt.host, opt.port);
-    let mut stream = TcpStream::connect(addr)?;
-    let initiate = message::Request {
-        command: Some(Command::InitiateSession(message::InitiateSession {
-            name: opt.name,
-        })),
-    };
-    send_message(initiate, &mut stream)?;
-    let welcome = read_message::<message::Welcome>(&mut stream)?;
+    if let Some(ca) = &opt.tls_ca {
+        let identity = opt.tls_cert.as_deref().zip(opt.tls_key.as_deref());
+        tls::configure(TlsConfig::client(ca, identity)?);
+    }
+    let mut stream = tls::connect(&addr)?;
+    let (welcome, _) = handshake(&mut *stream, &opt.name, "")?;
     print!("{}", welcome.message);
     io::stdout().flush()?;
 
+    if opt.pipeline > 0 {
+        return Ok(pipeline(&mut *stream, opt.pipeline, output)?);
+    }
+
     let mut input_num: i32 = 1;
 
     let mut stdin = io::stdin();
@@ -38,9 +216,33 @@ fn main() -> Result<(), Box<dyn Error>> {
         io::stdout().flush()?;
         let user_request = read_client_request(&mut stdin)?;
         let pb = parse_request(user_request.clone())?;
-        send_message(pb, &mut stream)?;
-        let response = read_message::<message::Response>(&mut stream)?;
-        println!("{}", response.message);
+        let watching = matches!(pb.command, Some(Command::Watch(_)));
+        let scanning = matches!(pb.command, Some(Command::Scan(_)));
+        let getting = match &pb.command {
+            Some(Command::Get(get)) => !get.key.is_empty(),
+            Some(Command::MultiGet(_)) => true,
+            _ => false,
+        };
+        send_request(pb, &mut *stream)?;
+        if scanning {
+            let page = read_message::<message::ScanResponse, _>(&mut *stream)?;
+            print_page(&page, output, "");
+            input_num += 1;
+            continue;
+        }
+        let mut response = read_message::<message::Response, _>(&mut *stream)?;
+        receive_values(&mut response, &mut *stream)?;
+        print_response(&response, getting, output, "");
+        if watching && response.success {
+            // The connection now only carries change events
+            loop {
+                let mut event = read_message::<message::WatchEvent, _>(&mut *stream)?;
+                if event.chunked {
+                    event.value = read_chunks(&mut *stream, usize::MAX)?;
+                }
+                print_event(&event, output);
+            }
+        }
         input_num += 1;
     }
 }
@@ -0,0 +1,108 @@
+use std::error::Error;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use structopt::StructOpt;
+
+extern crate blue;
+
+use blue::client::format::OutputFormat;
+use blue::inspect::args::{Command, Opt};
+use blue::inspect::{log, snapshot, wal_dir, Input};
+use blue::store::encryption::Keyring;
+use blue::store::restore::{restore, RestorePoint};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let opt = Opt::from_args();
+    let output = OutputFormat::from_str(opt.output.as_str())
+        .map_err(|_| format!("Unknown output format '{}'", opt.output))?;
+    let keyring = match &opt.key_file {
+        Some(path) => Some(Arc::new(Keyring::load(path)?)),
+        None => None,
+    };
+    let key = keyring.as_deref();
+
+    let sound = match opt.command {
+        Command::Header { path } => {
+            match Input::open(&path)? {
+                Input::Log(segments) => log::print_headers(&segments)?,
+                Input::Snapshot(path) => snapshot::print_header(&path, key)?,
+            }
+            true
+        }
+        Command::Records { path, from, to } => {
+            match Input::open(&path)? {
+                Input::Log(segments) => {
+                    log::print_records(&segments, keyring.as_ref(), from, to, output)?
+                }
+                Input::Snapshot(_) if from.is_some() || to.is_some() => {
+                    return Err("Snapshots don't keep sequences, --from and --to need a WAL".into())
+                }
+                Input::Snapshot(path) => snapshot::print_records(&path, key, output)?,
+            }
+            true
+        }
+        Command::Verify { path } => match Input::open(&path)? {
+            Input::Log(segments) => log::verify(&segments, keyring.as_ref())?,
+            Input::Snapshot(path) => snapshot::verify(&path, key)?,
+        },
+        Command::Dump { path, from, to } => {
+            match Input::open(&path)? {
+                Input::Log(segments) => log::dump(&segments, keyring.as_ref(), from, to, output)?,
+                Input::Snapshot(_) if from.is_some() || to.is_some() => {
+                    return Err("Snapshots don't keep sequences, --from and --to need a WAL".into())
+                }
+                Input::Snapshot(path) => snapshot::dump(&path, key, output)?,
+            }
+            true
+        }
+        Command::Diff { left, right } => match (Input::open(&left)?, Input::open(&right)?) {
+            (Input::Log(left), Input::Log(right)) => {
+                log::diff(&left, &right, keyring.as_ref(), output)?
+            }
+            (Input::Snapshot(left), Input::Snapshot(right)) => {
+                snapshot::diff(&left, &right, key, output)?
+            }
+            _ => return Err("Can only compare two WALs or two snapshots".into()),
+        },
+        Command::Restore {
+            wal,
+            snapshots,
+            sequence,
+            time,
+            out,
+        } => {
+            let point = match (sequence, time) {
+                (Some(sequence), _) => RestorePoint::Sequence(sequence),
+                (None, Some(time)) => RestorePoint::Time(time),
+                (None, None) => return Err("Either --sequence or --time is needed".into()),
+            };
+            let restored = restore(&wal_dir(&wal), &snapshots, point, &out, keyring.clone())?;
+            match restored.snapshot_sequence {
+                Some(snapshot) => println!(
+                    "Restored through #{} from the snapshot at #{} and {} WAL records",
+                    restored.sequence, snapshot, restored.replayed
+                ),
+                None => println!(
+                    "Restored through #{} from {} WAL records",
+                    restored.sequence, restored.replayed
+                ),
+            }
+            println!(
+                "{} keys written to {}. Start a node with `--engine map --data-dir {}`",
+                restored.records,
+                out.display(),
+                out.display()
+            );
+            true
+        }
+    };
+    // Verify and diff exit with a failure when they find a problem or a difference
+    if !sound {
+        process::exit(1);
+    }
+    Ok(())
+}
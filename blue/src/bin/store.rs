@@ -3,6 +3,7 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use log::{debug, info};
 use structopt::StructOpt;
@@ -12,11 +13,21 @@ use tokio::sync::Mutex;
 extern crate blue;
 
 use blue::ipc::message;
+use blue::ipc::message::Compression;
+use blue::ipc::tls::{self, TlsConfig};
 use blue::store::args;
+use blue::store::backup;
 use blue::store::cluster::{Cluster, NodeRole};
-use blue::store::deserialize::deserialize_store;
+use blue::store::data_dir::DataDir;
+use blue::store::encryption::Keyring;
+use blue::store::engine::{open_engine, EngineKind};
+use blue::store::expire::run_reaper;
+use blue::store::grpc::run_grpc_server;
 use blue::store::handler::handle_stream;
-use blue::store::wal::WriteAheadLog;
+use blue::store::http::run_http_listener;
+use blue::store::limits::Limits;
+use blue::store::resp::run_resp_listener;
+use blue::store::wal::{run_pruner, WriteAheadLog};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -26,45 +37,179 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let opt = args::Opt::from_args();
     let addr = SocketAddr::from_str(format!("{}:{}", opt.host, opt.port).as_str())?;
     let role = NodeRole::from_str(opt.role.as_str()).unwrap();
+    let engine = EngineKind::from_str(opt.engine.as_str())
+        .map_err(|_| format!("Unknown storage engine '{}'", opt.engine))?;
+    let compression = Compression::from_str(opt.compression.as_str())
+        .map_err(|_| format!("Unknown compression '{}'", opt.compression))?;
+    let name = addr.to_string().replace(".", "").replace(":", "");
+    let data_dir = DataDir::open(
+        &opt.data_dir
+            .unwrap_or_else(|| PathBuf::from(format!("blue{}", name))),
+    )?;
+    info!(
+        "Node {} using data directory {:?}",
+        data_dir.node_id(),
+        data_dir.path()
+    );
+    let keyring = match &opt.encryption_key_file {
+        Some(path) => {
+            let keyring = Keyring::load(path)?;
+            info!("Encrypting at rest with key {}", keyring.active());
+            Some(Arc::new(keyring))
+        }
+        None => None,
+    };
+
+    data_dir.adopt_legacy_files(&name, keyring.clone())?;
+
     let leader_addr = match role {
         NodeRole::Leader => addr,
-        NodeRole::Follower => SocketAddr::from_str(opt.follow.unwrap().as_str())?,
+        NodeRole::Follower => {
+            let saved = data_dir
+                .cluster_state()?
+                .filter(|state| matches!(NodeRole::from_str(&state.role), Ok(NodeRole::Follower)))
+                .map(|state| state.leader_addr);
+            let follow = opt.follow.or(saved).ok_or("Followers need --follow")?;
+            SocketAddr::from_str(follow.as_str())?
+        }
     };
+    data_dir.save_cluster_state(&message::ClusterState {
+        addr: addr.to_string(),
+        role: opt.role.clone(),
+        leader_addr: leader_addr.to_string(),
+    })?;
 
-    let wal_name = addr.to_string().replace(".", "").replace(":", "");
-    let wal_full_name = format!("wal{}.log", wal_name);
-    let wal_path = PathBuf::from(wal_full_name);
+    let wal_path = data_dir.wal_path();
     let mut wal = match wal_path.exists() {
         true => {
             info!("Existing WAL found");
-            WriteAheadLog::open(&wal_path)?
+            WriteAheadLog::open(&wal_path, keyring.clone())?
         }
         false => {
             info!("Creating WAL");
-            WriteAheadLog::new(&wal_path)?
+            WriteAheadLog::new(&wal_path, keyring.clone())?
         }
     };
+    wal.set_compression(compression);
     debug!("WAL: {:?}", wal);
 
-    let store_name = addr.to_string().replace(".", "").replace(":", "");
-    let store_pth = format!("{}.pb", store_name);
-    let store_path = PathBuf::from(store_pth);
-    // Store is wrapped in Protobuf Message so that it can be serialized to disk
-    let mut store = match store_path.exists() {
-        true => deserialize_store(&store_path)?,
-        false => message::Store::default(),
-    };
+    let mut store = open_engine(engine, &data_dir.store_path(), keyring, compression)?;
+    store.recover(&wal)?;
+
+    if let Some(dir) = &opt.backup_dir {
+        backup::configure(dir.clone());
+        info!("Keeping backups in {:?}", dir);
+    }
+
+    if let (Some(cert), Some(key), Some(ca)) = (&opt.tls_cert, &opt.tls_key, &opt.tls_ca) {
+        tls::configure(TlsConfig::node(cert, key, ca)?);
+        info!("Using TLS, with certificates signed by {:?}", ca);
+    }
 
     let listener = TcpListener::bind(addr).await?;
-    let cluster = Cluster::new(addr, &role, leader_addr, &mut wal, &mut store, &store_path).await?;
+    let resp_listener = match opt.resp_port {
+        Some(port) => {
+            let resp_addr = SocketAddr::from_str(format!("{}:{}", opt.host, port).as_str())?;
+            info!("Listening for RESP clients on {}", resp_addr);
+            Some(TcpListener::bind(resp_addr).await?)
+        }
+        None => None,
+    };
+    let http_listener = match opt.http_port {
+        Some(port) => {
+            let http_addr = SocketAddr::from_str(format!("{}:{}", opt.host, port).as_str())?;
+            info!("Listening for HTTP clients on {}", http_addr);
+            Some(TcpListener::bind(http_addr).await?)
+        }
+        None => None,
+    };
+    let grpc_listener = match opt.grpc_port {
+        Some(port) => {
+            let grpc_addr = SocketAddr::from_str(format!("{}:{}", opt.host, port).as_str())?;
+            info!("Listening for gRPC clients on {}", grpc_addr);
+            Some(TcpListener::bind(grpc_addr).await?)
+        }
+        None => None,
+    };
+    let mut cluster = Cluster::new(
+        addr,
+        &role,
+        leader_addr,
+        &mut wal,
+        store.as_mut(),
+        compression,
+        data_dir.node_id(),
+    )
+    .await?;
+    // Followers learn their leader's RESP and HTTP addresses from the leader, and redirect writes
+    // to them
+    if let NodeRole::Leader = role {
+        if let Some(resp_listener) = &resp_listener {
+            cluster.resp_addr = resp_listener.local_addr()?.to_string();
+        }
+        if let Some(http_listener) = &http_listener {
+            cluster.http_addr = http_listener.local_addr()?.to_string();
+        }
+    }
 
     let role = Arc::new(role);
+    let limits = Arc::new(Limits {
+        max_key_size: opt.max_key_size,
+        max_value_size: opt.max_value_size,
+    });
 
-    let store_path = Arc::new(store_path);
     let store = Arc::new(Mutex::new(store));
 
     let wal = Arc::new(Mutex::new(wal));
     let cluster = Arc::new(Mutex::new(cluster));
+
+    // Only the leader expires keys. Followers learn about expirations through replication so that
+    // every node removes a key at the same point in the WAL
+    if let NodeRole::Leader = *role {
+        tokio::spawn(run_reaper(
+            Duration::from_millis(opt.reap_interval),
+            Arc::clone(&store),
+            Arc::clone(&wal),
+            Arc::clone(&cluster),
+        ));
+    }
+    if opt.wal_prune_interval > 0 {
+        tokio::spawn(run_pruner(
+            Duration::from_millis(opt.wal_prune_interval),
+            Arc::clone(&store),
+            Arc::clone(&wal),
+        ));
+    }
+    if let Some(resp_listener) = resp_listener {
+        tokio::spawn(run_resp_listener(
+            resp_listener,
+            Arc::clone(&store),
+            Arc::clone(&wal),
+            Arc::clone(&cluster),
+            Arc::clone(&role),
+            Arc::clone(&limits),
+        ));
+    }
+    if let Some(http_listener) = http_listener {
+        tokio::spawn(run_http_listener(
+            http_listener,
+            Arc::clone(&store),
+            Arc::clone(&wal),
+            Arc::clone(&cluster),
+            Arc::clone(&role),
+            Arc::clone(&limits),
+        ));
+    }
+    if let Some(grpc_listener) = grpc_listener {
+        tokio::spawn(run_grpc_server(
+            grpc_listener,
+            Arc::clone(&store),
+            Arc::clone(&wal),
+            Arc::clone(&cluster),
+            Arc::clone(&role),
+            Arc::clone(&limits),
+        ));
+    }
     info!("Blue launched. Waiting for incoming connection");
 
     loop {
@@ -72,11 +217,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         info!("Incoming request from {}", addr);
         let role = Arc::clone(&role);
         let store = Arc::clone(&store);
-        let store_path = Arc::clone(&store_path);
         let wal = Arc::clone(&wal);
         let cluster = Arc::clone(&cluster);
-        tokio::spawn(
-            async move { handle_stream(stream, store, store_path, wal, cluster, role).await },
-        );
+        let limits = Arc::clone(&limits);
+        tokio::spawn(async move { handle_stream(stream, store, wal, cluster, role, limits).await });
     }
 }
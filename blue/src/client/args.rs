@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -13,4 +15,27 @@ pub struct Opt {
 
     #[structopt(short = "n", long = "name", default_value = "Unknown")]
     pub name: String,
+
+    /// How keys and values are printed. One of: text, hex, base64. Text falls back to hex for
+    /// anything that isn't printable UTF-8
+    #[structopt(short = "o", long = "output", default_value = "text")]
+    pub output: String,
+
+    /// Reads every command from stdin and sends up to this many before waiting for responses,
+    /// which may arrive out of order. Zero keeps to one command at a time
+    #[structopt(long = "pipeline", default_value = "0")]
+    pub pipeline: usize,
+
+    /// Certificate of the cluster CA, in PEM. Connects over TLS, to a node whose certificate it
+    /// signed
+    #[structopt(long = "tls-ca", parse(from_os_str))]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Certificate to present to the node, in PEM. Only needed if the client acts as a node
+    #[structopt(long = "tls-cert", parse(from_os_str), requires_all = &["tls-key", "tls-ca"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key for `--tls-cert`, in PEM
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    pub tls_key: Option<PathBuf>,
 }
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+
+static HEX_PREFIX: &str = "hex:";
+static BASE64_PREFIX: &str = "base64:";
+static FILE_PREFIX: &str = "file:";
+
+/// Reads a key or value typed by the user. Binary data can be given as `hex:<digits>` or
+/// `base64:<data>`, or read from a file with `file:<path>`. Anything else is taken as UTF-8 text.
+pub fn parse_bytes(token: &str) -> io::Result<Vec<u8>> {
+    if let Some(path) = token.strip_prefix(FILE_PREFIX) {
+        return fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not read {}: {}", path, e)));
+    }
+    decode_bytes(token)
+}
+
+/// Like `parse_bytes`, without reading files. For bytes that come from somewhere other than the
+/// user's own machine
+pub fn decode_bytes(token: &str) -> io::Result<Vec<u8>> {
+    if let Some(digits) = token.strip_prefix(HEX_PREFIX) {
+        return hex::decode(digits)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Invalid hex: {}", e)));
+    }
+    if let Some(data) = token.strip_prefix(BASE64_PREFIX) {
+        return base64::decode(data)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Invalid base64: {}", e)));
+    }
+    Ok(token.as_bytes().to_vec())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Hex,
+    Base64,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<OutputFormat, Self::Err> {
+        match input {
+            "text" | "Text" => Ok(OutputFormat::Text),
+            "hex" | "Hex" => Ok(OutputFormat::Hex),
+            "base64" | "Base64" => Ok(OutputFormat::Base64),
+            _ => Err(()),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Formats bytes so that they can be typed back in as input. Text containing control
+    /// characters is printed as hex so it can't garble the terminal
+    pub fn format(&self, bytes: &[u8]) -> String {
+        let text = std::str::from_utf8(bytes)
+            .ok()
+            .filter(|text| !text.chars().any(char::is_control));
+        match (self, text) {
+            (OutputFormat::Text, Some(text)) => text.to_string(),
+            (OutputFormat::Text, None) | (OutputFormat::Hex, _) => {
+                format!("{}{}", HEX_PREFIX, hex::encode(bytes))
+            }
+            (OutputFormat::Base64, _) => format!("{}{}", BASE64_PREFIX, base64::encode(bytes)),
+        }
+    }
+}
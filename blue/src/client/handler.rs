@@ -2,6 +2,7 @@ use std::io::{self, BufRead, BufReader, ErrorKind, Stdin};
 
 use super::super::ipc::message;
 use super::super::ipc::message::request::Command;
+use super::format::parse_bytes;
 
 pub fn read_client_request(stdin: &mut Stdin) -> io::Result<String> {
     let mut reader = BufReader::new(stdin);
@@ -16,14 +17,22 @@ pub fn parse_request(input: String) -> io::Result<message::Request> {
     // println!("{:?}", command);
     Ok(message::Request {
         command: Some(command),
+        ..Default::default()
     })
 }
 
 fn extract_command(tokens: Vec<&str>) -> io::Result<Command> {
     let command = match tokens[0].trim() {
         "get" | "Get" | "GET" => Ok(get_handler(&tokens)?),
+        "mget" | "Mget" | "MGET" => Ok(multi_get_handler(&tokens)?),
         "set" | "Set" | "SET " => Ok(set_handler(&tokens)?),
-        // "backup" | "Backup" | "BACKUP " => Ok(backup_handler(&tokens)?),
+        "mset" | "Mset" | "MSET" => Ok(multi_set_handler(&tokens)?),
+        "watch" | "Watch" | "WATCH" => Ok(watch_handler(&tokens)?),
+        "scan" | "Scan" | "SCAN" => Ok(scan_handler(&tokens)?),
+        "prefix" | "Prefix" | "PREFIX" => Ok(prefix_handler(&tokens)?),
+        "backup" | "Backup" | "BACKUP" => Ok(backup_handler(&tokens)?),
+        "restore" | "Restore" | "RESTORE" => Ok(restore_handler(&tokens)?),
+        "stats" | "Stats" | "STATS" => Ok(stats_handler(&tokens)?),
         _ => Err(io::Error::new(ErrorKind::InvalidData, "Invalid command")),
     };
     command
@@ -33,7 +42,7 @@ fn get_handler(tokens: &[&str]) -> io::Result<Command> {
     match tokens.len() {
         1 => Ok(Command::Get(message::Get::default())),
         2 => Ok(Command::Get(message::Get {
-            key: tokens[1].trim().to_string(),
+            key: parse_bytes(tokens[1].trim())?,
             write_to_wal: false,
         })),
         _ => Err(io::Error::new(
@@ -43,15 +52,47 @@ fn get_handler(tokens: &[&str]) -> io::Result<Command> {
     }
 }
 
+/// Parses `mget <key> <key>...`
+fn multi_get_handler(tokens: &[&str]) -> io::Result<Command> {
+    let keys = tokens[1..]
+        .iter()
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .map(parse_bytes)
+        .collect::<io::Result<Vec<_>>>()?;
+    match keys.is_empty() {
+        true => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Mget takes at least one key",
+        )),
+        false => Ok(Command::MultiGet(message::MultiGet { keys })),
+    }
+}
+
+fn parse_set(pair: &str, ttl_ms: u64) -> io::Result<message::Set> {
+    let (key, value) = pair
+        .split_once('=')
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Set takes a <key>=<value> pair"))?;
+    Ok(message::Set {
+        key: parse_bytes(key)?,
+        value: parse_bytes(value.trim())?,
+        write_to_wal: true,
+        ttl_ms,
+        expires_at: 0,
+        chunked: false,
+        compression: message::Compression::None as i32,
+        keep_value: false,
+    })
+}
+
 fn set_handler(tokens: &[&str]) -> io::Result<Command> {
     match tokens.len() {
-        2 => {
-            let pairs: Vec<&str> = tokens[1].split('=').collect();
-            Ok(Command::Set(message::Set {
-                key: pairs[0].to_string(),
-                value: pairs[1].trim().to_string(),
-                write_to_wal: true,
-            }))
+        2 | 3 => {
+            let ttl_ms = match tokens.get(2) {
+                Some(ttl) => parse_ttl(ttl)?,
+                None => 0,
+            };
+            Ok(Command::Set(parse_set(tokens[1], ttl_ms)?))
         }
         _ => Err(io::Error::new(
             ErrorKind::InvalidData,
@@ -60,19 +101,167 @@ fn set_handler(tokens: &[&str]) -> io::Result<Command> {
     }
 }
 
-// fn backup_handler(tokens: &Vec<&str>) -> io::Result<Command> {
-//     println!("{:?}", tokens);
-//     match tokens.len() {
-//         2 => {
-//             let addr =
-//                 SocketAddr::from_str(tokens[1].trim()).expect("TODO: Handle poorly formatted addr");
-//             Ok(Command::InitiateBackup(message::InitiateBackup {
-//                 addr: addr.to_string(),
-//             }))
-//         }
-//         _ => Err(io::Error::new(
-//             ErrorKind::InvalidData,
-//             "Too many tokens for get command",
-//         )),
-//     }
-// }
+/// Parses `mset <key>=<value> <key>=<value>...`
+fn multi_set_handler(tokens: &[&str]) -> io::Result<Command> {
+    let sets = tokens[1..]
+        .iter()
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| parse_set(pair, 0))
+        .collect::<io::Result<Vec<_>>>()?;
+    match sets.is_empty() {
+        true => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Mset takes at least one <key>=<value> pair",
+        )),
+        false => Ok(Command::MultiSet(message::MultiSet { sets })),
+    }
+}
+
+/// Parses `watch <key> [from=<sequence>]`. A key ending in `*` watches every key with that prefix
+fn watch_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        2 | 3 => {
+            let key = tokens[1].trim();
+            let from_sequence = match tokens.get(2) {
+                Some(from) => from
+                    .trim()
+                    .strip_prefix("from=")
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            "Starting sequence must be given as from=<sequence>",
+                        )
+                    })?,
+                None => 0,
+            };
+            let watch = match key.strip_suffix('*') {
+                Some(prefix) => message::Watch {
+                    key: parse_bytes(prefix)?,
+                    prefix: true,
+                    from_sequence,
+                },
+                None => message::Watch {
+                    key: parse_bytes(key)?,
+                    prefix: false,
+                    from_sequence,
+                },
+            };
+            Ok(Command::Watch(watch))
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Too many tokens for watch command",
+        )),
+    }
+}
+
+/// Parses `scan <start>..<end> [limit=<n>] [after=<cursor>]`. Either bound may be left empty
+fn scan_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        2..=4 => {
+            let (start, end) = tokens[1].trim().split_once("..").ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Scan range must be given as <start>..<end>",
+                )
+            })?;
+            let mut scan = parse_scan_options(&tokens[2..])?;
+            scan.start = parse_bytes(start)?;
+            scan.end = parse_bytes(end)?;
+            Ok(Command::Scan(scan))
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Too many tokens for scan command",
+        )),
+    }
+}
+
+/// Parses `prefix <prefix> [limit=<n>] [after=<cursor>]`
+fn prefix_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        2..=4 => {
+            let mut scan = parse_scan_options(&tokens[2..])?;
+            scan.prefix = parse_bytes(tokens[1].trim())?;
+            Ok(Command::Scan(scan))
+        }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Too many tokens for prefix command",
+        )),
+    }
+}
+
+fn parse_scan_options(tokens: &[&str]) -> io::Result<message::Scan> {
+    let mut scan = message::Scan::default();
+    for token in tokens {
+        match token.trim().split_once('=') {
+            Some(("limit", limit)) => {
+                scan.limit = limit
+                    .parse::<u32>()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Limit must be a number"))?
+            }
+            Some(("after", cursor)) => scan.cursor = parse_bytes(cursor)?,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Scan options are limit=<n> and after=<cursor>",
+                ))
+            }
+        }
+    }
+    Ok(scan)
+}
+
+/// Parses a `ttl=<seconds>` token into milliseconds
+fn parse_ttl(token: &str) -> io::Result<u64> {
+    let seconds = token
+        .trim()
+        .strip_prefix("ttl=")
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, "TTL must be given as ttl=<seconds>")
+        })?;
+    seconds
+        .checked_mul(1000)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "TTL is too large"))
+}
+
+/// Parses `backup <name>`. The backup is written to a directory of that name in the node's
+/// backup directory, not on the client's filesystem
+fn backup_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        2 => Ok(Command::InitiateBackup(message::InitiateBackup {
+            dir: tokens[1].trim().to_string(),
+        })),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Backup takes exactly one name",
+        )),
+    }
+}
+
+/// Parses `restore <name>`, for a backup in the node's backup directory
+fn restore_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        2 => Ok(Command::RestoreBackup(message::RestoreBackup {
+            dir: tokens[1].trim().to_string(),
+        })),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Restore takes exactly one name",
+        )),
+    }
+}
+
+/// Parses `stats`, which shows how well the node is compressing its data
+fn stats_handler(tokens: &[&str]) -> io::Result<Command> {
+    match tokens.len() {
+        1 => Ok(Command::Stats(message::Stats {})),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Stats takes no arguments",
+        )),
+    }
+}
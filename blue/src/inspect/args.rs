@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "Blue Inspect")]
+pub struct Opt {
+    /// How keys and values are printed. One of: text, hex, base64. Text falls back to hex for
+    /// anything that isn't printable UTF-8
+    #[structopt(short = "o", long = "output", default_value = "text", global = true)]
+    pub output: String,
+
+    /// Key file of the node, needed to read its files if it encrypts them
+    #[structopt(long = "key-file", parse(from_os_str), global = true)]
+    pub key_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+/// Every path may be a data directory, a WAL directory, a single `.log` segment of one or a `.pb`
+/// snapshot. A data directory stands for its WAL
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Print the magic, WAL version, Protocol Buffers version and cipher of each segment
+    Header { path: PathBuf },
+
+    /// Print each record with its sequence
+    Records {
+        path: PathBuf,
+
+        /// First WAL sequence to print
+        #[structopt(long = "from")]
+        from: Option<u64>,
+
+        /// Last WAL sequence to print
+        #[structopt(long = "to")]
+        to: Option<u64>,
+    },
+
+    /// Read everything, checking headers, sequence order and segment indexes
+    Verify { path: PathBuf },
+
+    /// Print records as JSON
+    Dump {
+        path: PathBuf,
+
+        /// First WAL sequence to dump
+        #[structopt(long = "from")]
+        from: Option<u64>,
+
+        /// Last WAL sequence to dump
+        #[structopt(long = "to")]
+        to: Option<u64>,
+    },
+
+    /// Compare the WALs, or the snapshots, of two nodes
+    Diff { left: PathBuf, right: PathBuf },
+
+    /// Rebuild a node's store as it was at a WAL sequence or a point in time, writing it and the
+    /// WAL up to that point to a new data directory to start a node from. The only command that
+    /// writes, and only to the new directory
+    Restore {
+        /// The node's data directory or WAL directory
+        wal: PathBuf,
+
+        /// Snapshots (`.pb` files) or backup directories to start from. The latest one at or
+        /// before the restore point is used and the WAL replayed on top of it
+        #[structopt(long = "snapshot")]
+        snapshots: Vec<PathBuf>,
+
+        /// Restore everything up to and including this sequence
+        #[structopt(long = "sequence", required_unless = "time", conflicts_with = "time")]
+        sequence: Option<u64>,
+
+        /// Restore everything written at or before this many milliseconds since the Unix epoch
+        #[structopt(long = "time")]
+        time: Option<u64>,
+
+        /// Empty or missing data directory to write the restored store and WAL to. It is
+        /// encrypted with `--key-file` if one is given
+        #[structopt(long = "out")]
+        out: PathBuf,
+    },
+}
@@ -0,0 +1,388 @@
+use std::cmp::Ordering;
+use std::io::{self, ErrorKind};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use super::super::client::format::OutputFormat;
+use super::super::ipc::chunk::CHUNK_SIZE;
+use super::super::ipc::message;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::store::encryption::Keyring;
+use super::super::store::wal::{
+    index_path, read_index, read_segment_header, SegmentEntry, SegmentReader, WalItem,
+};
+
+type Records = Box<dyn Iterator<Item = io::Result<WalItem>>>;
+
+/// First sequence of a segment, taken from its file name
+fn first_sequence(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Every entry of a log in order, stopping at the first that can't be read
+fn entries(
+    segments: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+) -> Box<dyn Iterator<Item = io::Result<SegmentEntry>>> {
+    let segments = segments.to_vec();
+    let keyring = keyring.cloned();
+    Box::new(
+        segments
+            .into_iter()
+            .flat_map(
+                move |path| -> Box<dyn Iterator<Item = io::Result<SegmentEntry>>> {
+                    match SegmentReader::open(&path, keyring.clone()) {
+                        Ok(reader) => Box::new(reader),
+                        Err(e) => Box::new(std::iter::once(Err(e))),
+                    }
+                },
+            )
+            .scan(false, |failed, item| match *failed {
+                true => None,
+                false => {
+                    *failed = item.is_err();
+                    Some(item)
+                }
+            }),
+    )
+}
+
+fn records(segments: &[PathBuf], keyring: Option<&Arc<Keyring>>) -> Records {
+    Box::new(
+        entries(segments, keyring).map(|item| item.map(|entry| (entry.sequence, entry.record))),
+    )
+}
+
+fn in_range(sequence: u64, from: Option<u64>, to: Option<u64>) -> bool {
+    from.is_none_or(|from| sequence >= from) && to.is_none_or(|to| sequence <= to)
+}
+
+/// One line describing a record, in the same form the client takes commands
+pub fn describe(record: &message::WalRecord, output: OutputFormat) -> String {
+    match &record.operation {
+        Some(Operation::Set(set)) => {
+            let value = match set.value.len() > CHUNK_SIZE {
+                true => format!("({} bytes)", set.value.len()),
+                false => output.format(&set.value),
+            };
+            match set.expires_at {
+                0 => format!("set {}={}", output.format(&set.key), value),
+                expires_at => format!(
+                    "set {}={} expires_at={}",
+                    output.format(&set.key),
+                    value,
+                    expires_at
+                ),
+            }
+        }
+        Some(Operation::Delete(delete)) => format!("delete {}", output.format(&delete.key)),
+        Some(Operation::Expire(expire)) => format!("expire {}", output.format(&expire.key)),
+        None => "unknown operation".to_string(),
+    }
+}
+
+fn to_json(entry: &SegmentEntry, output: OutputFormat) -> Value {
+    let sequence = entry.sequence;
+    let mut json = match &entry.record.operation {
+        Some(Operation::Set(set)) => json!({
+            "sequence": sequence,
+            "operation": "set",
+            "key": output.format(&set.key),
+            "value": output.format(&set.value),
+            "expires_at": set.expires_at,
+        }),
+        Some(Operation::Delete(delete)) => json!({
+            "sequence": sequence,
+            "operation": "delete",
+            "key": output.format(&delete.key),
+        }),
+        Some(Operation::Expire(expire)) => json!({
+            "sequence": sequence,
+            "operation": "expire",
+            "key": output.format(&expire.key),
+            "expires_at": expire.expires_at,
+        }),
+        None => json!({ "sequence": sequence }),
+    };
+    json["appended_at"] = json!(entry.appended_at);
+    json
+}
+
+pub fn print_headers(segments: &[PathBuf]) -> io::Result<()> {
+    for path in segments {
+        let header = read_segment_header(path)?;
+        println!(
+            "{}: magic {:?}, WAL version {}, Protocol Buffers version {}, {}{}",
+            path.display(),
+            String::from_utf8_lossy(&header.magic),
+            header.wal_version,
+            header.proto_buf_version,
+            match header.is_encrypted() {
+                true => format!("encrypted with cipher {}", header.cipher),
+                false => "unencrypted".to_string(),
+            },
+            match header.is_supported() {
+                true => "",
+                false => " (unsupported)",
+            }
+        );
+    }
+    Ok(())
+}
+
+pub fn print_records(
+    segments: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    from: Option<u64>,
+    to: Option<u64>,
+    output: OutputFormat,
+) -> io::Result<()> {
+    for item in records(segments, keyring) {
+        let (sequence, record) = item?;
+        if to.is_some_and(|to| sequence > to) {
+            break;
+        }
+        if in_range(sequence, from, to) {
+            println!("#{} {}", sequence, describe(&record, output));
+        }
+    }
+    Ok(())
+}
+
+/// Prints records as a JSON array, one element at a time so that large logs aren't held in memory
+pub fn dump(
+    segments: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    from: Option<u64>,
+    to: Option<u64>,
+    output: OutputFormat,
+) -> io::Result<()> {
+    println!("[");
+    let mut first = true;
+    for item in entries(segments, keyring) {
+        let entry = item?;
+        if to.is_some_and(|to| entry.sequence > to) {
+            break;
+        }
+        if !in_range(entry.sequence, from, to) {
+            continue;
+        }
+        if !first {
+            println!(",");
+        }
+        print!("  {}", to_json(&entry, output));
+        first = false;
+    }
+    match first {
+        true => println!("]"),
+        false => println!("\n]"),
+    }
+    Ok(())
+}
+
+/// Reads every segment in full, printing each problem found. Returns whether the log is sound.
+///
+/// A partial record at the end of the last segment is only a warning: it is what a crash
+/// mid-append leaves behind and the store cuts it off on startup.
+pub fn verify(segments: &[PathBuf], keyring: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    let mut problems = 0;
+    let mut records = 0;
+    let mut expected: Option<u64> = None;
+    let mut first_seen = None;
+    for (i, path) in segments.iter().enumerate() {
+        let last_segment = i == segments.len() - 1;
+        let header = read_segment_header(path)?;
+        if !header.is_supported() {
+            println!(
+                "{}: unreadable header, magic {:?} WAL version {}",
+                path.display(),
+                String::from_utf8_lossy(&header.magic),
+                header.wal_version
+            );
+            problems += 1;
+            expected = None;
+            continue;
+        }
+
+        let named = first_sequence(path);
+        // Start offset of every record, to check the index against
+        let mut offsets = Vec::new();
+        let mut reader = SegmentReader::open(path, keyring.cloned())?;
+        while let Some(item) = reader.next() {
+            match item {
+                Ok(SegmentEntry {
+                    offset, sequence, ..
+                }) => {
+                    if offsets.is_empty() && named.is_some_and(|named| named != sequence) {
+                        println!(
+                            "{}: named for #{} but starts at #{}",
+                            path.display(),
+                            named.unwrap(),
+                            sequence
+                        );
+                        problems += 1;
+                    }
+                    if let Some(expected) = expected.filter(|expected| *expected != sequence) {
+                        println!(
+                            "{}: expected #{} at offset {} but found #{}",
+                            path.display(),
+                            expected,
+                            offset,
+                            sequence
+                        );
+                        problems += 1;
+                    }
+                    first_seen.get_or_insert(sequence);
+                    expected = Some(sequence + 1);
+                    offsets.push((sequence, offset));
+                    records += 1;
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && last_segment => {
+                    println!(
+                        "{}: warning, partial record at offset {} will be cut off on startup",
+                        path.display(),
+                        reader.offset()
+                    );
+                }
+                Err(e) => {
+                    println!(
+                        "{}: unreadable record at offset {}: {}",
+                        path.display(),
+                        reader.offset(),
+                        e
+                    );
+                    problems += 1;
+                    expected = None;
+                }
+            }
+        }
+
+        let named = match named {
+            Some(named) => named,
+            None => continue,
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        if !index_path(dir, named).exists() {
+            println!(
+                "{}: warning, no index. It is rebuilt on startup",
+                path.display()
+            );
+            continue;
+        }
+        for (sequence, offset) in read_index(&index_path(dir, named))? {
+            if offsets.binary_search(&(sequence, offset)).is_err() {
+                println!(
+                    "{}: index points #{} at offset {} where no such record starts",
+                    path.display(),
+                    sequence,
+                    offset
+                );
+                problems += 1;
+            }
+        }
+    }
+
+    match (first_seen, expected) {
+        (Some(first), Some(next)) => println!(
+            "{} records in {} segments, #{} to #{}",
+            records,
+            segments.len(),
+            first,
+            next - 1
+        ),
+        _ => println!("{} records in {} segments", records, segments.len()),
+    }
+    match problems {
+        0 => println!("OK"),
+        n => println!("{} problems found", n),
+    }
+    Ok(problems == 0)
+}
+
+fn next_record(records: &mut Peekable<Records>) -> io::Result<Option<WalItem>> {
+    records.next().transpose()
+}
+
+/// Compares two logs record by record. Only the sequences both still hold are compared, as
+/// either node may have pruned older segments. Returns whether they match.
+pub fn diff(
+    left: &[PathBuf],
+    right: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    output: OutputFormat,
+) -> io::Result<bool> {
+    let mut left = records(left, keyring).peekable();
+    let mut right = records(right, keyring).peekable();
+    let start = match (left.peek(), right.peek()) {
+        (Some(Ok((l, _))), Some(Ok((r, _)))) => std::cmp::max(*l, *r),
+        _ => 0,
+    };
+    while left
+        .peek()
+        .is_some_and(|item| matches!(item, Ok((s, _)) if *s < start))
+    {
+        left.next();
+    }
+    while right
+        .peek()
+        .is_some_and(|item| matches!(item, Ok((s, _)) if *s < start))
+    {
+        right.next();
+    }
+
+    let mut differences = 0;
+    let mut first_difference = None;
+    let mut compared = 0;
+    let mut l = next_record(&mut left)?;
+    let mut r = next_record(&mut right)?;
+    loop {
+        let order = match (&l, &r) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((ls, _)), Some((rs, _))) => ls.cmp(rs),
+        };
+        let sequence = match order {
+            Ordering::Equal => {
+                let (sequence, lr) = l.take().unwrap();
+                let (_, rr) = r.take().unwrap();
+                l = next_record(&mut left)?;
+                r = next_record(&mut right)?;
+                compared += 1;
+                if lr == rr {
+                    continue;
+                }
+                println!("#{} differs", sequence);
+                println!("  < {}", describe(&lr, output));
+                println!("  > {}", describe(&rr, output));
+                sequence
+            }
+            Ordering::Less => {
+                let (sequence, lr) = l.take().unwrap();
+                l = next_record(&mut left)?;
+                println!("#{} only on the left: {}", sequence, describe(&lr, output));
+                sequence
+            }
+            Ordering::Greater => {
+                let (sequence, rr) = r.take().unwrap();
+                r = next_record(&mut right)?;
+                println!("#{} only on the right: {}", sequence, describe(&rr, output));
+                sequence
+            }
+        };
+        differences += 1;
+        first_difference.get_or_insert(sequence);
+    }
+
+    match first_difference {
+        Some(sequence) => println!(
+            "{} differences, the logs first diverge at #{}",
+            differences, sequence
+        ),
+        None => println!("The logs match across {} shared records", compared),
+    }
+    Ok(differences == 0)
+}
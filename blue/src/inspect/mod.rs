@@ -0,0 +1,62 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::store::data_dir::wal_path;
+use super::store::wal::{segment_path, segment_sequences};
+
+pub mod args;
+pub mod log;
+pub mod snapshot;
+
+/// A file or directory written by a store
+#[derive(Debug)]
+pub enum Input {
+    /// Segments of a WAL, oldest first
+    Log(Vec<PathBuf>),
+    Snapshot(PathBuf),
+}
+
+/// The WAL directory of a data directory, or the path itself if it isn't one
+pub fn wal_dir(path: &Path) -> PathBuf {
+    match wal_path(path).is_dir() {
+        true => wal_path(path),
+        false => path.to_path_buf(),
+    }
+}
+
+impl Input {
+    pub fn open(path: &Path) -> io::Result<Input> {
+        if path.is_dir() {
+            let path = wal_dir(path);
+            let path = path.as_path();
+            let segments: Vec<PathBuf> = segment_sequences(path)?
+                .into_iter()
+                .map(|first_sequence| segment_path(path, first_sequence))
+                .collect();
+            return match segments.is_empty() {
+                true => Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{:?} holds no WAL segments", path),
+                )),
+                false => Ok(Input::Log(segments)),
+            };
+        }
+        if !path.exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("{:?} does not exist", path),
+            ));
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("log") => Ok(Input::Log(vec![path.to_path_buf()])),
+            Some("pb") => Ok(Input::Snapshot(path.to_path_buf())),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} is neither a WAL directory, a .log segment nor a .pb snapshot",
+                    path
+                ),
+            )),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::cmp::Ordering;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use serde_json::json;
+
+use super::super::client::format::OutputFormat;
+use super::super::ipc::chunk::CHUNK_SIZE;
+use super::super::ipc::message;
+use super::super::store::deserialize::deserialize_store;
+use super::super::store::encryption::Keyring;
+
+fn read(path: &Path, keyring: Option<&Keyring>) -> io::Result<message::Store> {
+    deserialize_store(path, keyring).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Could not decode snapshot {:?}: {}", path, e),
+        )
+    })
+}
+
+fn describe(record: &message::Record, output: OutputFormat) -> String {
+    let value = match record.value.len() > CHUNK_SIZE {
+        true => format!("({} bytes)", record.value.len()),
+        false => output.format(&record.value),
+    };
+    match record.expires_at {
+        0 => format!("{}={}", output.format(&record.key), value),
+        expires_at => format!(
+            "{}={} expires_at={}",
+            output.format(&record.key),
+            value,
+            expires_at
+        ),
+    }
+}
+
+pub fn print_header(path: &Path, keyring: Option<&Keyring>) -> io::Result<()> {
+    let store = read(path, keyring)?;
+    println!(
+        "{}: snapshot of {} records covering the WAL through #{}",
+        path.display(),
+        store.records.len(),
+        store.sequence
+    );
+    Ok(())
+}
+
+pub fn print_records(
+    path: &Path,
+    keyring: Option<&Keyring>,
+    output: OutputFormat,
+) -> io::Result<()> {
+    for record in read(path, keyring)?.records {
+        println!("{}", describe(&record, output));
+    }
+    Ok(())
+}
+
+pub fn dump(path: &Path, keyring: Option<&Keyring>, output: OutputFormat) -> io::Result<()> {
+    let store = read(path, keyring)?;
+    let records: Vec<_> = store
+        .records
+        .iter()
+        .map(|record| {
+            json!({
+                "key": output.format(&record.key),
+                "value": output.format(&record.value),
+                "expires_at": record.expires_at,
+            })
+        })
+        .collect();
+    println!(
+        "{}",
+        json!({ "sequence": store.sequence, "records": records })
+    );
+    Ok(())
+}
+
+/// Checks that the snapshot decodes and that its keys are non empty, unique and in order, as the
+/// map engine writes them. Returns whether the snapshot is sound.
+pub fn verify(path: &Path, keyring: Option<&Keyring>) -> io::Result<bool> {
+    let store = read(path, keyring)?;
+    let mut problems = 0;
+    for (i, record) in store.records.iter().enumerate() {
+        if record.key.is_empty() {
+            println!("Record {} has an empty key", i);
+            problems += 1;
+        }
+        if i > 0 && store.records[i - 1].key >= record.key {
+            println!(
+                "Record {} is out of order or repeats the key before it: {}",
+                i,
+                OutputFormat::Hex.format(&record.key)
+            );
+            problems += 1;
+        }
+    }
+    println!(
+        "{} records covering the WAL through #{}",
+        store.records.len(),
+        store.sequence
+    );
+    match problems {
+        0 => println!("OK"),
+        n => println!("{} problems found", n),
+    }
+    Ok(problems == 0)
+}
+
+/// Compares two snapshots key by key. Returns whether they hold the same records.
+pub fn diff(
+    left: &Path,
+    right: &Path,
+    keyring: Option<&Keyring>,
+    output: OutputFormat,
+) -> io::Result<bool> {
+    let left = read(left, keyring)?;
+    let right = read(right, keyring)?;
+    if left.sequence != right.sequence {
+        println!(
+            "The left snapshot covers the WAL through #{}, the right through #{}",
+            left.sequence, right.sequence
+        );
+    }
+    let mut differences = 0;
+    let mut l = left.records.iter().peekable();
+    let mut r = right.records.iter().peekable();
+    loop {
+        let order = match (l.peek(), r.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(lr), Some(rr)) => lr.key.cmp(&rr.key),
+        };
+        match order {
+            Ordering::Equal => {
+                let (lr, rr) = (l.next().unwrap(), r.next().unwrap());
+                if lr == rr {
+                    continue;
+                }
+                println!("{} differs", output.format(&lr.key));
+                println!("  < {}", describe(lr, output));
+                println!("  > {}", describe(rr, output));
+            }
+            Ordering::Less => {
+                println!("only on the left: {}", describe(l.next().unwrap(), output));
+            }
+            Ordering::Greater => {
+                println!("only on the right: {}", describe(r.next().unwrap(), output));
+            }
+        }
+        differences += 1;
+    }
+    match differences {
+        0 => println!("The snapshots match"),
+        n => println!("{} differences", n),
+    }
+    Ok(differences == 0)
+}
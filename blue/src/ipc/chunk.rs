@@ -0,0 +1,149 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::message;
+use super::receiver::{async_read_message, read_message};
+use super::sender::{async_send_message, send_message};
+
+// Values larger than this are sent and logged as a series of `Chunk`s rather than inside the
+// message that carries their key
+pub static CHUNK_SIZE: usize = 256 * 1024;
+// Values carried by a message holding many keys add up to no more than this, keeping it well
+// under the message size limit. The rest follow the message in chunks
+pub static MAX_INLINE_VALUES: usize = 1024 * 1024;
+
+/// Moves a value too large for one message out of it. The value returned has to follow the
+/// message in chunks.
+pub fn take_large_value(value: &mut Vec<u8>) -> Option<Vec<u8>> {
+    match value.len() > CHUNK_SIZE {
+        true => Some(std::mem::take(value)),
+        false => None,
+    }
+}
+
+/// Moves a value too large for one message out of a set, marking the set as chunked
+pub fn split_set(set: &mut message::Set) -> Option<Vec<u8>> {
+    let value = take_large_value(&mut set.value);
+    set.chunked = value.is_some();
+    value
+}
+
+/// Moves values out of a batch wherever one is too large for a message, or once those left in it
+/// add up to `MAX_INLINE_VALUES`. The values returned have to follow the message in chunks, in
+/// order
+fn split_values<'a>(values: impl Iterator<Item = (&'a mut Vec<u8>, &'a mut bool)>) -> Vec<Vec<u8>> {
+    let mut inline = 0;
+    let mut split = Vec::new();
+    for (value, chunked) in values {
+        *chunked = value.len() > CHUNK_SIZE || inline + value.len() > MAX_INLINE_VALUES;
+        match *chunked {
+            true => split.push(std::mem::take(value)),
+            false => inline += value.len(),
+        }
+    }
+    split
+}
+
+/// Moves values out of a batch of sets, as `split_set` does for one
+pub fn split_sets(sets: &mut [message::Set]) -> Vec<Vec<u8>> {
+    split_values(
+        sets.iter_mut()
+            .map(|set| (&mut set.value, &mut set.chunked)),
+    )
+}
+
+/// Moves values out of the results of a multi-key request
+pub fn split_results(results: &mut [message::KeyResult]) -> Vec<Vec<u8>> {
+    split_values(
+        results
+            .iter_mut()
+            .map(|result| (&mut result.value, &mut result.chunked)),
+    )
+}
+
+/// Splits a value into chunks. An empty value is still sent as a single, last, chunk
+pub fn chunks(value: &[u8]) -> impl Iterator<Item = message::Chunk> + '_ {
+    let count = std::cmp::max(1, value.len().div_ceil(CHUNK_SIZE));
+    (0..count).map(move |i| {
+        let start = i * CHUNK_SIZE;
+        let end = std::cmp::min(start + CHUNK_SIZE, value.len());
+        message::Chunk {
+            data: value[start..end].to_vec(),
+            last: i + 1 == count,
+        }
+    })
+}
+
+/// Reassembles a value from chunks. Chunks past `limit` are still read, so that the stream stays
+/// in step with the sender, but are thrown away and an error is returned once the last arrives.
+#[derive(Debug)]
+pub struct ChunkCollector {
+    value: Vec<u8>,
+    size: usize,
+    limit: usize,
+}
+
+impl ChunkCollector {
+    pub fn new(limit: usize) -> ChunkCollector {
+        ChunkCollector {
+            value: Vec::new(),
+            size: 0,
+            limit,
+        }
+    }
+
+    /// Adds a chunk, returning true once the last chunk has been added
+    pub fn push(&mut self, chunk: message::Chunk) -> bool {
+        self.size += chunk.data.len();
+        if self.size <= self.limit {
+            self.value.extend(chunk.data);
+        }
+        chunk.last
+    }
+
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self.size <= self.limit {
+            true => Ok(self.value),
+            false => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Value is {} bytes, larger than the {} byte limit",
+                    self.size, self.limit
+                ),
+            )),
+        }
+    }
+}
+
+pub async fn async_send_chunks<W>(value: &[u8], stream: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for chunk in chunks(value) {
+        async_send_message(chunk, stream).await?;
+    }
+    Ok(())
+}
+
+pub fn send_chunks<W: Write + ?Sized>(value: &[u8], stream: &mut W) -> io::Result<()> {
+    for chunk in chunks(value) {
+        send_message(chunk, stream)?;
+    }
+    Ok(())
+}
+
+pub async fn async_read_chunks<R>(stream: &mut R, limit: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut collector = ChunkCollector::new(limit);
+    while !collector.push(async_read_message::<message::Chunk, _>(stream).await?) {}
+    collector.finish()
+}
+
+pub fn read_chunks<R: Read + ?Sized>(stream: &mut R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut collector = ChunkCollector::new(limit);
+    while !collector.push(read_message::<message::Chunk, _>(stream)?) {}
+    collector.finish()
+}
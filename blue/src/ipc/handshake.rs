@@ -0,0 +1,136 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::message;
+use super::message::request::Command;
+use super::receiver::{async_read_message, read_message};
+use super::sender::{async_send_message, send_message};
+
+/// Version of the protocol spoken by this build. Bumped whenever messages change in a way an
+/// older peer would misread. Version 1 is the protocol from before versions were exchanged
+pub static PROTOCOL_VERSION: u32 = 2;
+/// Oldest version this build still speaks, so that a cluster can be upgraded one node at a time
+pub static MIN_PROTOCOL_VERSION: u32 = 1;
+/// Optional features this build supports. Only features both sides support are used
+pub static FEATURES: &[&str] = &[
+    "chunked-values",
+    "compression-lz4",
+    "compression-zstd",
+    "watch",
+    "scan",
+    "backup",
+    "stats",
+    "status-codes",
+];
+
+/// What two peers agreed on when opening a session
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub protocol_version: u32,
+    /// The peer's name, or its node ID if it is a node
+    pub peer: String,
+    pub features: Vec<String>,
+}
+
+impl Session {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Peers from before versions were exchanged leave them as zero
+fn or_version_1(version: u32) -> u32 {
+    std::cmp::max(version, 1)
+}
+
+/// The first message of a session, from a client or from a node with `node_id`
+pub fn initiate_session(name: &str, node_id: &str) -> message::Request {
+    message::Request {
+        command: Some(Command::InitiateSession(message::InitiateSession {
+            name: name.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            node_id: node_id.to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        })),
+        ..Default::default()
+    }
+}
+
+/// Agrees on the newest protocol version both sides speak and the features both support. Fails
+/// with the reason to give the peer if the versions they speak don't overlap
+pub fn accept_session(initiate: &message::InitiateSession) -> Result<Session, String> {
+    let newest = or_version_1(initiate.protocol_version);
+    let oldest = or_version_1(initiate.min_protocol_version);
+    if newest < MIN_PROTOCOL_VERSION || oldest > PROTOCOL_VERSION {
+        return Err(format!(
+            "Incompatible protocol: the peer speaks versions {} to {} but this node speaks {} to {}",
+            oldest, newest, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok(Session {
+        protocol_version: std::cmp::min(newest, PROTOCOL_VERSION),
+        peer: match initiate.node_id.is_empty() {
+            true => initiate.name.clone(),
+            false => initiate.node_id.clone(),
+        },
+        features: initiate
+            .features
+            .iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect(),
+    })
+}
+
+/// Checks the node's answer to `initiate_session`
+pub fn check_welcome(welcome: &message::Welcome) -> io::Result<Session> {
+    if welcome.rejected {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Session rejected: {}", welcome.message.trim()),
+        ));
+    }
+    let version = or_version_1(welcome.protocol_version);
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Incompatible protocol: the node chose version {} but this build speaks {} to {}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    Ok(Session {
+        protocol_version: version,
+        peer: welcome.node_id.clone(),
+        features: welcome.features.clone(),
+    })
+}
+
+/// Opens a session on a new connection, returning the node's welcome along with what was agreed
+pub fn handshake<S: Read + Write + ?Sized>(
+    stream: &mut S,
+    name: &str,
+    node_id: &str,
+) -> io::Result<(message::Welcome, Session)> {
+    send_message(initiate_session(name, node_id), stream)?;
+    let welcome = read_message::<message::Welcome, _>(stream)?;
+    let session = check_welcome(&welcome)?;
+    Ok((welcome, session))
+}
+
+pub async fn async_handshake<S>(
+    stream: &mut S,
+    name: &str,
+    node_id: &str,
+) -> io::Result<(message::Welcome, Session)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async_send_message(initiate_session(name, node_id), stream).await?;
+    let welcome = async_read_message::<message::Welcome, _>(stream).await?;
+    let session = check_welcome(&welcome)?;
+    Ok((welcome, session))
+}
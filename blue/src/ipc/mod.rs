@@ -1,5 +1,9 @@
+pub mod chunk;
+pub mod handshake;
 pub mod receiver;
 pub mod sender;
+pub mod status;
+pub mod tls;
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/blue.ipc.message.rs"));
 }
@@ -1,37 +1,65 @@
-use std::io::{self, Read};
+use std::io::{self, ErrorKind, Read};
 use std::net::TcpStream;
 
 use bytes::BytesMut;
 use log::debug;
 
 use prost::{decode_length_delimiter, Message};
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream as asyncTcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub async fn async_read_message<M: Message + Default>(
-    stream: &mut asyncTcpStream,
-) -> io::Result<M> {
+// Largest message accepted off the wire. Values larger than a chunk travel as a series of `Chunk`
+// messages, so only a corrupt or hostile length comes anywhere near this
+pub static MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Checks a length prefix before anything is allocated for the message it describes
+pub fn message_length(len_buf: [u8; 4]) -> io::Result<usize> {
+    let len = i32::from_le_bytes(len_buf);
+    match len >= 0 && len as usize <= MAX_MESSAGE_SIZE {
+        true => Ok(len as usize),
+        false => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Message length {} is outside the {} byte limit",
+                len, MAX_MESSAGE_SIZE
+            ),
+        )),
+    }
+}
+
+pub async fn async_read_message<M, R>(stream: &mut R) -> io::Result<M>
+where
+    M: Message + Default,
+    R: AsyncRead + Unpin,
+{
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).await?;
-    let len = i32::from_le_bytes(len_buf);
-    let mut buf = vec![0u8; len as usize];
+    let mut buf = vec![0u8; message_length(len_buf)?];
     stream.read_exact(&mut buf).await?;
     let user_input = M::decode(&mut buf.as_slice())?;
     debug!("Received message: {:?}", user_input);
     Ok(user_input)
 }
 
-pub fn read_message<M: Message + Default>(stream: &mut TcpStream) -> io::Result<M> {
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
-    let len = i32::from_le_bytes(len_buf);
-    let mut buf = vec![0u8; len as usize];
-    stream.read_exact(&mut buf)?;
+pub fn read_message<M, R>(stream: &mut R) -> io::Result<M>
+where
+    M: Message + Default,
+    R: Read + ?Sized,
+{
+    let buf = read_frame(stream)?;
     let user_input = M::decode(&mut buf.as_slice())?;
     debug!("Received message: {:?}", user_input);
     Ok(user_input)
 }
 
+/// Reads one message without decoding it, for when its type isn't known until part of it is
+pub fn read_frame<R: Read + ?Sized>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let mut buf = vec![0u8; message_length(len_buf)?];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
 pub fn arm(stream: &mut TcpStream) -> io::Result<()> {
     let mut buf = BytesMut::with_capacity(10);
     let read_bytes = stream.peek(&mut buf)?;
@@ -1,37 +1,56 @@
-use std::io::{self, Write};
-use std::net::TcpStream;
+use std::io::{self, ErrorKind, Write};
 
 use log::debug;
 
 use prost::Message;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream as asyncTcpStream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-pub async fn async_send_message<M>(message: M, stream: &mut asyncTcpStream) -> io::Result<()>
+use super::receiver::MAX_MESSAGE_SIZE;
+
+/// Refuses to send what the other side would refuse to read
+fn check_length(length: usize) -> io::Result<()> {
+    match length <= MAX_MESSAGE_SIZE {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Message of {} bytes is larger than the {} byte limit",
+                length, MAX_MESSAGE_SIZE
+            ),
+        )),
+    }
+}
+
+/// The message behind its length, so that both go out in one write rather than the length being
+/// held back waiting for an acknowledgement
+fn frame<M: Message>(message: M, length: i32) -> io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = Vec::with_capacity(4 + length as usize);
+    buf.extend_from_slice(&length.to_le_bytes());
+    message.encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// Writes a length-prefixed message to a socket, or to a buffer that is sent on later
+pub async fn async_send_message<M, W>(message: M, stream: &mut W) -> io::Result<()>
 where
     M: Message,
+    W: AsyncWrite + Unpin,
 {
+    check_length(message.encoded_len())?;
     let length = message.encoded_len() as i32;
-    debug!(
-        "Sending message:\n\t{:?}\nOn Stream:\n\t{:?}",
-        message, stream
-    );
-    let mut buf: Vec<u8> = Vec::with_capacity(length as usize);
-    message.encode(&mut buf)?;
-    stream.write_all(&length.to_le_bytes()).await?;
-    stream.write_all(&buf).await?;
-    Ok(())
+    debug!("Sending message:\n\t{:?}", message);
+    stream.write_all(&frame(message, length)?).await?;
+    stream.flush().await
 }
 
-pub fn send_message<M>(message: M, stream: &mut TcpStream) -> io::Result<()>
+pub fn send_message<M, W>(message: M, stream: &mut W) -> io::Result<()>
 where
     M: Message,
+    W: Write + ?Sized,
 {
+    check_length(message.encoded_len())?;
     let length = message.encoded_len() as i32;
-    debug!("Sending message: {:?} \n\tOn Stream: {:?}", message, stream);
-    let mut buf: Vec<u8> = Vec::with_capacity(length as usize);
-    message.encode(&mut buf)?;
-    stream.write_all(&length.to_le_bytes())?;
-    stream.write_all(&buf)?;
-    Ok(())
+    debug!("Sending message:\n\t{:?}", message);
+    stream.write_all(&frame(message, length)?)?;
+    stream.flush()
 }
@@ -0,0 +1,70 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+
+use super::message;
+use super::message::Status;
+
+/// Suggested wait before retrying a request that failed for a reason that should pass
+pub static RETRY_AFTER_MS: u64 = 1000;
+
+/// A successful response carrying `message`
+pub fn ok(message: String) -> message::Response {
+    message::Response {
+        success: true,
+        message,
+        status: Status::Ok as i32,
+        ..Default::default()
+    }
+}
+
+/// A failed response. Statuses that are worth retrying come with a suggested wait
+pub fn failure(status: Status, message: String) -> message::Response {
+    let retry_after_ms = match status {
+        Status::Timeout | Status::Unavailable => RETRY_AFTER_MS,
+        _ => 0,
+    };
+    message::Response {
+        success: false,
+        message,
+        status: status as i32,
+        retry_after_ms,
+        ..Default::default()
+    }
+}
+
+/// Tells a client that writes go to the leader at `leader_addr`
+pub fn not_leader(leader_addr: SocketAddr) -> message::Response {
+    message::Response {
+        leader_addr: leader_addr.to_string(),
+        ..failure(
+            Status::NotLeader,
+            format!(
+                "Only the leader accepts writes. The leader is {}",
+                leader_addr
+            ),
+        )
+    }
+}
+
+/// The status that best describes an error
+pub fn status_of(e: &io::Error) -> Status {
+    match e.kind() {
+        ErrorKind::NotFound => Status::NotFound,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => Status::InvalidArgument,
+        ErrorKind::TimedOut => Status::Timeout,
+        ErrorKind::PermissionDenied => Status::PermissionDenied,
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::WouldBlock
+        | ErrorKind::Interrupted => Status::Unavailable,
+        _ => Status::Internal,
+    }
+}
+
+/// A failed response describing `e`
+pub fn from_error(e: &io::Error) -> message::Response {
+    failure(status_of(e), e.to_string())
+}
@@ -0,0 +1,226 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerName, StreamOwned,
+};
+use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream as asyncTcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// A blocking connection, over TLS or not
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// An async connection, over TLS or not
+pub trait AsyncConnection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncConnection for T {}
+
+// How this process uses TLS. Unset, every connection is plaintext
+static TLS: OnceLock<TlsConfig> = OnceLock::new();
+
+/// Certificates and keys loaded from PEM files
+pub struct TlsConfig {
+    // Only nodes accept connections
+    server: Option<ServerConfigs>,
+    client: Arc<ClientConfig>,
+}
+
+/// What a node accepts connections with, one for each application protocol a client may ask for
+/// with ALPN. Clients that don't ask get whichever the listener speaks
+struct ServerConfigs {
+    plain: Arc<ServerConfig>,
+    http: Arc<ServerConfig>,
+    grpc: Arc<ServerConfig>,
+}
+
+/// What a listener speaks
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    Blue,
+    Resp,
+    Http,
+    Grpc,
+}
+
+fn invalid(path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    match certificates.is_empty() {
+        true => Err(invalid(path, "No certificates found".to_string())),
+        false => Ok(certificates.into_iter().map(Certificate).collect()),
+    }
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => (),
+            None => return Err(invalid(path, "No private key found".to_string())),
+        }
+    }
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots
+            .add(&certificate)
+            .map_err(|e| invalid(path, e.to_string()))?;
+    }
+    Ok(roots)
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+impl TlsConfig {
+    /// For a node, which presents `certificate` both to those connecting to it and to the nodes
+    /// it connects to. Nodes it connects to must have certificates signed by the CA in `ca`.
+    /// Those connecting to it may go without a certificate, as clients do, but any certificate
+    /// they present must be signed by the CA too
+    pub fn node(certificate: &Path, key: &Path, ca: &Path) -> io::Result<TlsConfig> {
+        let certificates = load_certificates(certificate)?;
+        let key = load_private_key(key)?;
+        let roots = load_roots(ca)?;
+        let mut server = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed(),
+            )
+            .with_single_cert(certificates.clone(), key.clone())
+            .map_err(tls_error)?;
+        // Nodes send a message and close the connection without reading anything. Unread
+        // tickets would make that close a reset, which can drop what was sent before it
+        server.send_tls13_tickets = 0;
+        let mut http = server.clone();
+        http.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let mut grpc = server.clone();
+        grpc.alpn_protocols = vec![b"h2".to_vec()];
+        let client = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certificates, key)
+            .map_err(tls_error)?;
+        Ok(TlsConfig {
+            server: Some(ServerConfigs {
+                plain: Arc::new(server),
+                http: Arc::new(http),
+                grpc: Arc::new(grpc),
+            }),
+            client: Arc::new(client),
+        })
+    }
+
+    /// For a client, which only connects to nodes whose certificates are signed by the CA in
+    /// `ca`. A certificate of its own is only presented if one is given
+    pub fn client(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<TlsConfig> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?);
+        let client = match identity {
+            Some((certificate, key)) => builder
+                .with_client_auth_cert(load_certificates(certificate)?, load_private_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsConfig {
+            server: None,
+            client: Arc::new(client),
+        })
+    }
+}
+
+/// Uses TLS for every connection this process makes or accepts from now on. Only the first call
+/// has any effect
+pub fn configure(config: TlsConfig) {
+    let _ = TLS.set(config);
+}
+
+/// The name the certificate of the node at `addr`, a `host:port`, has to be issued to. Nodes are
+/// addressed by IP, so their certificates need their IP addresses as subject alternative names
+fn server_name(addr: &str) -> io::Result<ServerName> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' is not a valid host name", host),
+        )
+    })
+}
+
+/// Connects to `addr`, over TLS if it is configured. Blocks until the TLS handshake is done, so it
+/// is only for processes without a runtime, such as the client. Nodes use `async_connect`
+pub fn connect(addr: &str) -> io::Result<Box<dyn Connection>> {
+    let stream = TcpStream::connect(addr)?;
+    let tls = match TLS.get() {
+        Some(tls) => tls,
+        None => return Ok(Box::new(stream)),
+    };
+    let connection =
+        ClientConnection::new(Arc::clone(&tls.client), server_name(addr)?).map_err(tls_error)?;
+    let mut stream = StreamOwned::new(connection, stream);
+    // Finish the handshake here, so that a certificate that is turned down fails the connect
+    // rather than whatever is sent first
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(Box::new(stream))
+}
+
+/// Connects to `addr`, over TLS if it is configured
+pub async fn async_connect(addr: &str) -> io::Result<Box<dyn AsyncConnection>> {
+    let stream = asyncTcpStream::connect(addr).await?;
+    match TLS.get() {
+        Some(tls) => {
+            let connector = TlsConnector::from(Arc::clone(&tls.client));
+            Ok(Box::new(
+                connector.connect(server_name(addr)?, stream).await?,
+            ))
+        }
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Accepts a connection to a listener speaking `protocol`, over TLS if it is configured. Also
+/// returns whether the peer can be trusted as a node: it presented a certificate signed by the
+/// CA, or TLS is off and every peer is trusted as before
+pub async fn accept(
+    stream: asyncTcpStream,
+    protocol: Protocol,
+) -> io::Result<(Box<dyn AsyncConnection>, bool)> {
+    let server = TLS.get().and_then(|tls| tls.server.as_ref());
+    match server.map(|server| match protocol {
+        Protocol::Blue | Protocol::Resp => Arc::clone(&server.plain),
+        Protocol::Http => Arc::clone(&server.http),
+        Protocol::Grpc => Arc::clone(&server.grpc),
+    }) {
+        Some(server) => {
+            let stream = TlsAcceptor::from(server).accept(stream).await?;
+            let verified = stream.get_ref().1.peer_certificates().is_some();
+            Ok((Box::new(stream), verified))
+        }
+        None => Ok((Box::new(stream), true)),
+    }
+}
@@ -1,3 +1,4 @@
 pub mod client;
+pub mod inspect;
 pub mod ipc;
 pub mod store;
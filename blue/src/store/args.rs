@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -11,9 +13,83 @@ pub struct Opt {
     #[structopt(short = "p", long = "port", default_value = "7878")]
     pub port: usize,
 
+    /// Port of an optional second listener that speaks RESP, so that Redis clients can use the
+    /// store. Followers redirect writes to their leader's RESP listener with MOVED
+    #[structopt(long = "resp-port")]
+    pub resp_port: Option<usize>,
+
+    /// Port of an optional HTTP listener serving keys as JSON under `/kv/{key}`, along with
+    /// `/cluster` and `/health`. Followers redirect writes to their leader's HTTP listener
+    #[structopt(long = "http-port")]
+    pub http_port: Option<usize>,
+
+    /// Port of an optional gRPC listener serving the `Blue` service from `service.proto`
+    #[structopt(long = "grpc-port")]
+    pub grpc_port: Option<usize>,
+
     #[structopt(short = "r", long = "role", default_value = "leader")]
     pub role: String,
 
-    #[structopt(short = "f", long = "follow", required_if("role", "follower"))]
+    /// Address of the leader to follow. Only needed the first time a follower starts, after that
+    /// the data directory remembers it
+    #[structopt(short = "f", long = "follow")]
     pub follow: Option<String>,
+
+    /// Directory the node keeps all of its files in. Defaults to `blue{$IP Address and Port}` in
+    /// the working directory
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    pub data_dir: Option<PathBuf>,
+
+    /// Storage engine used to keep records. One of: map, lsm, bitcask
+    #[structopt(long = "engine", default_value = "map")]
+    pub engine: String,
+
+    /// File of keys to encrypt the WAL and snapshots with, one `<id> <256 bit key in hex>` per
+    /// line. The last key encrypts new data, the others only decrypt what they encrypted before a
+    /// rotation. Only the map engine supports encryption
+    #[structopt(long = "encryption-key-file", parse(from_os_str))]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// How set values in the WAL and in replication, each on its own, and map engine snapshots are
+    /// compressed.
+    /// One of: none, lz4, zstd. Followers ask their leader for the same
+    #[structopt(long = "compression", default_value = "none")]
+    pub compression: String,
+
+    /// How often, in milliseconds, the leader removes expired keys
+    #[structopt(long = "reap-interval", default_value = "1000")]
+    pub reap_interval: u64,
+
+    /// How often, in milliseconds, WAL segments already covered by the storage engine are
+    /// deleted. Zero keeps the whole WAL. Watches can't read removed segments, and followers
+    /// missing them are sent the whole store instead
+    #[structopt(long = "wal-prune-interval", default_value = "0")]
+    pub wal_prune_interval: u64,
+
+    /// Certificate this node presents, in PEM. With `--tls-key` and `--tls-ca`, every listener
+    /// and all traffic between nodes use TLS. The certificate must name the node's IP address
+    #[structopt(long = "tls-cert", parse(from_os_str), requires_all = &["tls-key", "tls-ca"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key for `--tls-cert`, in PEM
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Certificate of the cluster CA, in PEM. Nodes only follow, replicate to and take node
+    /// requests from peers whose certificates it signed
+    #[structopt(long = "tls-ca", parse(from_os_str), requires = "tls-cert")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Directory `backup` writes backups to and `restore` reads them from, each in a directory of
+    /// its own named in the request. Without it both are refused
+    #[structopt(long = "backup-dir", parse(from_os_str))]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Largest key, in bytes, the leader accepts
+    #[structopt(long = "max-key-size", default_value = "1024")]
+    pub max_key_size: usize,
+
+    /// Largest value, in bytes, the leader accepts
+    #[structopt(long = "max-value-size", default_value = "1048576")]
+    pub max_value_size: usize,
 }
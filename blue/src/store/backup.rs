@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use log::{error, info};
+use prost::Message;
+use tokio::io::AsyncWrite;
+use tokio::sync::Mutex;
+
+use super::super::ipc::message;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::Compression;
+use super::super::ipc::sender::async_send_message;
+use super::super::ipc::status;
+use super::cluster::Cluster;
+use super::compression::decompress_file;
+use super::encryption::{open_file, Keyring};
+use super::engine::{Entry, StorageEngine};
+use super::expire::now_millis;
+use super::serialize::write_atomic;
+use super::wal::{segment_path, segment_sequences, SegmentReader, WalItem, WriteAheadLog};
+
+static SNAPSHOT: &str = "snapshot";
+// Where backups kept the store before it was copied to a log. Only read
+static LEGACY_SNAPSHOT: &str = "snapshot.pb";
+static WAL: &str = "wal";
+static MANIFEST: &str = "MANIFEST";
+// Records copied from the store each time a backup takes its lock
+static COPY_BATCH: usize = 1024;
+
+// Directory every backup is written to and restored from. Unset, backups are turned off
+static BACKUP_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps backups in `dir`. Only the first call has any effect
+pub fn configure(dir: PathBuf) {
+    let _ = BACKUP_ROOT.set(dir);
+}
+
+/// Where the backup called `name` is kept, a directory under the backup root. Names that would
+/// lead out of it, absolute paths and any with `..`, are refused
+fn backup_path(name: &str) -> io::Result<PathBuf> {
+    let root = BACKUP_ROOT.get().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Backups are turned off, the node was started without --backup-dir",
+        )
+    })?;
+    let path = Path::new(name);
+    let confined = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    match confined && path.components().next().is_some() {
+        true => Ok(root.join(path)),
+        false => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' is not a name within the backup directory", name),
+        )),
+    }
+}
+
+fn backup_file(name: String, bytes: &[u8]) -> message::BackupFile {
+    message::BackupFile {
+        name,
+        size: bytes.len() as u64,
+        checksum: crc32fast::hash(bytes),
+    }
+}
+
+/// Syncs every file of a log in the backup and lists them for the manifest, oldest first
+fn log_files(dir: &Path, log: &str) -> io::Result<Vec<message::BackupFile>> {
+    let log_dir = dir.join(log);
+    let mut names: Vec<_> = fs::read_dir(&log_dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let path = log_dir.join(&name);
+            File::open(&path)?.sync_all()?;
+            let name = format!("{}/{}", log, name.to_string_lossy());
+            Ok(backup_file(name, &fs::read(&path)?))
+        })
+        .collect()
+}
+
+/// Starts a backup in an empty or missing directory. Returns the log in `snapshot/` that the
+/// store is copied to, one set per key. Given a keyring it is encrypted, and its values are
+/// compressed like the node's own.
+fn start_backup(
+    dir: &Path,
+    keyring: Option<&Arc<Keyring>>,
+    compression: Compression,
+) -> io::Result<WriteAheadLog> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} is not empty", dir),
+        ));
+    }
+    let mut snapshot = WriteAheadLog::new_at(&dir.join(SNAPSHOT), 1, keyring.cloned())?;
+    snapshot.set_compression(compression);
+    Ok(snapshot)
+}
+
+/// Finishes a backup whose store has been copied, holding every write through `sequence` and
+/// possibly some after it: the WAL records that follow `sequence` go in `wal/`, then a manifest
+/// of checksums. Replaying the records onto the snapshot gives the store as of the last of them,
+/// as each record leaves a key the same however many times it is applied. The manifest is written
+/// last so that a backup cut short is never mistaken for a whole one.
+fn finish_backup<I>(
+    dir: &Path,
+    sequence: u64,
+    records: I,
+    source: &str,
+    keyring: Option<&Arc<Keyring>>,
+    compression: Compression,
+) -> io::Result<message::BackupManifest>
+where
+    I: Iterator<Item = io::Result<WalItem>>,
+{
+    let mut wal = WriteAheadLog::new_at(&dir.join(WAL), sequence + 1, keyring.cloned())?;
+    wal.set_compression(compression);
+    for item in records {
+        wal.append_message(&item?.1)?;
+    }
+    let mut files = log_files(dir, SNAPSHOT)?;
+    files.extend(log_files(dir, WAL)?);
+
+    let manifest = message::BackupManifest {
+        sequence: wal.next_sequence - 1,
+        created_at: now_millis(),
+        source: source.to_string(),
+        files,
+    };
+    write_atomic(&dir.join(MANIFEST), &manifest.encode_to_vec())?;
+    Ok(manifest)
+}
+
+/// Applies a WAL record to records kept by key, as `StorageEngine::apply` does to a store
+fn apply(records: &mut BTreeMap<Vec<u8>, message::Record>, record: message::WalRecord) {
+    match record.operation {
+        Some(Operation::Set(set)) => {
+            let record = message::Record {
+                key: set.key,
+                value: set.value,
+                expires_at: set.expires_at,
+            };
+            records.insert(record.key.clone(), record);
+        }
+        Some(Operation::Delete(delete)) => {
+            records.remove(&delete.key);
+        }
+        Some(Operation::Expire(expire))
+            if records
+                .get(&expire.key)
+                .is_some_and(|record| record.expires_at == expire.expires_at) =>
+        {
+            records.remove(&expire.key);
+        }
+        Some(Operation::Expire(_)) | None => (),
+    }
+}
+
+/// Reads a backup, checking every file against the manifest, and replays its WAL onto its
+/// snapshot. The store returned is as of the manifest's sequence
+pub fn read_backup(
+    dir: &Path,
+    keyring: Option<&Arc<Keyring>>,
+) -> io::Result<(message::BackupManifest, message::Store)> {
+    let manifest = match fs::read(dir.join(MANIFEST)) {
+        Ok(bytes) => message::BackupManifest::decode(bytes.as_slice())?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{:?} has no manifest, the backup is missing or incomplete",
+                    dir
+                ),
+            ))
+        }
+        Err(e) => return Err(e),
+    };
+    for file in &manifest.files {
+        let bytes = fs::read(dir.join(&file.name))?;
+        if bytes.len() as u64 != file.size || crc32fast::hash(&bytes) != file.checksum {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not match the checksum in the manifest", file.name),
+            ));
+        }
+    }
+    // Backups taken before the store was copied to a log held it in a single file
+    let mut records = match dir.join(SNAPSHOT).exists() {
+        true => {
+            let mut records = BTreeMap::new();
+            replay(&dir.join(SNAPSHOT), keyring, &mut records)?;
+            records
+        }
+        false => read_legacy_snapshot(&dir.join(LEGACY_SNAPSHOT), keyring)?,
+    };
+    // Backups taken before they held a WAL are as of the snapshot
+    let wal_dir = dir.join(WAL);
+    if wal_dir.exists() {
+        replay(&wal_dir, keyring, &mut records)?;
+    }
+    let snapshot = message::Store {
+        records: records.into_values().collect(),
+        sequence: manifest.sequence,
+        ..Default::default()
+    };
+    Ok((manifest, snapshot))
+}
+
+/// Applies every record of a log in the backup to records kept by key
+fn replay(
+    dir: &Path,
+    keyring: Option<&Arc<Keyring>>,
+    records: &mut BTreeMap<Vec<u8>, message::Record>,
+) -> io::Result<()> {
+    for first_sequence in segment_sequences(dir)? {
+        let path = segment_path(dir, first_sequence);
+        for entry in SegmentReader::open(&path, keyring.cloned())? {
+            apply(records, entry?.record);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the records of a snapshot written whole to a single file
+fn read_legacy_snapshot(
+    path: &Path,
+    keyring: Option<&Arc<Keyring>>,
+) -> io::Result<BTreeMap<Vec<u8>, message::Record>> {
+    let bytes = decompress_file(open_file(keyring.map(Arc::as_ref), fs::read(path)?)?)?;
+    Ok(message::Store::decode(bytes.as_slice())?
+        .records
+        .into_iter()
+        .map(|record| (record.key.clone(), record))
+        .collect())
+}
+
+async fn respond<W>(stream: &mut W, request_id: u64, result: io::Result<String>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = match result {
+        Ok(message) => status::ok(message),
+        Err(e) => {
+            error!("{}", e);
+            status::from_error(&e)
+        }
+    };
+    let response = message::Response {
+        request_id,
+        ..response
+    };
+    async_send_message(response, stream).await
+}
+
+/// Copies every record of the store to the backup's snapshot, taking the store's lock for one
+/// batch at a time so that writes carry on in between. Each batch is written out before the next
+/// is read, so only one is ever held. Each record is as it was when its batch was copied
+async fn copy_store(
+    store: &Mutex<Box<dyn StorageEngine>>,
+    snapshot: &mut WriteAheadLog,
+) -> io::Result<()> {
+    let mut after: Option<Vec<u8>> = None;
+    loop {
+        let lower = match &after {
+            Some(key) => Bound::Excluded(key.as_slice()),
+            None => Bound::Unbounded,
+        };
+        let mut batch = Vec::with_capacity(COPY_BATCH);
+        store
+            .lock()
+            .await
+            .scan(lower, Bound::Unbounded, &mut |key, entry| {
+                batch.push(message::Set {
+                    key: key.to_vec(),
+                    value: entry.value.clone(),
+                    write_to_wal: true,
+                    expires_at: entry.expires_at,
+                    ..Default::default()
+                });
+                batch.len() < COPY_BATCH
+            })?;
+        let copied = batch.len();
+        after = batch.last().map(|set| set.key.clone());
+        let records: Vec<message::WalRecord> = batch
+            .into_iter()
+            .map(|set| message::WalRecord {
+                operation: Some(Operation::Set(set)),
+                ..Default::default()
+            })
+            .collect();
+        if !records.is_empty() {
+            snapshot.append_messages(&records)?;
+        }
+        if copied < COPY_BATCH {
+            return Ok(());
+        }
+    }
+}
+
+/// Takes a backup without stopping writes. The store is copied a batch at a time, so the copy
+/// holds every write logged before it started and maybe some logged while it ran. The WAL
+/// records logged while it ran are backed up along with it to make up the difference, and are
+/// pinned until then so that pruning can't remove them first
+async fn backup(
+    name: &str,
+    source: SocketAddr,
+    store: &Mutex<Box<dyn StorageEngine>>,
+    wal: &Mutex<WriteAheadLog>,
+) -> io::Result<message::BackupManifest> {
+    let dir = backup_path(name)?;
+    // Writes hold the WAL's lock until they are in the store too, so none is half done here
+    let (sequence, keyring, compression, _pin) = {
+        let wal = wal.lock().await;
+        let keyring = wal.keyring().map(Arc::clone);
+        let pin = wal.pin(wal.next_sequence);
+        (wal.next_sequence - 1, keyring, wal.compression(), pin)
+    };
+    let mut snapshot = start_backup(&dir, keyring.as_ref(), compression)?;
+    copy_store(store, &mut snapshot).await?;
+    let records = {
+        let wal = wal.lock().await;
+        match wal.next_sequence > sequence + 1 {
+            true => Some(wal.iter_from(sequence + 1)?),
+            false => None,
+        }
+    };
+    finish_backup(
+        &dir,
+        sequence,
+        records.into_iter().flatten(),
+        &source.to_string(),
+        keyring.as_ref(),
+        compression,
+    )
+}
+
+pub async fn backup_handler<W>(
+    stream: &mut W,
+    backup_request: message::InitiateBackup,
+    request_id: u64,
+    source: SocketAddr,
+    store: &Mutex<Box<dyn StorageEngine>>,
+    wal: &Mutex<WriteAheadLog>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let name = backup_request.dir;
+    let result = backup(&name, source, store, wal).await.map(|manifest| {
+        info!("Backed up through #{} to {}", manifest.sequence, name);
+        format!(
+            "Backed up through sequence #{} to {}",
+            manifest.sequence, name
+        )
+    });
+    respond(stream, request_id, result).await
+}
+
+fn is_fresh(store: &dyn StorageEngine, wal: &WriteAheadLog) -> io::Result<bool> {
+    let mut empty = true;
+    store.scan(Bound::Unbounded, Bound::Unbounded, &mut |_, _| {
+        empty = false;
+        false
+    })?;
+    Ok(empty && wal.next_sequence == wal.first_sequence())
+}
+
+/// Seeds a fresh leader, and through it every follower, from a backup. Each record is written
+/// and replicated like a set, so the new cluster starts its own WAL rather than carrying on the
+/// old one's sequences. Keys that have expired since the backup was taken are left out.
+async fn restore(
+    restore: &message::RestoreBackup,
+    store: &mut dyn StorageEngine,
+    wal: &mut WriteAheadLog,
+    cluster: &Cluster,
+) -> io::Result<String> {
+    if !is_fresh(store, wal)? {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Backups can only be restored to a node with no records",
+        ));
+    }
+    let (manifest, snapshot) = read_backup(&backup_path(&restore.dir)?, wal.keyring())?;
+    info!(
+        "Restoring backup of {} taken at #{}",
+        manifest.source, manifest.sequence
+    );
+    let now = now_millis();
+    let mut restored = 0;
+    for record in snapshot.records {
+        let entry = Entry {
+            value: record.value,
+            expires_at: record.expires_at,
+        };
+        if entry.is_expired(now) {
+            continue;
+        }
+        let set = message::Set {
+            key: record.key,
+            value: entry.value.clone(),
+            write_to_wal: true,
+            expires_at: entry.expires_at,
+            ..Default::default()
+        };
+        let sequence = wal.next_sequence;
+        wal.append_message(&message::WalRecord {
+            operation: Some(Operation::Set(set.clone())),
+            ..Default::default()
+        })?;
+        store.put(&set.key, entry)?;
+        cluster.replicate_set(&set, sequence).await?;
+        restored += 1;
+    }
+    store.commit(wal.next_sequence - 1)?;
+    Ok(format!(
+        "Restored {} keys from the backup of {} taken at #{}",
+        restored, manifest.source, manifest.sequence
+    ))
+}
+
+pub async fn restore_handler<W>(
+    stream: &mut W,
+    restore_backup: message::RestoreBackup,
+    request_id: u64,
+    store: &mut dyn StorageEngine,
+    wal: &mut WriteAheadLog,
+    cluster: &Cluster,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let result = restore(&restore_backup, store, wal, cluster).await;
+    respond(stream, request_id, result).await
+}
@@ -1,21 +1,26 @@
-use std::io::{self, ErrorKind, Read};
-use std::net::{SocketAddr, TcpStream};
-use std::path::Path;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::ops::Bound;
 use std::str::FromStr;
 
-use log::{error, info};
+use log::{error, info, warn};
 use prost::Message;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream as asyncTcpStream;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::ipc::receiver::{async_read_message, read_message};
-use crate::store::serialize::persist_store;
+use crate::ipc::receiver::async_read_message;
 
+use super::super::ipc::chunk::{async_read_chunks, async_send_chunks, split_set, split_sets};
+use super::super::ipc::handshake::async_handshake;
 use super::super::ipc::message;
 use super::super::ipc::message::request::Command;
-use super::super::ipc::message::{FollowRequest, FollowResponse, Replication};
-use super::super::ipc::sender::{async_send_message, send_message};
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::{Compression, FollowRequest, FollowResponse, Replication, Status};
+use super::super::ipc::sender::async_send_message;
+use super::super::ipc::tls::async_connect;
 use super::super::store::handler::synchronize_handler;
+use super::compression::{compress_set, decompress_set, negotiate, offer, STATS};
+use super::engine::StorageEngine;
 use super::wal::WriteAheadLog;
 
 #[derive(Debug, Clone)]
@@ -41,6 +46,8 @@ pub struct Node {
     pub addr: SocketAddr,
     pub role: NodeRole,
     pub replication: Replication,
+    // Compression agreed with the node for values replicated to it
+    pub compression: Compression,
 }
 
 #[derive(Debug, Clone)]
@@ -48,16 +55,27 @@ pub struct Cluster {
     pub leader: Node,
     pub sync_follower: Option<Node>,
     pub async_followers: Option<Vec<Node>>,
+    // Compression this node asks for, or agrees to when a follower asks for it
+    pub compression: Compression,
+    // This node's ID, from its data directory
+    pub node_id: String,
+    // Address of the leader's RESP listener, where Redis clients are sent to write. Empty if it
+    // has none
+    pub resp_addr: String,
+    // Address of the leader's HTTP listener, where HTTP clients are redirected to write. Empty if
+    // it has none
+    pub http_addr: String,
 }
 
 impl Cluster {
-    pub async fn new<'a>(
+    pub async fn new(
         addr: SocketAddr,
         role: &NodeRole,
         leader: SocketAddr,
         wal: &mut WriteAheadLog,
-        store: &mut message::Store,
-        store_path: &Path,
+        store: &mut dyn StorageEngine,
+        compression: Compression,
+        node_id: &str,
     ) -> io::Result<Cluster> {
         match role {
             NodeRole::Leader => {
@@ -66,24 +84,38 @@ impl Cluster {
                     addr,
                     role: NodeRole::Leader,
                     replication: Replication::Sync,
+                    compression,
                 };
                 Ok(Cluster {
                     leader,
                     sync_follower: None,
                     async_followers: None,
+                    compression,
+                    node_id: node_id.to_string(),
+                    resp_addr: String::new(),
+                    http_addr: String::new(),
                 })
             }
             NodeRole::Follower => {
                 info!("Joining cluster (leader: {:?}) as follower", leader);
+                let mut stream = async_connect(&leader.to_string()).await?;
+                let (_, session) = async_handshake(&mut stream, &addr.to_string(), node_id).await?;
+                info!(
+                    "Leader {} speaks protocol version {}",
+                    session.peer, session.protocol_version
+                );
                 let follow_request = message::Request {
                     command: Some(Command::FollowRequest(FollowRequest {
                         follower_addr: addr.to_string(),
+                        compression: offer(compression, &session) as i32,
                     })),
+                    ..Default::default()
                 };
-                let mut stream = asyncTcpStream::connect(leader).await?;
                 async_send_message(follow_request, &mut stream).await?;
-                let follow_response = async_read_message::<FollowResponse>(&mut stream).await?;
+                let follow_response = async_read_message::<FollowResponse, _>(&mut stream).await?;
                 stream.shutdown().await?;
+                let agreed = negotiate(follow_response.compression);
+                info!("Leader agreed to {:?} compression", agreed);
                 let cluster = match follow_response.replication {
                     // Synchronous
                     0 => {
@@ -91,12 +123,17 @@ impl Cluster {
                             addr: leader,
                             role: NodeRole::Leader,
                             replication: Replication::Sync,
+                            compression: agreed,
                         };
                         // TODO: Add cluster sync and async followers to followers
                         Ok(Cluster {
                             leader,
                             sync_follower: None,
                             async_followers: None,
+                            compression,
+                            node_id: node_id.to_string(),
+                            resp_addr: follow_response.resp_addr.clone(),
+                            http_addr: follow_response.http_addr.clone(),
                         })
                     }
                     // Asynchronous
@@ -105,12 +142,17 @@ impl Cluster {
                             addr: leader,
                             role: NodeRole::Leader,
                             replication: Replication::Async,
+                            compression: agreed,
                         };
                         // TODO: Add cluster sync and async followers to followers
                         Ok(Cluster {
                             leader,
                             sync_follower: None,
                             async_followers: None,
+                            compression,
+                            node_id: node_id.to_string(),
+                            resp_addr: follow_response.resp_addr.clone(),
+                            http_addr: follow_response.http_addr.clone(),
                         })
                     }
                     _ => Err(io::Error::new(
@@ -118,16 +160,41 @@ impl Cluster {
                         "Invalid Cluster config",
                     )),
                 };
-                Cluster::synchronize(leader, wal, store, store_path)?;
+                Cluster::synchronize(addr, leader, wal, store, compression, node_id).await?;
                 cluster
             }
         }
     }
-    pub async fn add_follower(
+
+    /// The cluster as this node sees it. Only the leader knows its followers
+    pub fn to_json(&self) -> Value {
+        json!({
+            "node_id": self.node_id,
+            "leader": self.leader.addr.to_string(),
+            "sync_follower": self.sync_follower.as_ref().map(|node| node.addr.to_string()),
+            "async_followers": self
+                .async_followers
+                .iter()
+                .flatten()
+                .map(|node| node.addr.to_string())
+                .collect::<Vec<String>>(),
+            "compression": format!("{:?}", self.compression).to_lowercase(),
+            "leader_resp_addr": self.resp_addr,
+            "leader_http_addr": self.http_addr,
+        })
+    }
+
+    /// Adds a follower, agreeing to the compression it asked for if this build supports it
+    pub async fn add_follower<W>(
         &mut self,
         addr: SocketAddr,
-        stream: &mut asyncTcpStream,
-    ) -> io::Result<()> {
+        compression: i32,
+        stream: &mut W,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let compression = negotiate(compression);
         // TODO: Handle failure when adding following
         // Sync follower already exists
         match self.sync_follower {
@@ -136,6 +203,7 @@ impl Cluster {
                     addr,
                     role: NodeRole::Follower,
                     replication: Replication::Async,
+                    compression,
                 };
                 info!("Adding async follower: {:?}", node);
                 let followers = self.async_followers.as_mut();
@@ -146,6 +214,9 @@ impl Cluster {
                 let response = FollowResponse {
                     leader: self.leader.addr.to_string(),
                     replication: 1,
+                    compression: compression as i32,
+                    resp_addr: self.resp_addr.clone(),
+                    http_addr: self.http_addr.clone(),
                 };
                 async_send_message(response, stream).await?;
             }
@@ -154,11 +225,15 @@ impl Cluster {
                     addr,
                     role: NodeRole::Follower,
                     replication: Replication::Sync,
+                    compression,
                 };
                 info!("Adding sync follower: {:?}", node);
                 let response = FollowResponse {
                     leader: self.leader.addr.to_string(),
                     replication: 0,
+                    compression: compression as i32,
+                    resp_addr: self.resp_addr.clone(),
+                    http_addr: self.http_addr.clone(),
                 };
                 let r = async_send_message(response, stream).await;
                 match r {
@@ -171,72 +246,234 @@ impl Cluster {
         Ok(())
     }
 
+    /// Sends a message to every follower. A value too large for the message follows it in chunks
     pub async fn replicate<M>(
         message: M,
+        value: Option<&[u8]>,
         sync_follower: &Option<Node>,
         async_followers: &Option<Vec<Node>>,
     ) -> io::Result<()>
     where
         M: Message + Clone,
     {
+        let values: Vec<&[u8]> = value.into_iter().collect();
         if let Some(node) = sync_follower {
-            Cluster::send_to_follower(node, message.clone())?;
+            Cluster::send_to_follower(node, message.clone(), &values).await?;
         }
         if let Some(nodes) = async_followers {
             for node in nodes {
-                Cluster::async_send_to_followers(node, message.clone()).await?;
+                Cluster::async_send_to_followers(node, message.clone(), &values).await?;
             }
         }
 
         Ok(())
     }
 
-    fn send_to_follower<M: Message>(node: &Node, message: M) -> io::Result<()> {
+    /// Sends a set to every follower, its value compressed with whatever each one agreed to
+    pub async fn replicate_set(&self, set: &message::Set, sequence: u64) -> io::Result<()> {
+        if let Some(node) = &self.sync_follower {
+            let (request, value) = self.replicate_set_request(node, set, sequence)?;
+            let values: Vec<&[u8]> = value.as_deref().into_iter().collect();
+            Cluster::send_to_follower(node, request, &values).await?;
+        }
+        if let Some(nodes) = &self.async_followers {
+            for node in nodes {
+                let (request, value) = self.replicate_set_request(node, set, sequence)?;
+                let values: Vec<&[u8]> = value.as_deref().into_iter().collect();
+                Cluster::async_send_to_followers(node, request, &values).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends sets written as one batch to every follower in a single message. They were given
+    /// consecutive sequences starting from `sequence`
+    pub async fn replicate_batch(&self, sets: &[message::Set], sequence: u64) -> io::Result<()> {
+        if let Some(node) = &self.sync_follower {
+            let (request, values) = self.replicate_batch_request(node, sets, sequence)?;
+            let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
+            Cluster::send_to_follower(node, request, &values).await?;
+        }
+        if let Some(nodes) = &self.async_followers {
+            for node in nodes {
+                let (request, values) = self.replicate_batch_request(node, sets, sequence)?;
+                let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
+                Cluster::async_send_to_followers(node, request, &values).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replicate_batch_request(
+        &self,
+        node: &Node,
+        sets: &[message::Set],
+        sequence: u64,
+    ) -> io::Result<(message::Request, Vec<Vec<u8>>)> {
+        let mut sets = sets.to_vec();
+        for set in sets.iter_mut() {
+            compress_set(set, node.compression, &STATS.replication)?;
+        }
+        let values = split_sets(&mut sets);
+        let request = message::Request {
+            command: Some(Command::ReplicateBatch(message::ReplicateBatch {
+                leader_addr: self.leader.addr.to_string(),
+                sequence,
+                sets,
+            })),
+            ..Default::default()
+        };
+        Ok((request, values))
+    }
+
+    fn replicate_set_request(
+        &self,
+        node: &Node,
+        set: &message::Set,
+        sequence: u64,
+    ) -> io::Result<(message::Request, Option<Vec<u8>>)> {
+        let mut set = set.clone();
+        compress_set(&mut set, node.compression, &STATS.replication)?;
+        let value = split_set(&mut set);
+        let request = message::Request {
+            command: Some(Command::ReplicateSet(message::ReplicateSet {
+                leader_addr: self.leader.addr.to_string(),
+                set: Some(set),
+                sequence,
+            })),
+            ..Default::default()
+        };
+        Ok((request, value))
+    }
+
+    /// Sends a message to a follower, followed by the chunks of any values left out of it
+    async fn send_to_follower<M: Message>(
+        node: &Node,
+        message: M,
+        values: &[&[u8]],
+    ) -> io::Result<()> {
         info!("Replicating to: {:?}", node);
-        let mut stream = TcpStream::connect(node.addr)?;
-        send_message(message, &mut stream)?;
+        let mut stream = async_connect(&node.addr.to_string()).await?;
+        async_send_message(message, &mut stream).await?;
+        for value in values {
+            async_send_chunks(value, &mut stream).await?;
+        }
         Ok(())
     }
 
-    async fn async_send_to_followers<M: Message>(node: &Node, message: M) -> io::Result<()> {
+    async fn async_send_to_followers<M: Message>(
+        node: &Node,
+        message: M,
+        values: &[&[u8]],
+    ) -> io::Result<()> {
         info!("Async replicating to: {:?}", node);
-        let mut stream = asyncTcpStream::connect(node.addr).await?;
+        let mut stream = async_connect(&node.addr.to_string()).await?;
         async_send_message(message, &mut stream).await?;
+        for value in values {
+            async_send_chunks(value, &mut stream).await?;
+        }
         Ok(())
     }
 
-    fn synchronize(
+    async fn synchronize(
+        addr: SocketAddr,
         leader: SocketAddr,
         wal: &mut WriteAheadLog,
-        store: &mut message::Store,
-        store_path: &Path,
+        store: &mut dyn StorageEngine,
+        compression: Compression,
+        node_id: &str,
     ) -> io::Result<()> {
         info!("Synchronizing to leader");
-        let mut stream = TcpStream::connect(leader)?;
+        let mut stream = async_connect(&leader.to_string()).await?;
+        let (_, session) = async_handshake(&mut stream, &addr.to_string(), node_id).await?;
         let sync_request = message::Request {
             command: Some(Command::SynchronizeRequest(message::SynchronizeRequest {
                 next_sequence: wal.next_sequence,
+                compression: offer(compression, &session) as i32,
             })),
+            ..Default::default()
         };
-        send_message(sync_request, &mut stream)?;
-        let synchronize_response = read_message::<message::SynchronizeResponse>(&mut stream)?;
+        async_send_message(sync_request, &mut stream).await?;
+        let synchronize_response =
+            async_read_message::<message::SynchronizeResponse, _>(&mut stream).await?;
         let latest_sequence = synchronize_response.latest_sequence;
+        if synchronize_response.status == Status::OutOfRange as i32 {
+            return Cluster::receive_snapshot(
+                &mut stream,
+                wal,
+                store,
+                latest_sequence,
+                synchronize_response.snapshot_records,
+            )
+            .await;
+        }
         if latest_sequence == wal.next_sequence - 1 {
             info!("Already synchronized with leader");
             return Ok(());
         }
         loop {
             let mut seq_bytes = [0u8; 8];
-            stream.read_exact(&mut seq_bytes)?;
+            stream.read_exact(&mut seq_bytes).await?;
             let sequence = u64::from_le_bytes(seq_bytes);
-            let set = read_message::<message::Set>(&mut stream)?;
-            synchronize_handler(&set, store)?;
-            wal.append_message(&set)?;
-            persist_store(store, store_path)?;
+            let record = Cluster::read_synchronized_record(&mut stream).await?;
+            let local_sequence = wal.next_sequence;
+            wal.append_message(&record)?;
+            synchronize_handler(&record, store)?;
+            store.commit(local_sequence)?;
             if sequence == latest_sequence {
                 break;
             }
         }
         Ok(())
     }
+
+    /// Replaces everything this node holds with the leader's store as of `sequence`, for when the
+    /// records it is missing have been pruned from the leader's WAL. The local WAL starts over
+    /// after `sequence`, as its records no longer lead up to what the store holds
+    async fn receive_snapshot<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        wal: &mut WriteAheadLog,
+        store: &mut dyn StorageEngine,
+        sequence: u64,
+        records: u64,
+    ) -> io::Result<()> {
+        warn!(
+            "#{} has been pruned from the leader's WAL, replacing the store with its own as of #{}",
+            wal.next_sequence, sequence
+        );
+        let mut keys = Vec::new();
+        store.scan(Bound::Unbounded, Bound::Unbounded, &mut |key, _| {
+            keys.push(key.to_vec());
+            true
+        })?;
+        for key in keys {
+            store.delete(&key)?;
+        }
+        for _ in 0..records {
+            store.apply(&Cluster::read_synchronized_record(stream).await?)?;
+        }
+        // Flushed before the WAL is reset, as the reset throws away the records an engine would
+        // otherwise recover unflushed changes from. A crash in between leaves a store that is
+        // ahead of its WAL and is simply sent the snapshot again
+        store.flush(sequence)?;
+        wal.reset(sequence + 1)?;
+        info!("Synchronized {} records from leader", records);
+        Ok(())
+    }
+
+    /// Reads a record sent by `synchronize_request_handler`, with its value reassembled and
+    /// decompressed
+    async fn read_synchronized_record<R: AsyncRead + Unpin>(
+        stream: &mut R,
+    ) -> io::Result<message::WalRecord> {
+        let mut record = async_read_message::<message::WalRecord, _>(stream).await?;
+        if let Some(Operation::Set(set)) = &mut record.operation {
+            if set.chunked {
+                set.value = async_read_chunks(stream, usize::MAX).await?;
+                set.chunked = false;
+            }
+            decompress_set(set, usize::MAX)?;
+        }
+        Ok(record)
+    }
 }
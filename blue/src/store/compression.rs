@@ -0,0 +1,236 @@
+use std::io::{self, ErrorKind, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::{json, Value};
+
+use super::super::ipc::handshake::Session;
+use super::super::ipc::message;
+use super::super::ipc::message::Compression;
+
+// Marks a whole file, such as a snapshot, as compressed. Followed by the codec
+static COMPRESSED_MAGIC: &[u8; 4] = b"BLCZ";
+// Values smaller than this aren't worth compressing
+static MIN_COMPRESSED_SIZE: usize = 64;
+static ZSTD_LEVEL: i32 = 3;
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<Compression, Self::Err> {
+        match input {
+            "none" | "None" | "NONE" => Ok(Compression::None),
+            "lz4" | "Lz4" | "LZ4" => Ok(Compression::Lz4),
+            "zstd" | "Zstd" | "ZSTD" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Bytes before and after compression, kept for one kind of data since the node started
+#[derive(Debug)]
+pub struct Ratio {
+    uncompressed: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl Ratio {
+    const fn new() -> Ratio {
+        Ratio {
+            uncompressed: AtomicU64::new(0),
+            compressed: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, uncompressed: usize, compressed: usize) {
+        self.uncompressed
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.compressed
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let uncompressed = self.uncompressed.load(Ordering::Relaxed);
+        let compressed = self.compressed.load(Ordering::Relaxed);
+        let ratio = match compressed {
+            0 => 1.0,
+            compressed => uncompressed as f64 / compressed as f64,
+        };
+        json!({
+            "uncompressed_bytes": uncompressed,
+            "compressed_bytes": compressed,
+            "ratio": ratio,
+        })
+    }
+}
+
+/// How well compression is doing for each kind of data the node compresses. Only data written
+/// while compression is on is counted
+#[derive(Debug)]
+pub struct CompressionStats {
+    pub wal: Ratio,
+    pub snapshot: Ratio,
+    pub replication: Ratio,
+}
+
+pub static STATS: CompressionStats = CompressionStats {
+    wal: Ratio::new(),
+    snapshot: Ratio::new(),
+    replication: Ratio::new(),
+};
+
+impl CompressionStats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "wal": self.wal.to_json(),
+            "snapshot": self.snapshot.to_json(),
+            "replication": self.replication.to_json(),
+        })
+    }
+}
+
+/// The compression to use with a peer that asked for `requested`, a `Compression` as sent on the
+/// wire. Anything this build doesn't know is refused in favour of none
+pub fn negotiate(requested: i32) -> Compression {
+    Compression::from_i32(requested).unwrap_or(Compression::None)
+}
+
+/// The handshake feature of a peer that can read values compressed with `compression`
+fn feature(compression: Compression) -> Option<&'static str> {
+    match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some("compression-lz4"),
+        Compression::Zstd => Some("compression-zstd"),
+    }
+}
+
+/// The compression to ask a peer for: `compression` if both sides listed it among the features
+/// agreed in the handshake, otherwise none
+pub fn offer(compression: Compression, session: &Session) -> Compression {
+    match feature(compression) {
+        Some(feature) if session.supports(feature) => compression,
+        _ => Compression::None,
+    }
+}
+
+fn too_large(limit: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Value decompresses to more than the {} byte limit", limit),
+    )
+}
+
+pub fn compress(compression: Compression, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(bytes.to_vec()),
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+        Compression::Zstd => zstd::encode_all(bytes, ZSTD_LEVEL),
+    }
+}
+
+/// Reverses `compress`, refusing to produce more than `limit` bytes so that a small hostile
+/// message can't take up all of memory
+pub fn decompress(compression: Compression, bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(bytes.to_vec()),
+        Compression::Lz4 => {
+            // LZ4 blocks start with their decompressed size
+            let mut size = [0u8; 4];
+            size.copy_from_slice(
+                bytes.get(..4).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "LZ4 block is truncated")
+                })?,
+            );
+            if u32::from_le_bytes(size) as usize > limit {
+                return Err(too_large(limit));
+            }
+            lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+        }
+        Compression::Zstd => {
+            let mut value = Vec::new();
+            // Reading from memory only fails on bytes that aren't valid zstd
+            zstd::Decoder::new(bytes)?
+                .take((limit as u64).saturating_add(1))
+                .read_to_end(&mut value)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            match value.len() > limit {
+                true => Err(too_large(limit)),
+                false => Ok(value),
+            }
+        }
+    }
+}
+
+/// Compresses a set's value in place, unless it is too small to bother with or wouldn't get any
+/// smaller. Either way the set's `compression` says what was done
+pub fn compress_set(
+    set: &mut message::Set,
+    compression: Compression,
+    ratio: &Ratio,
+) -> io::Result<()> {
+    if compression == Compression::None || set.compression != Compression::None as i32 {
+        return Ok(());
+    }
+    let size = set.value.len();
+    if size >= MIN_COMPRESSED_SIZE {
+        let compressed = compress(compression, &set.value)?;
+        if compressed.len() < size {
+            set.value = compressed;
+            set.compression = compression as i32;
+        }
+    }
+    ratio.record(size, set.value.len());
+    Ok(())
+}
+
+/// Restores a value compressed by `compress_set`
+pub fn decompress_set(set: &mut message::Set, limit: usize) -> io::Result<()> {
+    let compression = Compression::from_i32(set.compression).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown compression {}", set.compression),
+        )
+    })?;
+    if compression != Compression::None {
+        set.value = decompress(compression, &set.value, limit)?;
+        set.compression = Compression::None as i32;
+    }
+    Ok(())
+}
+
+/// Wraps a whole file's contents, compressing them unless compression is off
+pub fn compress_file(
+    compression: Compression,
+    bytes: Vec<u8>,
+    ratio: &Ratio,
+) -> io::Result<Vec<u8>> {
+    if compression == Compression::None {
+        return Ok(bytes);
+    }
+    let mut compressed = COMPRESSED_MAGIC.to_vec();
+    compressed.push(compression as u8);
+    compressed.extend(compress(compression, &bytes)?);
+    ratio.record(bytes.len(), compressed.len());
+    Ok(compressed)
+}
+
+/// Unwraps what `compress_file` wrote. Uncompressed files are passed through, so that
+/// compression can be turned on or off for a node that already has data
+pub fn decompress_file(bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !bytes.starts_with(COMPRESSED_MAGIC) {
+        return Ok(bytes);
+    }
+    let codec = bytes.get(COMPRESSED_MAGIC.len()).copied().unwrap_or(0);
+    match Compression::from_i32(codec as i32) {
+        Some(compression) if compression != Compression::None => decompress(
+            compression,
+            &bytes[COMPRESSED_MAGIC.len() + 1..],
+            usize::MAX,
+        ),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown compression {}", codec),
+        )),
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use log::{info, warn};
+use prost::Message;
+
+use super::super::ipc::message;
+use super::encryption::Keyring;
+use super::expire::now_millis;
+use super::serialize::write_atomic;
+use super::wal::convert_legacy_log;
+
+static LAYOUT_VERSION: u32 = 1;
+static NODE: &str = "NODE";
+static LOCK: &str = "LOCK";
+static WAL: &str = "wal";
+// Engines add their own extension: `store.pb`, `store.lsm` or `store.bitcask`
+static STORE: &str = "store.pb";
+static CLUSTER: &str = "cluster.pb";
+
+/// Path of the WAL in a data directory
+pub fn wal_path(dir: &Path) -> PathBuf {
+    dir.join(WAL)
+}
+
+/// Path the storage engine keeps its files at in a data directory
+pub fn store_path(dir: &Path) -> PathBuf {
+    dir.join(STORE)
+}
+
+/// A random identifier, drawing on the per process random keys std seeds its hash maps with
+fn new_node_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now_millis());
+    hasher.write_u32(process::id());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Everything a node keeps on disk, in one directory that doesn't depend on the node's address:
+///
+/// - `NODE`: the node's ID and the layout version
+/// - `LOCK`: held for as long as a process has the directory open
+/// - `wal/`: WAL segments
+/// - `store.pb`, `store.lsm/` or `store.bitcask/`: the storage engine's files
+/// - `cluster.pb`: the node's role and leader when it last started
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    metadata: message::NodeMetadata,
+    // Released when the process exits
+    _lock: File,
+}
+
+impl DataDir {
+    /// Opens a data directory, creating it and a node ID the first time. Fails if another
+    /// process has it open.
+    pub fn open(path: &Path) -> io::Result<DataDir> {
+        fs::create_dir_all(path)?;
+        let mut lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOCK))?;
+        match lock.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("{:?} is in use by another process", path),
+                ))
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        // The pid is only there for whoever wonders who holds the lock
+        lock.set_len(0)?;
+        writeln!(lock, "{}", process::id())?;
+
+        let metadata = match fs::read(path.join(NODE)) {
+            Ok(bytes) => message::NodeMetadata::decode(bytes.as_slice())?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let metadata = message::NodeMetadata {
+                    node_id: new_node_id(),
+                    layout_version: LAYOUT_VERSION,
+                    created_at: now_millis(),
+                };
+                write_atomic(&path.join(NODE), &metadata.encode_to_vec())?;
+                info!("Created data directory {:?}", path);
+                metadata
+            }
+            Err(e) => return Err(e),
+        };
+        if metadata.layout_version != LAYOUT_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{:?} has layout version {} but only version {} can be read",
+                    path, metadata.layout_version, LAYOUT_VERSION
+                ),
+            ));
+        }
+        Ok(DataDir {
+            path: path.to_path_buf(),
+            metadata,
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.metadata.node_id
+    }
+
+    pub fn wal_path(&self) -> PathBuf {
+        wal_path(&self.path)
+    }
+
+    pub fn store_path(&self) -> PathBuf {
+        store_path(&self.path)
+    }
+
+    /// Moves files kept in the working directory by nodes from before data directories, named
+    /// after the node's address, into this one. A WAL from before segments, a single `.log` file,
+    /// is converted into segments rather than moved, and the file is then renamed so that it is
+    /// kept but never read again. Does nothing once the directory has a WAL.
+    pub fn adopt_legacy_files(&self, name: &str, keyring: Option<Arc<Keyring>>) -> io::Result<()> {
+        if self.wal_path().exists() {
+            return Ok(());
+        }
+        let store_path = self.store_path();
+        let legacy = [
+            (PathBuf::from(format!("wal{}", name)), self.wal_path()),
+            (PathBuf::from(format!("{}.pb", name)), store_path.clone()),
+            (
+                PathBuf::from(format!("{}.lsm", name)),
+                store_path.with_extension("lsm"),
+            ),
+            (
+                PathBuf::from(format!("{}.bitcask", name)),
+                store_path.with_extension("bitcask"),
+            ),
+        ];
+        for (from, to) in legacy.iter() {
+            if from.exists() {
+                info!("Moving {:?} to {:?}", from, to);
+                fs::rename(from, to)?;
+            }
+        }
+        // Last, so that the WAL only exists once everything else has been moved
+        let legacy_log = PathBuf::from(format!("wal{}.log", name));
+        if legacy_log.exists() {
+            match self.wal_path().exists() {
+                true => warn!("Ignoring {:?}, a WAL was moved in already", legacy_log),
+                false => {
+                    convert_legacy_log(&legacy_log, &self.wal_path(), keyring)?;
+                    fs::rename(&legacy_log, legacy_log.with_extension("log.converted"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the node sat in its cluster when it last started
+    pub fn cluster_state(&self) -> io::Result<Option<message::ClusterState>> {
+        match fs::read(self.path.join(CLUSTER)) {
+            Ok(bytes) => Ok(Some(message::ClusterState::decode(bytes.as_slice())?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_cluster_state(&self, state: &message::ClusterState) -> io::Result<()> {
+        write_atomic(&self.path.join(CLUSTER), &state.encode_to_vec())
+    }
+}
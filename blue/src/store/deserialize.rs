@@ -1,18 +1,40 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::path::Path;
 
 use prost::Message;
 
 use super::super::ipc::message;
+use super::compression::decompress_file;
+use super::encryption::{open_file, Keyring};
 
-pub fn deserialize_store(path: &Path) -> Result<message::Store, prost::DecodeError> {
+/// Reads a snapshot, decrypting and decompressing it if it was written that way
+pub fn deserialize_store(path: &Path, keyring: Option<&Keyring>) -> io::Result<message::Store> {
     let store = match path.exists() {
         true => {
-            let existing_store = fs::read(&path).unwrap();
-            message::Store::decode(&mut Cursor::new(existing_store.as_slice()))
+            let existing_store = decompress_file(open_file(keyring, fs::read(path)?)?)?;
+            message::Store::decode(&mut Cursor::new(existing_store.as_slice()))?
         }
-        false => Ok(message::Store::default()),
+        false => message::Store::default(),
     };
+    Ok(fold_expirations(store))
+}
+
+/// Moves the expiries of a snapshot written before records carried their own onto its records.
+/// Those snapshots came from maps, so their records aren't in key order
+fn fold_expirations(mut store: message::Store) -> message::Store {
+    if store.expirations.is_empty() {
+        return store;
+    }
+    let expirations: HashMap<Vec<u8>, u64> = std::mem::take(&mut store.expirations)
+        .into_iter()
+        .map(|expiration| (expiration.key, expiration.expires_at))
+        .collect();
+    for record in store.records.iter_mut() {
+        if let Some(expires_at) = expirations.get(&record.key) {
+            record.expires_at = *expires_at;
+        }
+    }
     store
 }
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+/// Cipher ids recorded in WAL segment headers and encrypted files
+pub static CIPHER_NONE: u8 = 0;
+// Sealed without associated data. Still read, but no longer written
+pub static CIPHER_XCHACHA20_POLY1305: u8 = 1;
+// Sealed with the key id, the kind of data and, for a WAL frame, where it is as associated data
+pub static CIPHER_XCHACHA20_POLY1305_BOUND: u8 = 2;
+
+// Marks a whole file, such as a snapshot, as encrypted. Followed by the cipher id
+static SEALED_MAGIC: &[u8; 4] = b"BLSE";
+static KEY_ID_SIZE: usize = 4;
+static NONCE_SIZE: usize = 24;
+static KEY_SIZE: usize = 32;
+// Kinds of sealed data, bound into it so that one can't be passed off as the other
+static SNAPSHOT_KIND: &[u8] = b"snapshot";
+static WAL_FRAME_KIND: &[u8] = b"wal frame";
+
+/// The keys a node encrypts and decrypts its files with, loaded from a key file.
+///
+/// Each line of the key file is a key id and a 256 bit key in hex, e.g.
+/// `1 00112233...`. The last line is the key new data is encrypted with, the others are only
+/// kept to read data written before a rotation. Blank lines and lines starting with `#` are
+/// ignored.
+pub struct Keyring {
+    ciphers: BTreeMap<u32, XChaCha20Poly1305>,
+    active: u32,
+}
+
+impl std::fmt::Debug for Keyring {
+    // Never print the keys themselves
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("keys", &self.ciphers.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> io::Result<Keyring> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not read {:?}: {}", path, e)))?;
+        let mut ciphers = BTreeMap::new();
+        let mut active = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                invalid(format!("Line {} of {:?} is not <id> <key>", i + 1, path))
+            })?;
+            let id: u32 = id
+                .parse()
+                .map_err(|_| invalid(format!("Line {} of {:?} has an invalid id", i + 1, path)))?;
+            let key = hex::decode(key.trim())
+                .ok()
+                .filter(|key| key.len() == KEY_SIZE)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Line {} of {:?} must hold a {} byte key in hex",
+                        i + 1,
+                        path,
+                        KEY_SIZE
+                    ))
+                })?;
+            if ciphers
+                .insert(id, XChaCha20Poly1305::new(Key::from_slice(&key)))
+                .is_some()
+            {
+                return Err(invalid(format!("Key {} appears twice in {:?}", id, path)));
+            }
+            active = Some(id);
+        }
+        let active = active.ok_or_else(|| invalid(format!("{:?} holds no keys", path)))?;
+        Ok(Keyring { ciphers, active })
+    }
+
+    /// Id of the key new data is encrypted with
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    /// Encrypts with the active key. The result carries the key id and a random nonce, and is
+    /// bound to both the key id and `context`, which `open` must be given again
+    pub fn seal(&self, plaintext: &[u8], context: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(self.active, context);
+        let ciphertext = self.ciphers[&self.active]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        let mut sealed = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+        sealed.extend(self.active.to_le_bytes());
+        sealed.extend(nonce.as_slice());
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts and authenticates what `seal` produced, with whichever key it was sealed with.
+    /// `context` is `None` for data sealed before it was bound to anything
+    pub fn open(&self, sealed: &[u8], context: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        if sealed.len() < KEY_ID_SIZE + NONCE_SIZE {
+            return Err(invalid("Encrypted data is truncated".to_string()));
+        }
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&sealed[..KEY_ID_SIZE]);
+        let id = u32::from_le_bytes(id);
+        // A missing key is a problem with the key file, not with the data
+        let cipher = self.ciphers.get(&id).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Data is encrypted with key {} which is not in the key file",
+                    id
+                ),
+            )
+        })?;
+        let nonce = XNonce::from_slice(&sealed[KEY_ID_SIZE..KEY_ID_SIZE + NONCE_SIZE]);
+        let aad = match context {
+            Some(context) => associated_data(id, context),
+            None => Vec::new(),
+        };
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &sealed[KEY_ID_SIZE + NONCE_SIZE..],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                invalid(format!(
+                    "Data encrypted with key {} failed to authenticate",
+                    id
+                ))
+            })
+    }
+}
+
+fn associated_data(key_id: u32, context: &[u8]) -> Vec<u8> {
+    let mut aad = key_id.to_le_bytes().to_vec();
+    aad.extend(context);
+    aad
+}
+
+/// What a WAL frame is sealed with besides its key id: the segment it is in, by first sequence,
+/// and the offset it starts at. A frame copied anywhere else, or into a snapshot, fails to open
+pub fn wal_frame_context(segment: u64, offset: u64) -> Vec<u8> {
+    let mut context = WAL_FRAME_KIND.to_vec();
+    context.extend(segment.to_le_bytes());
+    context.extend(offset.to_le_bytes());
+    context
+}
+
+/// Wraps a whole file's contents, encrypting them when there is a keyring. Only snapshots are
+/// sealed whole, and they are sealed as such
+pub fn seal_file(keyring: Option<&Keyring>, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    match keyring {
+        Some(keyring) => {
+            let mut sealed = SEALED_MAGIC.to_vec();
+            sealed.push(CIPHER_XCHACHA20_POLY1305_BOUND);
+            sealed.extend(keyring.seal(&bytes, SNAPSHOT_KIND)?);
+            Ok(sealed)
+        }
+        None => Ok(bytes),
+    }
+}
+
+/// Unwraps what `seal_file` wrote. Unencrypted files are passed through, so that encryption can
+/// be turned on for a node that already has data
+pub fn open_file(keyring: Option<&Keyring>, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !bytes.starts_with(SEALED_MAGIC) {
+        return Ok(bytes);
+    }
+    let keyring = keyring.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "File is encrypted but no key file was given",
+        )
+    })?;
+    match bytes.get(SEALED_MAGIC.len()) {
+        Some(cipher) if *cipher == CIPHER_XCHACHA20_POLY1305 => {
+            keyring.open(&bytes[SEALED_MAGIC.len() + 1..], None)
+        }
+        Some(cipher) if *cipher == CIPHER_XCHACHA20_POLY1305_BOUND => {
+            keyring.open(&bytes[SEALED_MAGIC.len() + 1..], Some(SNAPSHOT_KIND))
+        }
+        cipher => Err(invalid(format!("Unknown cipher {:?}", cipher))),
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{info, warn};
+use prost::Message;
+
+use super::super::super::ipc::message;
+use super::super::super::ipc::message::wal_record::Operation;
+use super::super::wal::{read_length_delimited, read_record, write_record, WriteAheadLog};
+use super::{is_empty_range, Entry, StorageEngine};
+
+// Size at which the active data file is closed and a new one started
+static MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// Merge once this many bytes are taken up by overwritten or deleted values, as long as they are at
+// least half of all data
+static MERGE_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+fn data_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.data", id))
+}
+
+fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.hint", id))
+}
+
+/// Where the merged output for `id` is written before it replaces the merged files
+fn merge_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".merge");
+    PathBuf::from(name)
+}
+
+/// Ids of the data files in `dir`, oldest first
+fn data_file_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("data") {
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Finishes a merge that was interrupted after its output was complete, or throws away one that
+/// wasn't. The merge's hint file is renamed into place last, so its presence marks completion.
+fn finish_merge(dir: &Path, target: u64) -> io::Result<()> {
+    let merged_data = merge_path(&data_path(dir, target));
+    let merged_hint = merge_path(&hint_path(dir, target));
+    if !merged_hint.exists() {
+        return fs::remove_file(&merged_data);
+    }
+    for id in data_file_ids(dir)? {
+        if id < target {
+            fs::remove_file(data_path(dir, id))?;
+            let _ = fs::remove_file(hint_path(dir, id));
+        }
+    }
+    fs::rename(&merged_data, data_path(dir, target))?;
+    fs::rename(&merged_hint, hint_path(dir, target))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeydirEntry {
+    file_id: u64,
+    offset: u64,
+    length: u64,
+    expires_at: u64,
+}
+
+/// A Bitcask style engine. Every write is appended to the active data file using the same
+/// length delimited `WalRecord` format as the WAL, and an in memory keydir maps each live key to
+/// the record holding its value. Merging rewrites the older data files with only the live
+/// records, along with hint files that let startup build the keydir without reading values.
+/// Each commit appends a marker holding the WAL sequence it was made at and syncs the active file,
+/// so that startup knows which WAL records to replay.
+#[derive(Debug)]
+pub struct BitcaskEngine {
+    dir: PathBuf,
+    keydir: BTreeMap<Vec<u8>, KeydirEntry>,
+    active_id: u64,
+    active: File,
+    active_size: u64,
+    total_bytes: u64,
+    dead_bytes: u64,
+    readers: Mutex<HashMap<u64, File>>,
+    // Last WAL sequence committed, from the newest commit marker in the data files
+    committed: u64,
+}
+
+impl BitcaskEngine {
+    pub fn open(dir: &Path) -> io::Result<BitcaskEngine> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if let Some(id) = name.strip_suffix(".data.merge") {
+                if let Ok(target) = id.parse() {
+                    info!("Finishing interrupted merge into data file {}", target);
+                    finish_merge(dir, target)?;
+                }
+            }
+        }
+
+        let ids = data_file_ids(dir)?;
+        let active_id = ids.last().map(|id| id + 1).unwrap_or(1);
+        let mut engine = BitcaskEngine {
+            dir: dir.to_path_buf(),
+            keydir: BTreeMap::new(),
+            active_id,
+            active: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(data_path(dir, active_id))?,
+            active_size: 0,
+            total_bytes: 0,
+            dead_bytes: 0,
+            readers: Mutex::new(HashMap::new()),
+            committed: 0,
+        };
+        for id in ids {
+            match hint_path(dir, id).exists() {
+                true => engine.load_hint_file(id)?,
+                false => engine.load_data_file(id)?,
+            }
+        }
+        info!(
+            "Opened Bitcask engine with {} keys in {} bytes ({} dead)",
+            engine.keydir.len(),
+            engine.total_bytes,
+            engine.dead_bytes
+        );
+        Ok(engine)
+    }
+
+    fn index(&mut self, key: Vec<u8>, entry: KeydirEntry) {
+        if let Some(old) = self.keydir.insert(key, entry) {
+            self.dead_bytes += old.length;
+        }
+    }
+
+    fn unindex(&mut self, key: &[u8]) {
+        if let Some(old) = self.keydir.remove(key) {
+            self.dead_bytes += old.length;
+        }
+    }
+
+    fn load_hint_file(&mut self, id: u64) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(hint_path(&self.dir, id))?);
+        while let Some(buf) = read_length_delimited(&mut reader)? {
+            let hint = message::BitcaskHint::decode(buf.as_slice())?;
+            self.total_bytes += hint.length;
+            let entry = KeydirEntry {
+                file_id: id,
+                offset: hint.offset,
+                length: hint.length,
+                expires_at: hint.expires_at,
+            };
+            self.index(hint.key, entry);
+        }
+        Ok(())
+    }
+
+    fn load_data_file(&mut self, id: u64) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(data_path(&self.dir, id))?);
+        let mut offset = 0;
+        loop {
+            let record = match read_record(&mut reader) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                // A crash while appending leaves a partial record at the end of the file
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("Ignoring partial record at the end of data file {}", id);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let end = reader.stream_position()?;
+            let length = end - offset;
+            self.total_bytes += length;
+            match record.operation {
+                Some(Operation::Set(set)) => {
+                    let entry = KeydirEntry {
+                        file_id: id,
+                        offset,
+                        length,
+                        expires_at: set.expires_at,
+                    };
+                    self.index(set.key, entry);
+                }
+                Some(Operation::Delete(delete)) => {
+                    self.unindex(&delete.key);
+                    self.dead_bytes += length;
+                }
+                _ => {
+                    if record.committed != 0 {
+                        self.committed = record.committed;
+                    }
+                    self.dead_bytes += length;
+                }
+            }
+            offset = end;
+        }
+        Ok(())
+    }
+
+    /// Closes the active data file and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.active_id += 1;
+        self.active_size = 0;
+        self.active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_path(&self.dir, self.active_id))?;
+        Ok(())
+    }
+
+    /// Appends a record to the active data file, returning its offset and length
+    fn append(&mut self, record: &message::WalRecord) -> io::Result<(u64, u64)> {
+        if self.active_size >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let mut bytes = Vec::new();
+        write_record(&mut bytes, record)?;
+        self.active.write_all(&bytes)?;
+        let offset = self.active_size;
+        self.active_size += bytes.len() as u64;
+        self.total_bytes += bytes.len() as u64;
+        Ok((offset, bytes.len() as u64))
+    }
+
+    /// Appends a marker for the last committed sequence and syncs the active data file, making it
+    /// and everything appended before it durable
+    fn mark_committed(&mut self) -> io::Result<()> {
+        let (_, length) = self.append(&message::WalRecord {
+            operation: None,
+            committed: self.committed,
+        })?;
+        self.dead_bytes += length;
+        self.active.sync_data()
+    }
+
+    /// Reads the raw record, including any chunks of its value, that an entry points at
+    fn read_raw(&self, entry: &KeydirEntry) -> io::Result<Vec<u8>> {
+        let mut readers = self.readers.lock().unwrap();
+        let file = match readers.get_mut(&entry.file_id) {
+            Some(file) => file,
+            None => {
+                let file = File::open(data_path(&self.dir, entry.file_id))?;
+                readers.entry(entry.file_id).or_insert(file)
+            }
+        };
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut buf = vec![0u8; entry.length as usize];
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_entry(&self, entry: &KeydirEntry) -> io::Result<Entry> {
+        let buf = self.read_raw(entry)?;
+        match read_record(&mut buf.as_slice())?.and_then(|r| r.operation) {
+            Some(Operation::Set(set)) => Ok(Entry {
+                value: set.value,
+                expires_at: set.expires_at,
+            }),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Keydir points at a record without a value in file {}",
+                    entry.file_id
+                ),
+            )),
+        }
+    }
+
+    fn should_merge(&self) -> bool {
+        self.dead_bytes >= MERGE_THRESHOLD && self.dead_bytes * 2 >= self.total_bytes
+    }
+
+    /// Rewrites every data file into a single file holding only live records. The active file is
+    /// rotated first so that it can be merged too. The merged file takes the id of the newest file
+    /// it replaces, so it still sorts before the new active file on startup.
+    fn merge(&mut self) -> io::Result<()> {
+        self.rotate()?;
+        let target = self.active_id - 1;
+        info!("Merging data files into {}", target);
+        let merged_data = merge_path(&data_path(&self.dir, target));
+        let mut data = BufWriter::new(File::create(&merged_data)?);
+        let mut hints = Vec::new();
+        let mut offset = 0;
+        for (key, entry) in self.keydir.iter() {
+            if entry.file_id == self.active_id {
+                continue;
+            }
+            let raw = self.read_raw(entry)?;
+            data.write_all(&raw)?;
+            hints.push(message::BitcaskHint {
+                key: key.clone(),
+                file_id: target,
+                offset,
+                length: entry.length,
+                expires_at: entry.expires_at,
+            });
+            offset += entry.length;
+        }
+        data.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let mut hint_buf = Vec::new();
+        for hint in &hints {
+            hint_buf.extend(hint.encode_length_delimited_to_vec());
+        }
+        let merged_hint = merge_path(&hint_path(&self.dir, target));
+        let hint_tmp = merged_hint.with_extension("tmp");
+        let mut hint_file = File::create(&hint_tmp)?;
+        hint_file.write_all(&hint_buf)?;
+        hint_file.sync_all()?;
+        fs::rename(&hint_tmp, &merged_hint)?;
+        // Merged files carry no markers, so the new active file needs one before the files that
+        // held the last marker are removed
+        self.mark_committed()?;
+
+        self.readers.lock().unwrap().clear();
+        finish_merge(&self.dir, target)?;
+        for hint in hints {
+            if let Some(entry) = self.keydir.get_mut(&hint.key) {
+                entry.file_id = hint.file_id;
+                entry.offset = hint.offset;
+            }
+        }
+        self.total_bytes = offset + self.active_size;
+        self.dead_bytes = 0;
+        Ok(())
+    }
+}
+
+impl StorageEngine for BitcaskEngine {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        match self.keydir.get(key) {
+            Some(entry) => Ok(Some(self.read_entry(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], entry: Entry) -> io::Result<()> {
+        let expires_at = entry.expires_at;
+        let record = message::WalRecord {
+            operation: Some(Operation::Set(message::Set {
+                key: key.to_vec(),
+                value: entry.value,
+                expires_at,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let (offset, length) = self.append(&record)?;
+        let entry = KeydirEntry {
+            file_id: self.active_id,
+            offset,
+            length,
+            expires_at,
+        };
+        self.index(key.to_vec(), entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<Option<Entry>> {
+        let entry = match self.get(key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let record = message::WalRecord {
+            operation: Some(Operation::Delete(message::Delete { key: key.to_vec() })),
+            ..Default::default()
+        };
+        let (_, length) = self.append(&record)?;
+        self.unindex(key);
+        self.dead_bytes += length;
+        Ok(Some(entry))
+    }
+
+    fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        visit: &mut dyn FnMut(&[u8], &Entry) -> bool,
+    ) -> io::Result<()> {
+        if is_empty_range(lower, upper) {
+            return Ok(());
+        }
+        for (key, entry) in self.keydir.range::<[u8], _>((lower, upper)) {
+            if !visit(key, &self.read_entry(entry)?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<message::Store> {
+        let mut store = message::Store::default();
+        self.scan(Bound::Unbounded, Bound::Unbounded, &mut |key, entry| {
+            store.records.push(message::Record {
+                key: key.to_vec(),
+                value: entry.value.clone(),
+                expires_at: entry.expires_at,
+            });
+            true
+        })?;
+        Ok(store)
+    }
+
+    fn commit(&mut self, sequence: u64) -> io::Result<()> {
+        // Records are appended as they are applied, so only the marker is left to write. Merging
+        // writes one of its own
+        self.committed = sequence;
+        match self.should_merge() {
+            true => self.merge(),
+            false => self.mark_committed(),
+        }
+    }
+
+    fn snapshot_sequence(&self) -> u64 {
+        self.committed
+    }
+
+    fn recover(&mut self, wal: &WriteAheadLog) -> io::Result<()> {
+        // Data files written before markers existed give no sequence, so all of the WAL that is
+        // left is replayed. Records the files already hold are applied again to the same effect
+        let start = match self.committed {
+            0 => wal.first_sequence(),
+            committed => committed + 1,
+        };
+        let mut replayed = 0;
+        let mut last = self.committed;
+        for item in wal.iter_from(start)? {
+            let (sequence, record) = item?;
+            self.apply(&record)?;
+            last = sequence;
+            replayed += 1;
+        }
+        if replayed > 0 {
+            self.commit(last)?;
+        }
+        info!("Replayed {} WAL records into the data files", replayed);
+        Ok(())
+    }
+
+    fn expired(&self, now: u64) -> io::Result<Vec<message::Expire>> {
+        let mut expired: Vec<message::Expire> = self
+            .keydir
+            .iter()
+            .filter(|(_, entry)| entry.expires_at != 0 && entry.expires_at <= now)
+            .map(|(key, entry)| message::Expire {
+                key: key.clone(),
+                expires_at: entry.expires_at,
+            })
+            .collect();
+        expired.sort_by(|a, b| (a.expires_at, &a.key).cmp(&(b.expires_at, &b.key)));
+        Ok(expired)
+    }
+}
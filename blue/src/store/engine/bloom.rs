@@ -0,0 +1,67 @@
+// Bits set per key. Ten bits gives roughly a 1% false positive rate
+static BITS_PER_KEY: usize = 10;
+
+/// FNV-1a, seeded so that two independent hashes can be taken from the same key. Bloom filters are
+/// written to disk so the hash must not change between builds, which rules out std's hasher.
+fn fnv1a(key: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(keys: usize) -> BloomFilter {
+        let bits = std::cmp::max(64, keys * BITS_PER_KEY);
+        // ln(2) * bits per key hashes minimises the false positive rate
+        let hashes = ((BITS_PER_KEY as f64) * 0.69).round() as u32;
+        BloomFilter {
+            bits: vec![0u8; bits.div_ceil(8)],
+            hashes: hashes.clamp(1, 30),
+        }
+    }
+
+    pub fn from_parts(bits: Vec<u8>, hashes: u32) -> BloomFilter {
+        BloomFilter { bits, hashes }
+    }
+
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Bit positions for a key using double hashing
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let h1 = fnv1a(key, 0);
+        let h2 = fnv1a(key, 0x9e37_79b9_7f4a_7c15) | 1;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let positions: Vec<usize> = self.positions(key).collect();
+        for position in positions {
+            self.bits[position / 8] |= 1 << (position % 8);
+        }
+    }
+
+    /// False means the key is definitely absent
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.positions(key)
+            .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+}
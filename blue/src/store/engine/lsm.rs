@@ -0,0 +1,372 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{error, info};
+use prost::Message;
+
+use super::super::super::ipc::message;
+use super::super::serialize::write_atomic;
+use super::super::wal::WriteAheadLog;
+use super::sstable::{Slot, SlotItem, SsTable};
+use super::{is_empty_range, Entry, StorageEngine};
+
+// Memtable size, in bytes, at which it is flushed to a new SSTable
+static MEMTABLE_LIMIT: usize = 4 * 1024 * 1024;
+// Number of SSTables that triggers a compaction
+static COMPACTION_TRIGGER: usize = 4;
+static MANIFEST: &str = "MANIFEST";
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.sst", id))
+}
+
+fn slot_size(key: &[u8], slot: &Slot) -> usize {
+    key.len() + slot.as_ref().map(|e| e.value.len()).unwrap_or(0) + 16
+}
+
+/// Merges sorted sources into one sorted stream. Sources are given newest first and when several
+/// hold the same key only the newest slot is returned.
+struct MergeIter<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = SlotItem> + 'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = SlotItem> + 'a>>) -> MergeIter<'a> {
+        MergeIter {
+            sources: sources.into_iter().map(|s| s.peekable()).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = SlotItem;
+
+    fn next(&mut self) -> Option<SlotItem> {
+        let mut smallest: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if smallest.as_ref().is_none_or(|(_, k)| key < k) => {
+                    smallest = Some((i, key.clone()));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => (),
+            }
+        }
+        let (newest, key) = smallest?;
+        let item = self.sources[newest].next();
+        // Skip older versions of the same key
+        for source in self.sources.iter_mut().skip(newest + 1) {
+            if let Some(Ok((k, _))) = source.peek() {
+                if *k == key {
+                    source.next();
+                }
+            }
+        }
+        item
+    }
+}
+
+#[derive(Debug)]
+struct Tables {
+    // Oldest first
+    tables: Vec<Arc<SsTable>>,
+    manifest: message::LsmManifest,
+}
+
+impl Tables {
+    fn persist_manifest(&mut self, dir: &Path) -> io::Result<()> {
+        self.manifest.tables = self
+            .tables
+            .iter()
+            .filter_map(|t| t.path().file_stem()?.to_str()?.parse::<u64>().ok())
+            .collect();
+        write_atomic(&dir.join(MANIFEST), &self.manifest.encode_to_vec())
+    }
+
+    fn reserve_table_id(&mut self) -> u64 {
+        let id = self.manifest.next_table_id;
+        self.manifest.next_table_id += 1;
+        id
+    }
+}
+
+/// A log-structured merge-tree. Writes go to an in memory memtable, which the node's WAL makes
+/// durable, and are flushed to immutable sorted SSTables once it grows past `MEMTABLE_LIMIT`. A
+/// background thread merges SSTables once there are `COMPACTION_TRIGGER` of them.
+#[derive(Debug)]
+pub struct LsmEngine {
+    dir: PathBuf,
+    memtable: BTreeMap<Vec<u8>, Slot>,
+    memtable_bytes: usize,
+    // Expiry of every key with a TTL so the reaper doesn't need to read the tables
+    expirations: HashMap<Vec<u8>, u64>,
+    tables: Arc<Mutex<Tables>>,
+    compactor: Sender<()>,
+}
+
+impl LsmEngine {
+    pub fn open(dir: &Path) -> io::Result<LsmEngine> {
+        fs::create_dir_all(dir)?;
+        let manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => message::LsmManifest::decode(bytes.as_slice())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => message::LsmManifest {
+                next_table_id: 1,
+                ..Default::default()
+            },
+            Err(e) => return Err(e),
+        };
+        let mut tables = Vec::new();
+        for id in &manifest.tables {
+            tables.push(Arc::new(SsTable::open(&table_path(dir, *id))?));
+        }
+        info!(
+            "Opened LSM engine with {} SSTables, flushed through sequence #{}",
+            tables.len(),
+            manifest.flushed_sequence
+        );
+        let tables = Arc::new(Mutex::new(Tables { tables, manifest }));
+
+        let (compactor, requests) = mpsc::channel();
+        let compaction_dir = dir.to_path_buf();
+        let compaction_tables = Arc::clone(&tables);
+        thread::spawn(move || run_compactor(compaction_dir, compaction_tables, requests));
+
+        let mut engine = LsmEngine {
+            dir: dir.to_path_buf(),
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            expirations: HashMap::new(),
+            tables,
+            compactor,
+        };
+        let mut expirations = HashMap::new();
+        engine.scan(Bound::Unbounded, Bound::Unbounded, &mut |key, entry| {
+            if entry.expires_at != 0 {
+                expirations.insert(key.to_vec(), entry.expires_at);
+            }
+            true
+        })?;
+        engine.expirations = expirations;
+        // Compact anything left over from a previous run
+        let _ = engine.compactor.send(());
+        Ok(engine)
+    }
+
+    fn tables(&self) -> Vec<Arc<SsTable>> {
+        self.tables.lock().unwrap().tables.clone()
+    }
+
+    fn insert(&mut self, key: &[u8], slot: Slot) {
+        match &slot {
+            Some(entry) if entry.expires_at != 0 => {
+                self.expirations.insert(key.to_vec(), entry.expires_at)
+            }
+            _ => self.expirations.remove(key),
+        };
+        self.memtable_bytes += slot_size(key, &slot);
+        self.memtable.insert(key.to_vec(), slot);
+    }
+
+    fn get_slot(&self, key: &[u8]) -> io::Result<Option<Slot>> {
+        if let Some(slot) = self.memtable.get(key) {
+            return Ok(Some(slot.clone()));
+        }
+        for table in self.tables().iter().rev() {
+            if let Some(slot) = table.get(key)? {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes the memtable to a new SSTable holding everything up to `sequence`
+    fn flush_memtable(&mut self, sequence: u64) -> io::Result<()> {
+        let id = self.tables.lock().unwrap().reserve_table_id();
+        let path = table_path(&self.dir, id);
+        info!(
+            "Flushing memtable ({} keys) to {:?}",
+            self.memtable.len(),
+            path
+        );
+        let slots = self
+            .memtable
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), v.clone())));
+        let table = SsTable::write(&path, slots, self.memtable.len())?;
+        {
+            let mut tables = self.tables.lock().unwrap();
+            tables.tables.push(Arc::new(table));
+            tables.manifest.flushed_sequence = sequence;
+            tables.persist_manifest(&self.dir)?;
+        }
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        let _ = self.compactor.send(());
+        Ok(())
+    }
+}
+
+/// Merges every SSTable into one whenever there are enough of them. As the merge always covers the
+/// oldest table, tombstones have nothing left to hide and are dropped.
+fn compact(dir: &Path, tables: &Mutex<Tables>) -> io::Result<()> {
+    let (inputs, id) = {
+        let mut tables = tables.lock().unwrap();
+        if tables.tables.len() < COMPACTION_TRIGGER {
+            return Ok(());
+        }
+        (tables.tables.clone(), tables.reserve_table_id())
+    };
+    let path = table_path(dir, id);
+    info!("Compacting {} SSTables into {:?}", inputs.len(), path);
+    let sources = inputs
+        .iter()
+        .rev()
+        .map(|t| Box::new(SsTable::iter(t, Bound::Unbounded)) as Box<dyn Iterator<Item = SlotItem>>)
+        .collect();
+    let merged = MergeIter::new(sources).filter(|item| !matches!(item, Ok((_, None))));
+    // An upper bound, as keys in several tables are only written once
+    let count = inputs.iter().map(|t| t.len()).sum();
+    let table = SsTable::write(&path, merged, count)?;
+    {
+        let mut tables = tables.lock().unwrap();
+        // Tables flushed while compacting are newer than the merged table so stay after it
+        let newer = tables.tables.split_off(inputs.len());
+        tables.tables = vec![Arc::new(table)];
+        tables.tables.extend(newer);
+        tables.persist_manifest(dir)?;
+    }
+    for input in inputs {
+        fs::remove_file(input.path())?;
+    }
+    Ok(())
+}
+
+fn run_compactor(dir: PathBuf, tables: Arc<Mutex<Tables>>, requests: Receiver<()>) {
+    // Stops once the engine, and with it the sender, is dropped
+    while requests.recv().is_ok() {
+        if let Err(e) = compact(&dir, &tables) {
+            error!("Compaction failed: {}", e);
+        }
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        Ok(self.get_slot(key)?.flatten())
+    }
+
+    fn put(&mut self, key: &[u8], entry: Entry) -> io::Result<()> {
+        self.insert(key, Some(entry));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<Option<Entry>> {
+        let entry = self.get(key)?;
+        self.insert(key, None);
+        Ok(entry)
+    }
+
+    fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        visit: &mut dyn FnMut(&[u8], &Entry) -> bool,
+    ) -> io::Result<()> {
+        if is_empty_range(lower, upper) {
+            return Ok(());
+        }
+        let tables = self.tables();
+        let mut sources: Vec<Box<dyn Iterator<Item = SlotItem>>> = vec![Box::new(
+            self.memtable
+                .range::<[u8], _>((lower, Bound::Unbounded))
+                .map(|(k, v)| Ok((k.clone(), v.clone()))),
+        )];
+        for table in tables.iter().rev() {
+            sources.push(Box::new(SsTable::iter(table, lower)));
+        }
+        for item in MergeIter::new(sources) {
+            let (key, slot) = item?;
+            let past_upper = match upper {
+                Bound::Included(u) => key.as_slice() > u,
+                Bound::Excluded(u) => key.as_slice() >= u,
+                Bound::Unbounded => false,
+            };
+            if past_upper {
+                break;
+            }
+            if let Some(entry) = slot {
+                if !visit(&key, &entry) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<message::Store> {
+        let mut store = message::Store::default();
+        self.scan(Bound::Unbounded, Bound::Unbounded, &mut |key, entry| {
+            store.records.push(message::Record {
+                key: key.to_vec(),
+                value: entry.value.clone(),
+                expires_at: entry.expires_at,
+            });
+            true
+        })?;
+        Ok(store)
+    }
+
+    fn commit(&mut self, sequence: u64) -> io::Result<()> {
+        // The WAL already makes the memtable durable, so there is only work to do once it is full
+        if self.memtable_bytes >= MEMTABLE_LIMIT {
+            self.flush_memtable(sequence)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, sequence: u64) -> io::Result<()> {
+        if !self.memtable.is_empty() {
+            return self.flush_memtable(sequence);
+        }
+        let mut tables = self.tables.lock().unwrap();
+        tables.manifest.flushed_sequence = sequence;
+        tables.persist_manifest(&self.dir)
+    }
+
+    fn snapshot_sequence(&self) -> u64 {
+        self.tables.lock().unwrap().manifest.flushed_sequence
+    }
+
+    fn recover(&mut self, wal: &WriteAheadLog) -> io::Result<()> {
+        let flushed = self.tables.lock().unwrap().manifest.flushed_sequence;
+        let mut replayed = 0;
+        for item in wal.iter_from(flushed + 1)? {
+            let (_, record) = item?;
+            self.apply(&record)?;
+            replayed += 1;
+        }
+        info!("Replayed {} WAL records into the memtable", replayed);
+        Ok(())
+    }
+
+    fn expired(&self, now: u64) -> io::Result<Vec<message::Expire>> {
+        let mut expired: Vec<message::Expire> = self
+            .expirations
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, expires_at)| message::Expire {
+                key: key.clone(),
+                expires_at: *expires_at,
+            })
+            .collect();
+        expired.sort_by(|a, b| (a.expires_at, &a.key).cmp(&(b.expires_at, &b.key)));
+        Ok(expired)
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use super::super::super::ipc::message;
+use super::super::super::ipc::message::Compression;
+use super::super::deserialize::deserialize_store;
+use super::super::encryption::Keyring;
+use super::super::serialize::{persist_store, previous_store_path};
+use super::super::wal::WriteAheadLog;
+use super::{is_empty_range, Entry, StorageEngine};
+
+/// Keeps every record in memory and rewrites the whole store as a protobuf `Store` to a single
+/// `.pb` file on each flush
+#[derive(Debug)]
+pub struct MapEngine {
+    records: BTreeMap<Vec<u8>, Entry>,
+    // Last WAL sequence committed
+    sequence: u64,
+    // Last WAL sequence held by the previous snapshot, kept on disk to fall back on. Zero until
+    // the first commit since opening, as the previous snapshot isn't read
+    previous_sequence: u64,
+    // The snapshot at `path` couldn't be read. It is moved aside by the next commit, so that it
+    // stays where it was should the node fail to start
+    unreadable: bool,
+    path: PathBuf,
+    keyring: Option<Arc<Keyring>>,
+    compression: Compression,
+}
+
+impl MapEngine {
+    /// Opens the snapshot at `path`. If it can't be decoded, as when a disk fault has corrupted
+    /// it, the previous snapshot is used instead and failing that an empty store. Either way
+    /// `recover` then replays what is missing from the WAL. Any other error, such as a key
+    /// missing from the key file or a file that can't be opened, is returned as it is, so that
+    /// a misconfigured node doesn't set a good snapshot aside.
+    ///
+    /// With a keyring every snapshot written is encrypted. Snapshots written unencrypted can
+    /// still be opened, so encryption can be turned on for an existing node. The same goes for
+    /// compression.
+    pub fn open(
+        path: &Path,
+        keyring: Option<Arc<Keyring>>,
+        compression: Compression,
+    ) -> io::Result<MapEngine> {
+        let (store, unreadable) = match deserialize_store(path, keyring.as_deref()) {
+            Ok(store) => (store, false),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let previous = previous_store_path(path);
+                warn!(
+                    "Snapshot {:?} is unreadable ({}), falling back to {:?}",
+                    path, e, previous
+                );
+                let store = match deserialize_store(&previous, keyring.as_deref()) {
+                    Ok(store) => store,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        warn!(
+                            "Snapshot {:?} is unreadable too ({}), rebuilding from the WAL alone",
+                            previous, e
+                        );
+                        message::Store::default()
+                    }
+                    Err(e) => return Err(e),
+                };
+                (store, true)
+            }
+            Err(e) => return Err(e),
+        };
+        let sequence = store.sequence;
+        let records = store
+            .records
+            .into_iter()
+            .map(|record| {
+                (
+                    record.key,
+                    Entry {
+                        value: record.value,
+                        expires_at: record.expires_at,
+                    },
+                )
+            })
+            .collect();
+        Ok(MapEngine {
+            records,
+            sequence,
+            previous_sequence: 0,
+            unreadable,
+            path: path.to_path_buf(),
+            keyring,
+            compression,
+        })
+    }
+}
+
+impl StorageEngine for MapEngine {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Entry>> {
+        Ok(self.records.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &[u8], entry: Entry) -> io::Result<()> {
+        self.records.insert(key.to_vec(), entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> io::Result<Option<Entry>> {
+        Ok(self.records.remove(key))
+    }
+
+    fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        visit: &mut dyn FnMut(&[u8], &Entry) -> bool,
+    ) -> io::Result<()> {
+        // BTreeMap::range panics on an inverted range
+        if is_empty_range(lower, upper) {
+            return Ok(());
+        }
+        for (key, entry) in self.records.range::<[u8], _>((lower, upper)) {
+            if !visit(key, entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<message::Store> {
+        Ok(message::Store {
+            records: self
+                .records
+                .iter()
+                .map(|(key, entry)| message::Record {
+                    key: key.clone(),
+                    value: entry.value.clone(),
+                    expires_at: entry.expires_at,
+                })
+                .collect(),
+            sequence: self.sequence,
+            ..Default::default()
+        })
+    }
+
+    fn commit(&mut self, sequence: u64) -> io::Result<()> {
+        if self.unreadable {
+            // Moved aside so that it isn't kept as the previous snapshot
+            fs::rename(&self.path, self.path.with_extension("corrupt"))?;
+            self.unreadable = false;
+        }
+        self.previous_sequence = self.sequence;
+        self.sequence = sequence;
+        persist_store(
+            &mut self.snapshot()?,
+            &self.path,
+            self.keyring.as_deref(),
+            self.compression,
+        )
+    }
+
+    /// The WAL is kept back to the previous snapshot so that falling back on it stays possible
+    fn snapshot_sequence(&self) -> u64 {
+        self.previous_sequence
+    }
+
+    /// Replays anything the snapshot is missing. Only needed after falling back to an older
+    /// snapshot, or when the node stopped between logging a write and committing it
+    fn recover(&mut self, wal: &WriteAheadLog) -> io::Result<()> {
+        if self.sequence + 1 >= wal.next_sequence {
+            return Ok(());
+        }
+        let mut replayed = 0;
+        let mut last = self.sequence;
+        for item in wal.iter_from(self.sequence + 1)? {
+            let (sequence, record) = item?;
+            self.apply(&record)?;
+            last = sequence;
+            replayed += 1;
+        }
+        info!("Replayed {} WAL records onto the snapshot", replayed);
+        // The sequence is left at the loaded snapshot's until the commit, which keeps that
+        // snapshot as the previous one and so has to keep the WAL back to it
+        self.commit(last)
+    }
+}
@@ -0,0 +1,188 @@
+pub mod bitcask;
+pub mod bloom;
+pub mod lsm;
+pub mod map;
+pub mod sstable;
+
+use std::fmt::Debug;
+use std::io::{self, ErrorKind};
+use std::ops::Bound;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::super::ipc::message;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::Compression;
+use super::encryption::Keyring;
+use super::wal::{WalItem, WriteAheadLog};
+
+use bitcask::BitcaskEngine;
+use lsm::LsmEngine;
+use map::MapEngine;
+
+/// A value held by a storage engine along with its absolute expiry (zero if it never expires)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    pub expires_at: u64,
+}
+
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+/// Returns true if no key can fall between the bounds
+pub fn is_empty_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Excluded(u) | Bound::Included(u)) => {
+            l >= u
+        }
+        _ => false,
+    }
+}
+
+/// Where and how a node keeps its records. Engines don't know about time: expired entries are
+/// returned like any other and it is up to the caller to hide them.
+pub trait StorageEngine: Debug + Send + Sync {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Entry>>;
+
+    fn put(&mut self, key: &[u8], entry: Entry) -> io::Result<()>;
+
+    /// Removes a key, returning the entry it held
+    fn delete(&mut self, key: &[u8]) -> io::Result<Option<Entry>>;
+
+    /// Visits entries in key order between the bounds until `visit` returns false
+    fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        visit: &mut dyn FnMut(&[u8], &Entry) -> bool,
+    ) -> io::Result<()>;
+
+    /// A point in time copy of every record
+    fn snapshot(&self) -> io::Result<message::Store>;
+
+    /// Makes every change applied so far durable. `sequence` is the WAL sequence of the last
+    /// record applied, which engines that recover from the WAL use to know where to resume
+    fn commit(&mut self, sequence: u64) -> io::Result<()>;
+
+    /// Commits and makes the engine's own files hold every change applied so far, so that
+    /// `snapshot_sequence` reaches `sequence` and the WAL up to it can be thrown away. Engines
+    /// whose commit already writes everything to their files just commit
+    fn flush(&mut self, sequence: u64) -> io::Result<()> {
+        self.commit(sequence)
+    }
+
+    /// Last WAL sequence whose effect the engine's own files hold, so that the WAL up to it is
+    /// no longer needed to rebuild the engine. Zero if the engine always needs the whole WAL
+    fn snapshot_sequence(&self) -> u64 {
+        0
+    }
+
+    /// Brings the engine up to date with records in the WAL it hadn't made durable before the
+    /// node last stopped. Engines that make every commit durable on their own have nothing to do
+    fn recover(&mut self, _wal: &WriteAheadLog) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Entries whose expiry is at or before `now`, ordered by expiry then key so that the order
+    /// they are expired in doesn't depend on the engine
+    fn expired(&self, now: u64) -> io::Result<Vec<message::Expire>> {
+        let mut expired = Vec::new();
+        self.scan(Bound::Unbounded, Bound::Unbounded, &mut |key, entry| {
+            if entry.is_expired(now) {
+                expired.push(message::Expire {
+                    key: key.to_vec(),
+                    expires_at: entry.expires_at,
+                });
+            }
+            true
+        })?;
+        expired.sort_by(|a, b| (a.expires_at, &a.key).cmp(&(b.expires_at, &b.key)));
+        Ok(expired)
+    }
+
+    /// Applies a single WAL record without flushing
+    fn apply(&mut self, record: &message::WalRecord) -> io::Result<()> {
+        match &record.operation {
+            Some(Operation::Set(set)) => self.put(
+                &set.key,
+                Entry {
+                    value: set.value.clone(),
+                    expires_at: set.expires_at,
+                },
+            ),
+            Some(Operation::Delete(delete)) => self.delete(&delete.key).map(|_| ()),
+            // Only remove the key if it still carries the expiry being applied, so a key that was
+            // set again after it was scheduled to expire is left alone
+            Some(Operation::Expire(expire)) => match self.get(&expire.key)? {
+                Some(entry) if entry.expires_at == expire.expires_at => {
+                    self.delete(&expire.key).map(|_| ())
+                }
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Applies WAL items in order and commits once they have all been applied
+    fn apply_batch(&mut self, items: &[WalItem]) -> io::Result<()> {
+        for (_, record) in items {
+            self.apply(record)?;
+        }
+        match items.last() {
+            Some((sequence, _)) => self.commit(*sequence),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EngineKind {
+    Map,
+    Lsm,
+    Bitcask,
+}
+
+impl FromStr for EngineKind {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<EngineKind, Self::Err> {
+        match input {
+            "map" | "Map" => Ok(EngineKind::Map),
+            "lsm" | "Lsm" | "LSM" => Ok(EngineKind::Lsm),
+            "bitcask" | "Bitcask" => Ok(EngineKind::Bitcask),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Opens the engine chosen at startup, recovering anything it has already persisted at `path`.
+/// Only the map engine can encrypt its files, so the others refuse to start with a keyring. Only
+/// the map engine compresses its files too, but the others just leave theirs uncompressed
+pub fn open_engine(
+    kind: EngineKind,
+    path: &Path,
+    keyring: Option<Arc<Keyring>>,
+    compression: Compression,
+) -> io::Result<Box<dyn StorageEngine>> {
+    if keyring.is_some() && !matches!(kind, EngineKind::Map) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Encryption at rest is only supported by the map engine",
+        ));
+    }
+    match kind {
+        EngineKind::Map => Ok(Box::new(MapEngine::open(path, keyring, compression)?)),
+        // Disk based engines keep their files in a directory next to where the map engine would
+        // keep its single file
+        EngineKind::Lsm => Ok(Box::new(LsmEngine::open(&path.with_extension("lsm"))?)),
+        EngineKind::Bitcask => Ok(Box::new(BitcaskEngine::open(
+            &path.with_extension("bitcask"),
+        )?)),
+    }
+}
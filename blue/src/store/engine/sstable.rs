@@ -0,0 +1,251 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use prost::Message;
+
+use super::super::super::ipc::message;
+use super::super::serialize::sync_parent_dir;
+use super::bloom::BloomFilter;
+use super::Entry;
+
+// Target size of a data block before it is written out and a new one started
+static BLOCK_SIZE: usize = 4096;
+
+/// A value in the LSM engine. `None` is a tombstone left by a delete.
+pub type Slot = Option<Entry>;
+pub type SlotItem = io::Result<(Vec<u8>, Slot)>;
+
+fn to_entry(key: &[u8], slot: &Slot) -> message::SsTableEntry {
+    match slot {
+        Some(entry) => message::SsTableEntry {
+            key: key.to_vec(),
+            value: entry.value.clone(),
+            expires_at: entry.expires_at,
+            deleted: false,
+        },
+        None => message::SsTableEntry {
+            key: key.to_vec(),
+            deleted: true,
+            ..Default::default()
+        },
+    }
+}
+
+fn to_slot(entry: message::SsTableEntry) -> (Vec<u8>, Slot) {
+    match entry.deleted {
+        true => (entry.key, None),
+        false => (
+            entry.key,
+            Some(Entry {
+                value: entry.value,
+                expires_at: entry.expires_at,
+            }),
+        ),
+    }
+}
+
+/// An immutable, sorted table on disk.
+///
+/// Layout: protobuf encoded `SsTableBlock`s, then an `SsTableMeta` holding the block index and the
+/// bloom filter, then the offset of the meta as 8 little endian bytes.
+#[derive(Debug)]
+pub struct SsTable {
+    path: PathBuf,
+    file: Mutex<File>,
+    blocks: Vec<message::SsTableBlockHandle>,
+    bloom: BloomFilter,
+    entries: usize,
+}
+
+impl SsTable {
+    /// Writes sorted slots to a new table. The table is written under a temporary name and renamed
+    /// into place once it is complete and synced, so a crash never leaves a partial table behind.
+    pub fn write<I>(path: &Path, slots: I, count: usize) -> io::Result<SsTable>
+    where
+        I: Iterator<Item = SlotItem>,
+    {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut bloom = BloomFilter::new(count);
+        let mut blocks = Vec::new();
+        let mut block = message::SsTableBlock::default();
+        let mut offset = 0u64;
+        let mut entries = 0u64;
+
+        let mut write_block =
+            |block: &mut message::SsTableBlock, writer: &mut BufWriter<File>| -> io::Result<()> {
+                if block.entries.is_empty() {
+                    return Ok(());
+                }
+                let bytes = block.encode_to_vec();
+                writer.write_all(&bytes)?;
+                blocks.push(message::SsTableBlockHandle {
+                    first_key: block.entries[0].key.clone(),
+                    offset,
+                    length: bytes.len() as u64,
+                });
+                offset += bytes.len() as u64;
+                block.entries.clear();
+                Ok(())
+            };
+
+        for slot in slots {
+            let (key, slot) = slot?;
+            bloom.insert(&key);
+            entries += 1;
+            block.entries.push(to_entry(&key, &slot));
+            if block.encoded_len() >= BLOCK_SIZE {
+                write_block(&mut block, &mut writer)?;
+            }
+        }
+        write_block(&mut block, &mut writer)?;
+
+        let meta = message::SsTableMeta {
+            blocks,
+            bloom: bloom.bits().to_vec(),
+            bloom_hashes: bloom.hashes(),
+            entries,
+        };
+        writer.write_all(&meta.encode_to_vec())?;
+        writer.write_all(&offset.to_le_bytes())?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        sync_parent_dir(path)?;
+        SsTable::open(path)
+    }
+
+    pub fn open(path: &Path) -> io::Result<SsTable> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < 8 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("SSTable {:?} is truncated", path),
+            ));
+        }
+        file.seek(SeekFrom::Start(size - 8))?;
+        let mut offset_bytes = [0u8; 8];
+        file.read_exact(&mut offset_bytes)?;
+        let meta_offset = u64::from_le_bytes(offset_bytes);
+        file.seek(SeekFrom::Start(meta_offset))?;
+        let mut meta_bytes = vec![0u8; (size - 8 - meta_offset) as usize];
+        file.read_exact(&mut meta_bytes)?;
+        let meta = message::SsTableMeta::decode(meta_bytes.as_slice())?;
+        Ok(SsTable {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            blocks: meta.blocks,
+            bloom: BloomFilter::from_parts(meta.bloom, meta.bloom_hashes),
+            entries: meta.entries as usize,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of slots, tombstones included
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    fn read_block(&self, index: usize) -> io::Result<Vec<message::SsTableEntry>> {
+        let handle = &self.blocks[index];
+        let mut buf = vec![0u8; handle.length as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        Ok(message::SsTableBlock::decode(buf.as_slice())?.entries)
+    }
+
+    /// Index of the only block that can hold `key`
+    fn block_for(&self, key: &[u8]) -> Option<usize> {
+        match self
+            .blocks
+            .partition_point(|b| b.first_key.as_slice() <= key)
+        {
+            0 => None,
+            n => Some(n - 1),
+        }
+    }
+
+    /// `None` if the table knows nothing of the key, otherwise its slot (which may be a tombstone)
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Slot>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let index = match self.block_for(key) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let entries = self.read_block(index)?;
+        match entries.binary_search_by(|e| e.key.as_slice().cmp(key)) {
+            Ok(i) => Ok(Some(to_slot(entries[i].clone()).1)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Iterates slots in key order starting at the lower bound
+    pub fn iter(table: &Arc<SsTable>, lower: Bound<&[u8]>) -> SsTableIter {
+        let block = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => table.block_for(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        SsTableIter {
+            table: Arc::clone(table),
+            lower: lower.map(|k| k.to_vec()),
+            block,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+pub struct SsTableIter {
+    table: Arc<SsTable>,
+    lower: Bound<Vec<u8>>,
+    block: usize,
+    entries: std::vec::IntoIter<message::SsTableEntry>,
+}
+
+impl Iterator for SsTableIter {
+    type Item = SlotItem;
+
+    fn next(&mut self) -> Option<SlotItem> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                let before_lower = match &self.lower {
+                    Bound::Included(l) => entry.key < *l,
+                    Bound::Excluded(l) => entry.key <= *l,
+                    Bound::Unbounded => false,
+                };
+                if before_lower {
+                    continue;
+                }
+                self.lower = Bound::Unbounded;
+                return Some(Ok(to_slot(entry)));
+            }
+            if self.block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    // Stop after reporting the error
+                    self.block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.block += 1;
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use tokio::sync::Mutex;
+
+use super::super::ipc::message;
+use super::super::ipc::message::request::Command;
+use super::super::ipc::message::wal_record::Operation;
+use super::cluster::Cluster;
+use super::engine::StorageEngine;
+use super::wal::WriteAheadLog;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the Unix epoch")
+        .as_millis() as u64
+}
+
+/// Converts the client supplied TTL into an absolute expiry. Only the leader calls this so that
+/// followers replay the exact same expiry from the WAL instead of computing their own. Any
+/// `expires_at` the client sent is ignored, so without a TTL the key never expires. Fails if the
+/// expiry doesn't fit in a u64.
+pub fn stamp_expiration(set: &mut message::Set) -> io::Result<()> {
+    set.expires_at = match set.ttl_ms {
+        0 => 0,
+        ttl_ms => now_millis().checked_add(ttl_ms).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("TTL of {}ms is too large", ttl_ms),
+            )
+        })?,
+    };
+    Ok(())
+}
+
+/// Removes every expired key from the leader's store, logging each removal to the WAL and
+/// replicating it to followers. Returns the number of keys removed.
+pub async fn reap_expired(
+    store: &Mutex<Box<dyn StorageEngine>>,
+    wal: &Mutex<WriteAheadLog>,
+    cluster: &Mutex<Cluster>,
+) -> io::Result<usize> {
+    let mut store = store.lock().await;
+    let mut wal = wal.lock().await;
+    let cluster = cluster.lock().await;
+
+    let expired = store.expired(now_millis())?;
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    let mut sequence = wal.next_sequence;
+    for expire in &expired {
+        info!("Expiring key={}", String::from_utf8_lossy(&expire.key));
+        let record = message::WalRecord {
+            operation: Some(Operation::Expire(expire.clone())),
+            ..Default::default()
+        };
+        sequence = wal.next_sequence;
+        debug!("Appending sequence #{} to WAL", sequence);
+        wal.append_message(&record)?;
+        store.apply(&record)?;
+        let r = message::Request {
+            command: Some(Command::ReplicateExpire(message::ReplicateExpire {
+                leader_addr: cluster.leader.addr.to_string(),
+                expire: Some(expire.clone()),
+                sequence,
+            })),
+            ..Default::default()
+        };
+        Cluster::replicate(r, None, &cluster.sync_follower, &cluster.async_followers).await?;
+    }
+    store.commit(sequence)?;
+    Ok(expired.len())
+}
+
+pub async fn run_reaper(
+    interval: Duration,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+) {
+    info!("Starting expired key reaper");
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match reap_expired(&store, &wal, &cluster).await {
+            Ok(0) => (),
+            Ok(n) => info!("Reaped {} expired keys", n),
+            Err(e) => error!("Failed to reap expired keys: {}", e),
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::Connected;
+use tonic::transport::Server;
+use tonic::Code;
+
+use super::super::ipc::chunk::async_read_chunks;
+use super::super::ipc::message;
+use super::super::ipc::message::blue_server::{Blue, BlueServer};
+use super::super::ipc::message::request::Command;
+use super::super::ipc::message::Status;
+use super::super::ipc::receiver::async_read_message;
+use super::super::ipc::status::status_of;
+use super::super::ipc::tls::{self, AsyncConnection, Protocol};
+use super::cluster::{Cluster, NodeRole};
+use super::compression::decompress_set;
+use super::engine::StorageEngine;
+use super::handler::{execute, execute_command, Context};
+use super::limits::Limits;
+use super::wal::WriteAheadLog;
+use super::watch::watch_handler;
+
+// Bytes of a watch's output held between the watch and its gRPC stream
+static WATCH_PIPE_SIZE: usize = 1024 * 1024;
+// Events held for a slow gRPC client before the watch waits for it
+static WATCH_EVENTS: usize = 64;
+// Connections through their TLS handshake but not yet taken up by the server
+static ACCEPTED_CONNECTIONS: usize = 64;
+
+type RpcResult<T> = Result<tonic::Response<T>, tonic::Status>;
+
+/// The gRPC code closest to a Blue status
+fn code_of(status: Status) -> Code {
+    match status {
+        Status::Ok => Code::Ok,
+        Status::NotFound => Code::NotFound,
+        Status::NotLeader => Code::FailedPrecondition,
+        Status::InvalidArgument => Code::InvalidArgument,
+        Status::OutOfRange => Code::OutOfRange,
+        Status::Timeout => Code::DeadlineExceeded,
+        Status::Unavailable => Code::Unavailable,
+        Status::Internal => Code::Internal,
+        Status::PermissionDenied => Code::PermissionDenied,
+    }
+}
+
+fn to_status(e: io::Error) -> tonic::Status {
+    tonic::Status::new(code_of(status_of(&e)), e.to_string())
+}
+
+/// Reads a watch event, and its value if that followed in chunks
+async fn read_event<R>(reader: &mut R) -> io::Result<message::WatchEvent>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut event = async_read_message::<message::WatchEvent, _>(reader).await?;
+    if event.chunked {
+        event.value = async_read_chunks(reader, usize::MAX).await?;
+        event.chunked = false;
+    }
+    Ok(event)
+}
+
+/// A connection to the gRPC listener, over TLS if it is configured
+struct GrpcConnection {
+    stream: Box<dyn AsyncConnection>,
+    peer: GrpcPeer,
+}
+
+/// Who an RPC came from, found in its extensions
+#[derive(Debug, Clone)]
+struct GrpcPeer {
+    addr: SocketAddr,
+    // Whether the peer can be trusted as a node, as `tls::accept` decided
+    is_node: bool,
+}
+
+impl Connected for GrpcConnection {
+    type ConnectInfo = GrpcPeer;
+
+    fn connect_info(&self) -> GrpcPeer {
+        self.peer.clone()
+    }
+}
+
+impl AsyncRead for GrpcConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GrpcConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections for the gRPC server, going through the TLS handshake of each on a task of
+/// its own so that a slow client holds up no other
+async fn accept_connections(
+    listener: TcpListener,
+    connections: mpsc::Sender<io::Result<GrpcConnection>>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let _ = connections.send(Err(e)).await;
+                return;
+            }
+        };
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            match tls::accept(stream, Protocol::Grpc).await {
+                Ok((stream, is_node)) => {
+                    let peer = GrpcPeer { addr, is_node };
+                    // Only fails once the server has stopped
+                    let _ = connections.send(Ok(GrpcConnection { stream, peer })).await;
+                }
+                Err(e) => error!("gRPC connection from {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// The `Blue` gRPC service. Each RPC is run as a Blue request through `execute`, on a context of
+/// its own
+pub struct BlueService {
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+    // Address the gRPC server listens on
+    local: SocketAddr,
+}
+
+impl BlueService {
+    fn context<T>(&self, request: &tonic::Request<T>) -> Context {
+        Context::new(
+            Arc::clone(&self.store),
+            Arc::clone(&self.wal),
+            Arc::clone(&self.cluster),
+            Arc::clone(&self.role),
+            Arc::clone(&self.limits),
+            request
+                .extensions()
+                .get::<GrpcPeer>()
+                .map_or(self.local, |peer| peer.addr),
+            self.local,
+        )
+    }
+
+    async fn run<T>(
+        &self,
+        request: tonic::Request<T>,
+        command: fn(T) -> Command,
+    ) -> RpcResult<message::Response> {
+        let context = self.context(&request);
+        execute_command(&context, command(request.into_inner()))
+            .await
+            .map(tonic::Response::new)
+            .map_err(to_status)
+    }
+
+    /// Admin RPCs are taken from anyone only while TLS is off, as on Blue's own listener. With
+    /// TLS on they need a certificate signed by the cluster CA
+    fn check_admin<T>(request: &tonic::Request<T>) -> io::Result<()> {
+        let peer = request.extensions().get::<GrpcPeer>();
+        match peer.is_some_and(|peer| peer.is_node) {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Backups and restores need a certificate signed by the cluster CA",
+            )),
+        }
+    }
+
+    /// Decompresses a set's value, which has to have been sent inline
+    fn receive_value(&self, set: &mut message::Set) -> io::Result<()> {
+        if set.chunked {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Values are sent inline over gRPC, chunked must not be set",
+            ));
+        }
+        decompress_set(set, self.limits.max_value_size)
+    }
+}
+
+#[tonic::async_trait]
+impl Blue for BlueService {
+    async fn get(&self, request: tonic::Request<message::Get>) -> RpcResult<message::Response> {
+        self.run(request, Command::Get).await
+    }
+
+    async fn set(&self, mut request: tonic::Request<message::Set>) -> RpcResult<message::Response> {
+        self.receive_value(request.get_mut()).map_err(to_status)?;
+        self.run(request, Command::Set).await
+    }
+
+    async fn delete(
+        &self,
+        request: tonic::Request<message::Delete>,
+    ) -> RpcResult<message::Response> {
+        self.run(request, Command::Delete).await
+    }
+
+    async fn multi_get(
+        &self,
+        request: tonic::Request<message::MultiGet>,
+    ) -> RpcResult<message::Response> {
+        self.run(request, Command::MultiGet).await
+    }
+
+    async fn multi_set(
+        &self,
+        mut request: tonic::Request<message::MultiSet>,
+    ) -> RpcResult<message::Response> {
+        for set in request.get_mut().sets.iter_mut() {
+            self.receive_value(set).map_err(to_status)?;
+        }
+        self.run(request, Command::MultiSet).await
+    }
+
+    async fn scan(
+        &self,
+        request: tonic::Request<message::Scan>,
+    ) -> RpcResult<message::ScanResponse> {
+        let context = self.context(&request);
+        let r = message::Request {
+            command: Some(Command::Scan(request.into_inner())),
+            ..Default::default()
+        };
+        let bytes = execute(&context, r).await.map_err(to_status)?;
+        async_read_message::<message::ScanResponse, _>(&mut bytes.as_slice())
+            .await
+            .map(tonic::Response::new)
+            .map_err(to_status)
+    }
+
+    type WatchStream = ReceiverStream<Result<message::WatchEvent, tonic::Status>>;
+
+    /// Runs the watch as it would run over a Blue connection, writing into a pipe that is read
+    /// back into events. Once the client cancels, the watch fails on its next write and stops
+    async fn watch(&self, request: tonic::Request<message::Watch>) -> RpcResult<Self::WatchStream> {
+        let watch = request.into_inner();
+        let (mut writer, mut reader) = tokio::io::duplex(WATCH_PIPE_SIZE);
+        let wal = Arc::clone(&self.wal);
+        tokio::spawn(async move {
+            if let Err(e) = watch_handler(&mut writer, watch, &wal).await {
+                info!("gRPC watch ended: {}", e);
+            }
+        });
+
+        let response = async_read_message::<message::Response, _>(&mut reader)
+            .await
+            .map_err(to_status)?;
+        if !response.success {
+            let status = Status::from_i32(response.status).unwrap_or(Status::Internal);
+            return Err(tonic::Status::new(code_of(status), response.message));
+        }
+        let (sender, receiver) = mpsc::channel(WATCH_EVENTS);
+        tokio::spawn(async move {
+            loop {
+                let event = match read_event(&mut reader).await {
+                    Ok(event) => Ok(event),
+                    // The watch stopped on its own, e.g. because the node is shutting down
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return,
+                    Err(e) => {
+                        warn!("gRPC watch failed: {}", e);
+                        Err(to_status(e))
+                    }
+                };
+                let failed = event.is_err();
+                if sender.send(event).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn stats(&self, request: tonic::Request<message::Stats>) -> RpcResult<message::Response> {
+        self.run(request, Command::Stats).await
+    }
+
+    async fn backup(
+        &self,
+        request: tonic::Request<message::InitiateBackup>,
+    ) -> RpcResult<message::Response> {
+        BlueService::check_admin(&request).map_err(to_status)?;
+        self.run(request, Command::InitiateBackup).await
+    }
+
+    async fn restore(
+        &self,
+        request: tonic::Request<message::RestoreBackup>,
+    ) -> RpcResult<message::Response> {
+        BlueService::check_admin(&request).map_err(to_status)?;
+        self.run(request, Command::RestoreBackup).await
+    }
+}
+
+/// Serves the `Blue` gRPC service on `listener`, alongside Blue's own listener
+pub async fn run_grpc_server(
+    listener: TcpListener,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+) -> io::Result<()> {
+    let service = BlueService {
+        store,
+        wal,
+        cluster,
+        role,
+        limits,
+        local: listener.local_addr()?,
+    };
+    let (connections, incoming) = mpsc::channel(ACCEPTED_CONNECTIONS);
+    tokio::spawn(accept_connections(listener, connections));
+    Server::builder()
+        .add_service(BlueServer::new(service))
+        .serve_with_incoming(ReceiverStream::new(incoming))
+        .await
+        .map_err(io::Error::other)
+}
@@ -1,247 +1,1045 @@
-use std::io;
-use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Write};
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use log::{debug, error, info};
 use serde_json::json;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf};
 use tokio::net::TcpStream as asyncTcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
 
+use super::super::ipc::chunk::{async_read_chunks, async_send_chunks, split_set, take_large_value};
+use super::super::ipc::handshake::accept_session;
 use super::super::ipc::message;
 use super::super::ipc::message::request::Command;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::Status;
 use super::super::ipc::receiver::async_read_message;
 use super::super::ipc::sender::{async_send_message, send_message};
+use super::super::ipc::status;
+use super::super::ipc::tls::{self, async_connect, AsyncConnection, Protocol};
+use super::backup::{backup_handler, restore_handler};
 use super::cluster::{Cluster, NodeRole};
-use super::serialize::persist_store;
+use super::compression::{compress_set, decompress_set, negotiate, STATS};
+use super::engine::{Entry, StorageEngine};
+use super::expire::{now_millis, stamp_expiration};
+use super::limits::Limits;
+use super::multi::{multi_get_handler, multi_set_handler, replicate_batch_handler};
+use super::scan::scan_handler;
 use super::wal::WriteAheadLog;
+use super::watch::watch_handler;
 
-pub async fn handle_stream<'a>(
-    mut stream: asyncTcpStream,
-    store: Arc<Mutex<message::Store>>,
-    store_path: Arc<PathBuf>,
+/// Everything the requests on one connection run against
+#[derive(Clone)]
+pub struct Context {
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
     wal: Arc<Mutex<WriteAheadLog>>,
     cluster: Arc<Mutex<Cluster>>,
     role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+    peer: SocketAddr,
+    local: SocketAddr,
+}
+
+/// The locks held by a request that touches the store
+struct Locked {
+    store: OwnedMutexGuard<Box<dyn StorageEngine>>,
+    wal: OwnedMutexGuard<WriteAheadLog>,
+    cluster: OwnedMutexGuard<Cluster>,
+}
+
+impl Context {
+    pub fn new(
+        store: Arc<Mutex<Box<dyn StorageEngine>>>,
+        wal: Arc<Mutex<WriteAheadLog>>,
+        cluster: Arc<Mutex<Cluster>>,
+        role: Arc<NodeRole>,
+        limits: Arc<Limits>,
+        peer: SocketAddr,
+        local: SocketAddr,
+    ) -> Context {
+        Context {
+            store,
+            wal,
+            cluster,
+            role,
+            limits,
+            peer,
+            local,
+        }
+    }
+
+    pub fn role(&self) -> &NodeRole {
+        &self.role
+    }
+
+    pub fn cluster(&self) -> &Mutex<Cluster> {
+        &self.cluster
+    }
+
+    /// Address of the leader's RESP listener. Empty if it has none
+    pub async fn leader_resp_addr(&self) -> String {
+        self.cluster.lock().await.resp_addr.clone()
+    }
+
+    /// The store lock alone, which is all reads hold, with the sequence of the last write applied
+    /// to the store. Writes keep the store locked until they are applied, so the WAL can't be
+    /// ahead of it
+    async fn lock_store(&self) -> (MutexGuard<'_, Box<dyn StorageEngine>>, u64) {
+        let store = self.store.lock().await;
+        let sequence = self.wal.lock().await.next_sequence - 1;
+        (store, sequence)
+    }
+
+    async fn lock(&self) -> Locked {
+        Locked {
+            store: Arc::clone(&self.store).lock_owned().await,
+            wal: Arc::clone(&self.wal).lock_owned().await,
+            cluster: Arc::clone(&self.cluster).lock_owned().await,
+        }
+    }
+}
+
+/// Whether a request may run alongside the others on its connection. Only client requests with
+/// an ID may, as everything else either expects one request at a time or takes over the
+/// connection
+fn is_pipelined(request: &message::Request) -> bool {
+    request.request_id != 0
+        && matches!(
+            request.command,
+            Some(Command::Get(_))
+                | Some(Command::MultiGet(_))
+                | Some(Command::Set(_))
+                | Some(Command::MultiSet(_))
+                | Some(Command::Delete(_))
+                | Some(Command::Scan(_))
+                | Some(Command::Stats(_))
+                | Some(Command::InitiateBackup(_))
+                | Some(Command::RestoreBackup(_))
+        )
+}
+
+/// Whether a request is one only other nodes, or administrators acting as one, send. With TLS
+/// on, these are only taken from peers holding a certificate signed by the cluster CA
+fn is_privileged(request: &message::Request) -> bool {
+    matches!(
+        request.command,
+        Some(Command::FollowRequest(_))
+            | Some(Command::SynchronizeRequest(_))
+            | Some(Command::ReplicateSet(_))
+            | Some(Command::ReplicateDelete(_))
+            | Some(Command::ReplicateExpire(_))
+            | Some(Command::ReplicateBatch(_))
+            | Some(Command::ReplicateResponse(_))
+            | Some(Command::InitiateBackup(_))
+            | Some(Command::RestoreBackup(_))
+    )
+}
+
+/// Whether a request runs under the store, WAL and cluster locks. Reads only take the store lock
+/// once they run
+fn is_locked(request: &message::Request) -> bool {
+    !matches!(
+        request.command,
+        Some(Command::Watch(_))
+            | Some(Command::Stats(_))
+            | Some(Command::InitiateBackup(_))
+            | Some(Command::Get(_))
+            | Some(Command::MultiGet(_))
+            | Some(Command::Scan(_))
+    )
+}
+
+pub async fn handle_stream(
+    stream: asyncTcpStream,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
 ) -> io::Result<()> {
+    let context = Context::new(
+        store,
+        wal,
+        cluster,
+        role,
+        limits,
+        stream.peer_addr()?,
+        stream.local_addr()?,
+    );
+    let (stream, is_node) = tls::accept(stream, Protocol::Blue).await?;
+    let (mut reader, writer) = tokio::io::split(stream);
+    // Shared with pipelined requests, each of which writes its whole response at once
+    let writer = Arc::new(Mutex::new(writer));
     loop {
-        info!("Handling stream: {:?}", stream);
-        let input = async_read_message::<message::Request>(&mut stream).await;
-        match input {
-            Ok(r) => {
-                let mut store = store.lock().await;
-                let mut wal = wal.lock().await;
-                let mut cluster = cluster.lock().await;
-
-                match r.command {
-                    Some(Command::FollowRequest(follow)) => {
-                        follow_request_handler(follow, &mut cluster, &mut stream).await?;
-                        debug!("New cluster: {:?}", cluster);
-                    }
-                    Some(Command::InitiateSession(initiate_session)) => {
-                        initiate_session_handler(&mut stream, initiate_session).await?
-                    }
-                    Some(Command::SynchronizeRequest(synchronize_request)) => {
-                        synchronize_request_handler(&mut stream, synchronize_request, &wal).await?
-                    }
-                    Some(Command::Get(get)) => get_handler(&mut stream, get, &mut store).await?,
-                    Some(Command::Set(set)) => match *role {
-                        NodeRole::Leader => {
-                            async_set_handler(&mut stream, &set, &mut store).await?;
-                            persist_store(&mut store, &store_path)?;
-                            let sequence = wal.next_sequence;
-                            debug!("Appending sequence #{} to WAL", sequence);
-                            wal.append_message(&set)?;
-                            let r = message::Request {
-                                command: Some(Command::ReplicateSet(message::ReplicateSet {
-                                    leader_addr: cluster.leader.addr.to_string(),
-                                    set: Some(set),
-                                    sequence,
-                                })),
-                            };
-                            Cluster::replicate(r, &cluster.sync_follower, &cluster.async_followers)
-                                .await?;
-                            info!("Replicated set command");
-                        }
-                        NodeRole::Follower => {
-                            let response = message::Response {
-                                success: false,
-                                message: "Only the leader accepts writes".to_string(),
-                            };
-                            async_send_message(response, &mut stream).await?;
-                            error!("Only the leader accepts writes");
-                        }
-                    },
-                    Some(Command::ReplicateSet(replicate_set)) => {
-                        let peer = stream.peer_addr()?;
-                        info!("Replication request from {}", peer);
-                        if peer == cluster.leader.addr {
-                            error!("Leader does not accept replication requests");
-                        } else {
-                            replicate_set_handler(&replicate_set, &mut store)?;
-                            persist_store(&mut store, &store_path)?;
-                            wal.append_message(&replicate_set)?;
-                        }
-                    }
-                    Some(Command::ReplicateResponse(replicate_response)) => {
-                        println!("{:?}", replicate_response)
-                    }
-                    None => error!("Figure this out"),
-                }
-            }
+        info!("Handling stream from {}", context.peer);
+        let mut r = match async_read_message::<message::Request, _>(&mut reader).await {
+            Ok(r) => r,
             Err(_) => {
                 error!("Unknown command");
                 return Ok(());
             }
+        };
+        if !is_node && is_privileged(&r) {
+            let response = message::Response {
+                request_id: r.request_id,
+                ..status::failure(
+                    Status::PermissionDenied,
+                    "Node and admin requests need a certificate signed by the cluster CA"
+                        .to_string(),
+                )
+            };
+            async_send_message(response, &mut *writer.lock().await).await?;
+            error!(
+                "Rejected node or admin request from {}: no certificate",
+                context.peer
+            );
+            return Ok(());
+        }
+        let received = match &mut r.command {
+            Some(Command::Set(set)) => {
+                receive_value(&mut reader, set, context.limits.max_value_size).await
+            }
+            Some(Command::ReplicateSet(message::ReplicateSet { set: Some(set), .. })) => {
+                receive_value(&mut reader, set, usize::MAX).await
+            }
+            Some(Command::MultiSet(multi_set)) => {
+                receive_values(
+                    &mut reader,
+                    &mut multi_set.sets,
+                    context.limits.max_value_size,
+                )
+                .await
+            }
+            Some(Command::ReplicateBatch(replicate_batch)) => {
+                receive_values(&mut reader, &mut replicate_batch.sets, usize::MAX).await
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = received {
+            if e.kind() != ErrorKind::InvalidInput {
+                return Err(e);
+            }
+            let response = message::Response {
+                request_id: r.request_id,
+                ..status::from_error(&e)
+            };
+            async_send_message(response, &mut *writer.lock().await).await?;
+            error!("Rejected set: {}", e);
+            continue;
+        }
+
+        if !is_pipelined(&r) {
+            let mut writer = writer.lock().await;
+            match run(&context, r, &mut *writer, None).await? {
+                true => continue,
+                false => return Ok(()),
+            }
+        }
+        // Locks are taken in the order requests arrive, so that pipelined writes are applied in
+        // the order they were sent. Only the responses may be sent out of order. Reads take the
+        // store lock once they run instead, so they may see writes sent after them
+        let locked = match is_locked(&r) {
+            true => Some(context.lock().await),
+            false => None,
+        };
+        let context = context.clone();
+        let writer = Arc::clone(&writer);
+        tokio::spawn(async move {
+            let request_id = r.request_id;
+            let mut response = Vec::new();
+            let result = run(&context, r, &mut response, locked).await;
+            complete(&writer, request_id, result, response).await;
+        });
+    }
+}
+
+/// Runs a request as if it had arrived on a connection of its own, returning what would have been
+/// sent back. Lets other protocols take the same paths as Blue's own clients. Watches, which never
+/// finish, can't be run this way
+pub async fn execute(context: &Context, request: message::Request) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    run(context, request, &mut response, None).await?;
+    Ok(response)
+}
+
+/// Runs a command through `execute` and decodes its response, along with any values that followed
+/// it in chunks. Scans, which are answered with a `ScanResponse`, have to use `execute`
+pub async fn execute_command(context: &Context, command: Command) -> io::Result<message::Response> {
+    let request = message::Request {
+        command: Some(command),
+        ..Default::default()
+    };
+    let bytes = execute(context, request).await?;
+    let mut reader = bytes.as_slice();
+    let mut response = async_read_message::<message::Response, _>(&mut reader).await?;
+    if response.chunked {
+        response.value = async_read_chunks(&mut reader, usize::MAX).await?;
+        response.chunked = false;
+    }
+    for result in response.results.iter_mut().filter(|result| result.chunked) {
+        result.value = async_read_chunks(&mut reader, usize::MAX).await?;
+        result.chunked = false;
+    }
+    Ok(response)
+}
+
+/// Sends a pipelined request's response, or an error in its place if the request failed
+async fn complete(
+    writer: &Mutex<WriteHalf<Box<dyn AsyncConnection>>>,
+    request_id: u64,
+    result: io::Result<bool>,
+    mut response: Vec<u8>,
+) {
+    if let Err(e) = result {
+        error!("Request #{} failed: {}", request_id, e);
+        response.clear();
+        let failure = message::Response {
+            request_id,
+            ..status::from_error(&e)
+        };
+        if let Err(e) = async_send_message(failure, &mut response).await {
+            error!(
+                "Could not describe the failure of request #{}: {}",
+                request_id, e
+            );
+            return;
+        }
+    }
+    let mut writer = writer.lock().await;
+    let written = match writer.write_all(&response).await {
+        Ok(()) => writer.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        error!("Could not answer request #{}: {}", request_id, e);
+    }
+}
+
+/// Runs one request, writing its response to `stream`. Reads take only the store lock. Other
+/// requests that touch the store run under `locked`, or under locks taken here if none were
+/// passed. Returns whether the connection should stay open
+async fn run<W>(
+    context: &Context,
+    r: message::Request,
+    stream: &mut W,
+    locked: Option<Locked>,
+) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    let request_id = r.request_id;
+    match r.command {
+        Some(Command::Watch(watch)) => {
+            // A watch takes over the connection, so it can't hold the locks below
+            watch_handler(stream, watch, &context.wal).await?;
+            return Ok(false);
+        }
+        Some(Command::Stats(_)) => {
+            stats_handler(stream, request_id).await?;
+            return Ok(true);
+        }
+        Some(Command::Get(get)) => {
+            let (store, sequence) = context.lock_store().await;
+            get_handler(stream, get, request_id, store.as_ref(), sequence).await?;
+            return Ok(true);
+        }
+        Some(Command::MultiGet(multi_get)) => {
+            let (store, sequence) = context.lock_store().await;
+            multi_get_handler(stream, multi_get, request_id, store.as_ref(), sequence).await?;
+            return Ok(true);
+        }
+        Some(Command::Scan(scan)) => {
+            let store = context.store.lock().await;
+            scan_handler(stream, scan, request_id, store.as_ref()).await?;
+            return Ok(true);
+        }
+        Some(Command::InitiateBackup(backup)) => {
+            // Takes the locks only for as long as it needs them
+            backup_handler(
+                stream,
+                backup,
+                request_id,
+                context.local,
+                &context.store,
+                &context.wal,
+            )
+            .await?;
+            return Ok(true);
+        }
+        _ => {}
+    }
+
+    let mut locked = match locked {
+        Some(locked) => locked,
+        None => context.lock().await,
+    };
+    let store = &mut locked.store;
+    let wal = &mut locked.wal;
+    let cluster = &mut locked.cluster;
+
+    match r.command {
+        Some(Command::FollowRequest(follow)) => {
+            follow_request_handler(follow, cluster, stream).await?;
+            debug!("New cluster: {:?}", **cluster);
+        }
+        Some(Command::InitiateSession(initiate_session)) => {
+            return initiate_session_handler(stream, initiate_session, cluster).await;
+        }
+        Some(Command::SynchronizeRequest(synchronize_request)) => {
+            synchronize_request_handler(stream, synchronize_request, wal, store.as_ref()).await?
+        }
+        Some(Command::Set(mut set)) => match *context.role {
+            NodeRole::Leader => match keep_value(&mut set, store.as_ref())
+                .and_then(|()| context.limits.check_set(&set))
+                .and_then(|()| stamp_expiration(&mut set))
+            {
+                Ok(()) => {
+                    let sequence = wal.next_sequence;
+                    debug!("Appending sequence #{} to WAL", sequence);
+                    wal.append_message(&message::WalRecord {
+                        operation: Some(Operation::Set(set.clone())),
+                        ..Default::default()
+                    })?;
+                    async_set_handler(stream, &set, request_id, store.as_mut(), sequence).await?;
+                    store.commit(sequence)?;
+                    cluster.replicate_set(&set, sequence).await?;
+                    info!("Replicated set command");
+                }
+                Err(e) => {
+                    let response = message::Response {
+                        request_id,
+                        ..status::from_error(&e)
+                    };
+                    async_send_message(response, stream).await?;
+                    error!("Rejected set: {}", e);
+                }
+            },
+            NodeRole::Follower => {
+                let response = message::Response {
+                    request_id,
+                    ..status::not_leader(cluster.leader.addr)
+                };
+                async_send_message(response, stream).await?;
+                error!("Only the leader accepts writes");
+            }
+        },
+        Some(Command::MultiSet(multi_set)) => match *context.role {
+            NodeRole::Leader => {
+                multi_set_handler(
+                    stream,
+                    multi_set,
+                    request_id,
+                    store.as_mut(),
+                    wal,
+                    cluster,
+                    &context.limits,
+                )
+                .await?
+            }
+            NodeRole::Follower => {
+                let response = message::Response {
+                    request_id,
+                    ..status::not_leader(cluster.leader.addr)
+                };
+                async_send_message(response, stream).await?;
+                error!("Only the leader accepts writes");
+            }
+        },
+        Some(Command::Delete(delete)) => match *context.role {
+            NodeRole::Leader => {
+                let sequence = wal.next_sequence;
+                if delete_handler(stream, &delete, request_id, store.as_mut(), sequence).await? {
+                    debug!("Appending sequence #{} to WAL", sequence);
+                    wal.append_message(&message::WalRecord {
+                        operation: Some(Operation::Delete(delete.clone())),
+                        ..Default::default()
+                    })?;
+                    store.commit(sequence)?;
+                    let r = message::Request {
+                        command: Some(Command::ReplicateDelete(message::ReplicateDelete {
+                            leader_addr: cluster.leader.addr.to_string(),
+                            delete: Some(delete),
+                            sequence,
+                        })),
+                        ..Default::default()
+                    };
+                    Cluster::replicate(r, None, &cluster.sync_follower, &cluster.async_followers)
+                        .await?;
+                    info!("Replicated delete command");
+                }
+            }
+            NodeRole::Follower => {
+                let response = message::Response {
+                    request_id,
+                    ..status::not_leader(cluster.leader.addr)
+                };
+                async_send_message(response, stream).await?;
+                error!("Only the leader accepts writes");
+            }
+        },
+        Some(Command::ReplicateSet(replicate_set)) => {
+            info!("Replication request from {}", context.peer);
+            if context.peer == cluster.leader.addr {
+                error!("Leader does not accept replication requests");
+            } else {
+                let sequence = wal.next_sequence;
+                wal.append_message(&message::WalRecord {
+                    operation: replicate_set.set.clone().map(Operation::Set),
+                    ..Default::default()
+                })?;
+                replicate_set_handler(&replicate_set, store.as_mut()).await?;
+                store.commit(sequence)?;
+            }
+        }
+        Some(Command::ReplicateBatch(replicate_batch)) => {
+            info!(
+                "Replication of {} sets from {}",
+                replicate_batch.sets.len(),
+                context.peer
+            );
+            if !replicate_batch.sets.is_empty() {
+                let records: Vec<message::WalRecord> = replicate_batch
+                    .sets
+                    .iter()
+                    .map(|set| message::WalRecord {
+                        operation: Some(Operation::Set(set.clone())),
+                        ..Default::default()
+                    })
+                    .collect();
+                let sequence = wal.append_messages(&records)?;
+                replicate_batch_handler(&replicate_batch, store.as_mut()).await?;
+                store.commit(sequence + records.len() as u64 - 1)?;
+            }
+        }
+        Some(Command::ReplicateExpire(replicate_expire)) => {
+            info!("Expire replication request from {}", context.peer);
+            let sequence = wal.next_sequence;
+            wal.append_message(&message::WalRecord {
+                operation: replicate_expire.expire.clone().map(Operation::Expire),
+                ..Default::default()
+            })?;
+            replicate_expire_handler(&replicate_expire, store.as_mut()).await?;
+            store.commit(sequence)?;
+        }
+        Some(Command::ReplicateDelete(replicate_delete)) => {
+            info!("Delete replication request from {}", context.peer);
+            let sequence = wal.next_sequence;
+            wal.append_message(&message::WalRecord {
+                operation: replicate_delete.delete.clone().map(Operation::Delete),
+                ..Default::default()
+            })?;
+            replicate_delete_handler(&replicate_delete, store.as_mut()).await?;
+            store.commit(sequence)?;
+        }
+        Some(Command::RestoreBackup(restore_backup)) => match *context.role {
+            NodeRole::Leader => {
+                restore_handler(
+                    stream,
+                    restore_backup,
+                    request_id,
+                    store.as_mut(),
+                    wal,
+                    cluster,
+                )
+                .await?
+            }
+            NodeRole::Follower => {
+                let response = message::Response {
+                    request_id,
+                    ..status::not_leader(cluster.leader.addr)
+                };
+                async_send_message(response, stream).await?;
+                error!("Only the leader accepts writes");
+            }
+        },
+        Some(Command::Watch(_))
+        | Some(Command::Stats(_))
+        | Some(Command::InitiateBackup(_))
+        | Some(Command::Get(_))
+        | Some(Command::MultiGet(_))
+        | Some(Command::Scan(_)) => {
+            unreachable!("Handled before locking")
         }
+        Some(Command::ReplicateResponse(replicate_response)) => {
+            println!("{:?}", replicate_response)
+        }
+        None => error!("Figure this out"),
     }
+    Ok(true)
 }
 
-async fn follow_request_handler(
+async fn follow_request_handler<W>(
     follow_request: message::FollowRequest,
     cluster: &mut Cluster,
-    stream: &mut asyncTcpStream,
-) -> io::Result<()> {
+    stream: &mut W,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
     let follower_addr = SocketAddr::from_str(follow_request.follower_addr.as_str()).unwrap();
     info!("Adding follower: {:?}", follower_addr);
-    cluster.add_follower(follower_addr, stream).await?;
+    cluster
+        .add_follower(follower_addr, follow_request.compression, stream)
+        .await?;
     Ok(())
 }
 
-async fn initiate_session_handler(
-    stream: &mut asyncTcpStream,
+/// Agrees on a protocol version with the peer. Returns false if the peer was rejected, after which
+/// the connection is closed. Peers that never initiate a session are treated as version 1 peers
+async fn initiate_session_handler<W>(
+    stream: &mut W,
     initiate_session: message::InitiateSession,
-) -> io::Result<()> {
-    info!("Initiating session with {}", initiate_session.name);
-    let welcome = message::Welcome {
-        message: "Welcome to Blue!\n".to_string(),
+    cluster: &Cluster,
+) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    let welcome = match accept_session(&initiate_session) {
+        Ok(session) => {
+            info!(
+                "Initiating session with {} at protocol version {}",
+                session.peer, session.protocol_version
+            );
+            message::Welcome {
+                message: "Welcome to Blue!\n".to_string(),
+                rejected: false,
+                protocol_version: session.protocol_version,
+                node_id: cluster.node_id.clone(),
+                features: session.features,
+            }
+        }
+        Err(reason) => {
+            error!(
+                "Rejected session with {}: {}",
+                initiate_session.name, reason
+            );
+            message::Welcome {
+                message: reason,
+                rejected: true,
+                node_id: cluster.node_id.clone(),
+                ..Default::default()
+            }
+        }
     };
-    async_send_message(welcome, stream).await
+    let accepted = !welcome.rejected;
+    async_send_message(welcome, stream).await?;
+    Ok(accepted)
 }
 
-async fn synchronize_request_handler<'a>(
-    stream: &mut asyncTcpStream,
+async fn synchronize_request_handler<W>(
+    stream: &mut W,
     request_synchronize: message::SynchronizeRequest,
     wal: &WriteAheadLog,
-) -> io::Result<()> {
-    info!(
-        "Synchronization requested: {:?}\nFrom: {:?}",
-        request_synchronize, stream
-    );
+    store: &dyn StorageEngine,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Synchronization requested: {:?}", request_synchronize);
     let seq_start = request_synchronize.next_sequence;
+    let compression = negotiate(request_synchronize.compression);
+    let latest_sequence = wal.next_sequence - 1;
+
+    // The records the follower is missing have been pruned, so it gets the whole store instead.
+    // Writes wait on the lock held here, so the store is exactly as of the latest sequence
+    if seq_start < wal.first_sequence() {
+        let snapshot = store.snapshot()?;
+        info!(
+            "#{} has been pruned from the WAL, sending {} records as of #{} instead",
+            seq_start,
+            snapshot.records.len(),
+            latest_sequence
+        );
+        let synchronize_response = message::SynchronizeResponse {
+            latest_sequence,
+            compression: compression as i32,
+            status: Status::OutOfRange as i32,
+            snapshot_records: snapshot.records.len() as u64,
+        };
+        async_send_message(synchronize_response, stream).await?;
+        for record in snapshot.records {
+            let set = message::Set {
+                key: record.key,
+                value: record.value,
+                expires_at: record.expires_at,
+                ..Default::default()
+            };
+            let record = message::WalRecord {
+                operation: Some(Operation::Set(set)),
+                ..Default::default()
+            };
+            send_synchronized_record(record, compression, stream).await?;
+        }
+        info!("Synchronization complete");
+        return Ok(());
+    }
 
     let synchronize_response = message::SynchronizeResponse {
-        latest_sequence: wal.next_sequence - 1,
+        latest_sequence,
+        compression: compression as i32,
+        ..Default::default()
     };
     async_send_message(synchronize_response, stream).await?;
     if seq_start == wal.next_sequence {
         info!("Synchronization not required. Follower already up to date");
         return Ok(());
     }
-    let messages = wal.clone().messages()?;
-    debug!("WAL Messages: {:?}", messages);
-    for item in messages {
-        if item.0 >= seq_start {
-            let seq_bytes = item.0.to_le_bytes();
-            stream.write_all(&seq_bytes).await?;
-            debug!("Sending sequence: {}", item.0);
-            async_send_message(item.1.clone(), stream).await?
-        };
-        info!("Synchronization complete");
+    for item in wal.iter_from(seq_start)? {
+        let (sequence, record) = item?;
+        let seq_bytes = sequence.to_le_bytes();
+        stream.write_all(&seq_bytes).await?;
+        debug!("Sending sequence: {}", sequence);
+        send_synchronized_record(record, compression, stream).await?;
     }
+    info!("Synchronization complete");
     Ok(())
 }
 
-async fn get_handler(
-    stream: &mut asyncTcpStream,
-    get: message::Get,
-    store: &mut message::Store,
-) -> io::Result<()> {
-    info!("Getting key={}", get.key);
-    let m = if get.key.is_empty() {
-        message::Response {
-            success: false,
-            message: json!(store.records).to_string(),
+/// Sends a record to a synchronizing follower, with a large value following in chunks
+async fn send_synchronized_record<W>(
+    mut record: message::WalRecord,
+    compression: message::Compression,
+    stream: &mut W,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let value = match &mut record.operation {
+        Some(Operation::Set(set)) => {
+            compress_set(set, compression, &STATS.replication)?;
+            split_set(set)
+        }
+        _ => None,
+    };
+    async_send_message(record, stream).await?;
+    if let Some(value) = value {
+        async_send_chunks(&value, stream).await?;
+    }
+    Ok(())
+}
+
+/// Fills in the value of a set that only changes when its key expires. Fails if the key doesn't
+/// exist
+pub fn keep_value(set: &mut message::Set, store: &dyn StorageEngine) -> io::Result<()> {
+    if !set.keep_value {
+        return Ok(());
+    }
+    match store
+        .get(&set.key)?
+        .filter(|entry| !entry.is_expired(now_millis()))
+    {
+        Some(entry) => {
+            set.value = entry.value;
+            set.keep_value = false;
+            Ok(())
         }
+        None => Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("Unknown key '{}'", String::from_utf8_lossy(&set.key)),
+        )),
+    }
+}
+
+/// Reads a value that follows its set in chunks and decompresses it. This is done before any lock
+/// is taken so that a slow upload doesn't hold up other clients
+async fn receive_value<R>(stream: &mut R, set: &mut message::Set, limit: usize) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    if set.chunked {
+        set.value = async_read_chunks(stream, limit).await?;
+        set.chunked = false;
+    }
+    decompress_set(set, limit)
+}
+
+/// Reads the values of a batch of sets, as `receive_value` does for one. Every value is read even
+/// once one has failed, so that the stream stays in step with the sender
+async fn receive_values<R>(
+    stream: &mut R,
+    sets: &mut [message::Set],
+    limit: usize,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut received = Ok(());
+    for set in sets {
+        let result = receive_value(stream, set, limit).await;
+        if let Err(e) = result {
+            if e.kind() != ErrorKind::InvalidInput {
+                return Err(e);
+            }
+            if received.is_ok() {
+                received = Err(e);
+            }
+        }
+    }
+    received
+}
+
+/// Reports how well compression is doing, as JSON
+async fn stats_handler<W>(stream: &mut W, request_id: u64) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = message::Response {
+        request_id,
+        ..status::ok(json!({ "compression": STATS.to_json() }).to_string())
+    };
+    async_send_message(response, stream).await
+}
+
+async fn get_handler<W>(
+    stream: &mut W,
+    get: message::Get,
+    request_id: u64,
+    store: &dyn StorageEngine,
+    sequence: u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Getting key={}", String::from_utf8_lossy(&get.key));
+    let now = now_millis();
+    let mut m = if get.key.is_empty() {
+        let snapshot = store.snapshot()?;
+        // JSON can only hold text, so binary keys and values are shown lossily
+        let live: BTreeMap<Cow<str>, Cow<str>> = snapshot
+            .records
+            .iter()
+            .filter(|r| r.expires_at == 0 || r.expires_at > now)
+            .map(|r| {
+                (
+                    String::from_utf8_lossy(&r.key),
+                    String::from_utf8_lossy(&r.value),
+                )
+            })
+            .collect();
+        status::ok(json!(live).to_string())
     } else {
-        let value = store.records.get(&get.key);
-        let msg = match value {
-            Some(v) => message::Response {
-                success: true,
-                message: v.clone(),
+        let value = store.get(&get.key)?.filter(|entry| !entry.is_expired(now));
+        match value {
+            Some(entry) => message::Response {
+                value: entry.value,
+                expires_at: entry.expires_at,
+                ..status::ok(String::new())
             },
-            None => message::Response {
-                success: false,
-                message: format!("Unknown key '{}'", &get.key),
-            },
-        };
-        msg
+            None => status::failure(
+                Status::NotFound,
+                format!("Unknown key '{}'", String::from_utf8_lossy(&get.key)),
+            ),
+        }
     };
-    async_send_message(m, stream).await
+    m.sequence = sequence;
+    m.request_id = request_id;
+    let value = take_large_value(&mut m.value);
+    m.chunked = value.is_some();
+    async_send_message(m, stream).await?;
+    match value {
+        Some(value) => async_send_chunks(&value, stream).await,
+        None => Ok(()),
+    }
 }
 
-async fn async_set_handler(
-    stream: &mut asyncTcpStream,
+async fn async_set_handler<W>(
+    stream: &mut W,
     set: &message::Set,
-    store: &mut message::Store,
-) -> io::Result<()> {
-    info!("Storing {}={}", set.key, set.value);
-    store.records.insert(set.key.clone(), set.value.clone());
+    request_id: u64,
+    store: &mut dyn StorageEngine,
+    sequence: u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!(
+        "Storing {} ({} bytes)",
+        String::from_utf8_lossy(&set.key),
+        set.value.len()
+    );
+    store.put(
+        &set.key,
+        Entry {
+            value: set.value.clone(),
+            expires_at: set.expires_at,
+        },
+    )?;
     let msg = message::Response {
-        success: true,
-        message: "Succesfully wrote key to in memory store".to_string(),
+        sequence,
+        request_id,
+        ..status::ok("Succesfully wrote key to in memory store".to_string())
     };
     async_send_message(msg, stream).await?;
 
     Ok(())
 }
 
-pub fn set_handler(
-    stream: &mut TcpStream,
+pub fn set_handler<W: Write + ?Sized>(
+    stream: &mut W,
     set: &message::Set,
-    store: &mut message::Store,
+    store: &mut dyn StorageEngine,
 ) -> io::Result<()> {
-    info!("Storing {}={}", set.key, set.value);
-    store.records.insert(set.key.clone(), set.value.clone());
-    let msg = message::Response {
-        success: true,
-        message: "Succesfully wrote key to in memory store".to_string(),
-    };
+    info!(
+        "Storing {} ({} bytes)",
+        String::from_utf8_lossy(&set.key),
+        set.value.len()
+    );
+    store.put(
+        &set.key,
+        Entry {
+            value: set.value.clone(),
+            expires_at: set.expires_at,
+        },
+    )?;
+    let msg = status::ok("Succesfully wrote key to in memory store".to_string());
     send_message(msg, stream)?;
 
     Ok(())
 }
 
-pub fn replicate_set_handler(
+pub async fn replicate_set_handler(
     replicate_set: &message::ReplicateSet,
-    store: &mut message::Store,
+    store: &mut dyn StorageEngine,
 ) -> io::Result<()> {
     if let Some(set) = replicate_set.clone().set {
-        info!("Storing {}={}", set.key, set.value);
-        store.records.insert(set.key, set.value);
+        info!(
+            "Storing {} ({} bytes)",
+            String::from_utf8_lossy(&set.key),
+            set.value.len()
+        );
+        store.apply(&message::WalRecord {
+            operation: Some(Operation::Set(set)),
+            ..Default::default()
+        })?;
     }
 
-    let mut stream = TcpStream::connect(&replicate_set.leader_addr)?;
+    let mut stream = async_connect(&replicate_set.leader_addr).await?;
     let msg = message::Request {
         command: Some(Command::ReplicateResponse(message::ReplicateResponse {
             success: true,
             sequence: replicate_set.sequence,
         })),
+        ..Default::default()
     };
-    send_message(msg, &mut stream)?;
+    async_send_message(msg, &mut stream).await?;
+
+    Ok(())
+}
+
+/// Removes a key on the leader. Returns whether the key existed, as only then does the delete
+/// need to be written to the WAL and replicated.
+async fn delete_handler<W>(
+    stream: &mut W,
+    delete: &message::Delete,
+    request_id: u64,
+    store: &mut dyn StorageEngine,
+    sequence: u64,
+) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Deleting key={}", String::from_utf8_lossy(&delete.key));
+    let now = now_millis();
+    // An expired key is left for the reaper so its removal is logged as an expiry
+    let existed = match store.get(&delete.key)? {
+        Some(entry) => !entry.is_expired(now),
+        None => false,
+    };
+    let mut msg = match existed {
+        true => {
+            store.delete(&delete.key)?;
+            message::Response {
+                sequence,
+                ..status::ok("Succesfully deleted key from in memory store".to_string())
+            }
+        }
+        false => status::failure(
+            Status::NotFound,
+            format!("Unknown key '{}'", String::from_utf8_lossy(&delete.key)),
+        ),
+    };
+    msg.request_id = request_id;
+    async_send_message(msg, stream).await?;
+
+    Ok(existed)
+}
+
+pub async fn replicate_delete_handler(
+    replicate_delete: &message::ReplicateDelete,
+    store: &mut dyn StorageEngine,
+) -> io::Result<()> {
+    if let Some(delete) = &replicate_delete.delete {
+        info!("Deleting key={}", String::from_utf8_lossy(&delete.key));
+        store.delete(&delete.key)?;
+    }
+
+    let mut stream = async_connect(&replicate_delete.leader_addr).await?;
+    let msg = message::Request {
+        command: Some(Command::ReplicateResponse(message::ReplicateResponse {
+            success: true,
+            sequence: replicate_delete.sequence,
+        })),
+        ..Default::default()
+    };
+    async_send_message(msg, &mut stream).await?;
+
+    Ok(())
+}
+
+pub async fn replicate_expire_handler(
+    replicate_expire: &message::ReplicateExpire,
+    store: &mut dyn StorageEngine,
+) -> io::Result<()> {
+    if let Some(expire) = replicate_expire.clone().expire {
+        info!("Expiring key={}", String::from_utf8_lossy(&expire.key));
+        store.apply(&message::WalRecord {
+            operation: Some(Operation::Expire(expire)),
+            ..Default::default()
+        })?;
+    }
+
+    let mut stream = async_connect(&replicate_expire.leader_addr).await?;
+    let msg = message::Request {
+        command: Some(Command::ReplicateResponse(message::ReplicateResponse {
+            success: true,
+            sequence: replicate_expire.sequence,
+        })),
+        ..Default::default()
+    };
+    async_send_message(msg, &mut stream).await?;
 
     Ok(())
 }
 
 pub fn synchronize_handler(
     // stream: &mut asyncTcpStream,
-    set: &message::Set,
-    store: &mut message::Store,
+    record: &message::WalRecord,
+    store: &mut dyn StorageEngine,
 ) -> io::Result<()> {
     // TODO: Send message back to leader to remove from WAL
-    store.records.insert(set.key.clone(), set.value.clone());
-    info!("Synchronized {}={} from leader", set.key, set.value);
+    store.apply(record)?;
+    match &record.operation {
+        Some(Operation::Set(set)) => info!(
+            "Synchronized {} from leader",
+            String::from_utf8_lossy(&set.key)
+        ),
+        Some(Operation::Expire(expire)) => {
+            info!(
+                "Synchronized expiry of {} from leader",
+                String::from_utf8_lossy(&expire.key)
+            )
+        }
+        Some(Operation::Delete(delete)) => {
+            info!(
+                "Synchronized delete of {} from leader",
+                String::from_utf8_lossy(&delete.key)
+            )
+        }
+        None => error!("Empty WAL record received from leader"),
+    }
     Ok(())
 }
@@ -0,0 +1,460 @@
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::{error, info};
+use serde_json::{json, Value};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream as asyncTcpStream};
+use tokio::sync::Mutex;
+
+use super::super::client::format::{decode_bytes, OutputFormat};
+use super::super::ipc::message;
+use super::super::ipc::message::request::Command;
+use super::super::ipc::message::Status;
+use super::super::ipc::status::status_of;
+use super::super::ipc::tls::{self, Protocol};
+use super::cluster::{Cluster, NodeRole};
+use super::engine::StorageEngine;
+use super::handler::{execute_command, Context};
+use super::limits::Limits;
+use super::wal::WriteAheadLog;
+
+// Most bytes accepted for a request line and its headers together
+static MAX_HEAD_SIZE: u64 = 64 * 1024;
+// Room for the rest of a PUT body, on top of its value. Values are allowed to be hex encoded, so
+// the value itself may take twice its size
+static MAX_BODY_OVERHEAD: usize = 64 * 1024;
+static KV_PATH: &str = "/kv/";
+
+/// An HTTP/1.1 request with its body read in full
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+    // Whether the client wants the connection closed after the response
+    close: bool,
+}
+
+/// An HTTP response with a JSON body
+struct HttpResponse {
+    code: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Value,
+}
+
+impl HttpResponse {
+    fn new(code: u16, body: Value) -> HttpResponse {
+        HttpResponse {
+            code,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// A response the request can't be served from, for reasons found before Blue is asked
+    fn rejected(code: u16, status: Status, message: &str) -> HttpResponse {
+        HttpResponse::new(
+            code,
+            json!({ "status": status_name(status), "error": message }),
+        )
+    }
+}
+
+/// The name `Status` values have in messages.proto
+fn status_name(status: Status) -> &'static str {
+    match status {
+        Status::Ok => "OK",
+        Status::NotFound => "NOT_FOUND",
+        Status::NotLeader => "NOT_LEADER",
+        Status::InvalidArgument => "INVALID_ARGUMENT",
+        Status::OutOfRange => "OUT_OF_RANGE",
+        Status::Timeout => "TIMEOUT",
+        Status::Unavailable => "UNAVAILABLE",
+        Status::Internal => "INTERNAL",
+        Status::PermissionDenied => "PERMISSION_DENIED",
+    }
+}
+
+fn status_code(status: Status) -> u16 {
+    match status {
+        Status::Ok => 200,
+        Status::NotFound => 404,
+        Status::InvalidArgument | Status::OutOfRange => 400,
+        Status::NotLeader => 307,
+        Status::Timeout => 504,
+        Status::Unavailable => 503,
+        Status::Internal => 500,
+        Status::PermissionDenied => 403,
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+fn role_name(role: &NodeRole) -> &'static str {
+    match role {
+        NodeRole::Leader => "leader",
+        NodeRole::Follower => "follower",
+    }
+}
+
+fn bad_request(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Decodes `%XX` escapes, which is how keys that aren't plain text are put in a path
+fn percent_decode(text: &str) -> io::Result<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| bad_request("Invalid percent encoding"))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+/// The value of a query parameter, if it was given
+fn query_param(query: &str, name: &str) -> io::Result<Option<String>> {
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            let value = percent_decode(parts.next().unwrap_or(""))?;
+            return Ok(Some(String::from_utf8_lossy(&value).to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a request line and headers, then the body they announce. None at the end of the stream.
+/// Bodies have to come with a `Content-Length`, chunked uploads aren't supported
+async fn read_request<R>(reader: &mut R, max_body: usize) -> Result<Option<HttpRequest>, u16>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut limited = (&mut *reader).take(MAX_HEAD_SIZE);
+    loop {
+        let start = head.len();
+        match limited.read_until(b'\n', &mut head).await {
+            Ok(0) if head.is_empty() => return Ok(None),
+            Ok(0) => return Err(431),
+            Ok(_) => (),
+            Err(_) => return Err(400),
+        }
+        if head.last() != Some(&b'\n') {
+            return Err(431);
+        }
+        // Blank lines before the request line are allowed
+        if matches!(&head[start..], b"\r\n" | b"\n") {
+            match start {
+                0 => head.clear(),
+                _ => break,
+            }
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| 400u16)?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, target, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(400),
+    };
+    let mut close = version == "HTTP/1.0";
+    let mut length = 0;
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (&line[..colon], line[colon + 1..].trim()),
+            None => return Err(400),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse::<usize>().map_err(|_| 400u16)?,
+            "transfer-encoding" => return Err(411),
+            "connection" => close = value.eq_ignore_ascii_case("close"),
+            _ => (),
+        }
+    }
+    if length > max_body {
+        return Err(413);
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await.map_err(|_| 400u16)?;
+    let (path, query) = match target.find('?') {
+        Some(mark) => (&target[..mark], &target[mark + 1..]),
+        None => (target, ""),
+    };
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        body,
+        close,
+    }))
+}
+
+async fn write_response<W>(stream: &mut W, response: &HttpResponse, close: bool) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let body = response.body.to_string();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        response.code,
+        reason(response.code),
+        body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    // One write, so that small responses aren't held back waiting for acknowledgements
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body.as_bytes());
+    stream.write_all(&bytes).await?;
+    // Over TLS, what was written may still be waiting to be sealed
+    stream.flush().await
+}
+
+/// Describes a failed Blue response. Writes sent to a follower are redirected to the same path on
+/// the leader's HTTP listener, if the leader has one
+async fn failure(
+    context: &Context,
+    request: &HttpRequest,
+    response: &message::Response,
+) -> HttpResponse {
+    let status = Status::from_i32(response.status).unwrap_or(Status::Internal);
+    let mut body = json!({ "status": status_name(status), "error": response.message });
+    let mut http = HttpResponse::new(status_code(status), Value::Null);
+    if status == Status::NotLeader {
+        body["leader_addr"] = json!(response.leader_addr);
+        let leader = context.cluster().lock().await.http_addr.clone();
+        match leader.as_str() {
+            "" => http.code = 421,
+            leader => {
+                let location = match request.query.as_str() {
+                    "" => format!("http://{}{}", leader, request.path),
+                    query => format!("http://{}{}?{}", leader, request.path, query),
+                };
+                body["location"] = json!(location);
+                http.headers.push(("Location", location));
+            }
+        }
+    }
+    if response.retry_after_ms > 0 {
+        // Retry-After is in whole seconds
+        let seconds = response.retry_after_ms.div_ceil(1000);
+        http.headers.push(("Retry-After", seconds.to_string()));
+    }
+    http.body = body;
+    http
+}
+
+/// The value of a PUT body, `{"value": "...", "ttl_ms": 1000}`. Values are text, or binary as
+/// `hex:<digits>` or `base64:<data>` like the client takes them
+fn parse_set(key: Vec<u8>, body: &[u8]) -> io::Result<message::Set> {
+    let body: Value = serde_json::from_slice(body)
+        .map_err(|e| bad_request(&format!("Body is not valid JSON: {}", e)))?;
+    let value = body
+        .get("value")
+        .and_then(Value::as_str)
+        .ok_or_else(|| bad_request("Body needs a \"value\" string"))?;
+    let ttl_ms = match body.get("ttl_ms") {
+        None | Some(Value::Null) => 0,
+        Some(ttl_ms) => ttl_ms
+            .as_u64()
+            .ok_or_else(|| bad_request("\"ttl_ms\" must be a whole number of milliseconds"))?,
+    };
+    Ok(message::Set {
+        key,
+        value: decode_bytes(value)?,
+        ttl_ms,
+        ..Default::default()
+    })
+}
+
+/// `GET`, `PUT` and `DELETE` of `/kv/{key}`. Keys that aren't plain text can be percent encoded
+async fn kv(context: &Context, request: &HttpRequest) -> io::Result<HttpResponse> {
+    let key = percent_decode(&request.path[KV_PATH.len()..])?;
+    if key.is_empty() {
+        return Err(bad_request("Key must not be empty"));
+    }
+    let format = match query_param(&request.query, "format")? {
+        Some(format) => OutputFormat::from_str(&format)
+            .map_err(|_| bad_request("format must be one of: text, hex, base64"))?,
+        None => OutputFormat::Text,
+    };
+    let command = match request.method.as_str() {
+        "GET" => Command::Get(message::Get {
+            key: key.clone(),
+            ..Default::default()
+        }),
+        "PUT" => Command::Set(parse_set(key.clone(), &request.body)?),
+        "DELETE" => Command::Delete(message::Delete { key: key.clone() }),
+        _ => {
+            let mut response = HttpResponse::rejected(
+                405,
+                Status::InvalidArgument,
+                "Keys can only be read with GET, written with PUT and removed with DELETE",
+            );
+            response
+                .headers
+                .push(("Allow", "GET, PUT, DELETE".to_string()));
+            return Ok(response);
+        }
+    };
+
+    let response = execute_command(context, command).await?;
+    if !response.success {
+        return Ok(failure(context, request, &response).await);
+    }
+    let mut body = json!({
+        "key": format.format(&key),
+        "sequence": response.sequence,
+    });
+    if request.method == "GET" {
+        body["value"] = json!(format.format(&response.value));
+        body["expires_at"] = json!(response.expires_at);
+    }
+    Ok(HttpResponse::new(200, body))
+}
+
+async fn route(context: &Context, request: &HttpRequest) -> io::Result<HttpResponse> {
+    match (request.method.as_str(), request.path.as_str()) {
+        (_, path) if path.starts_with(KV_PATH) => kv(context, request).await,
+        ("GET", "/health") => Ok(HttpResponse::new(
+            200,
+            json!({ "status": "ok", "role": role_name(context.role()) }),
+        )),
+        ("GET", "/cluster") => {
+            let mut cluster = context.cluster().lock().await.to_json();
+            cluster["role"] = json!(role_name(context.role()));
+            Ok(HttpResponse::new(200, cluster))
+        }
+        (_, "/health") | (_, "/cluster") => {
+            let mut response =
+                HttpResponse::rejected(405, Status::InvalidArgument, "Only GET is supported");
+            response.headers.push(("Allow", "GET".to_string()));
+            Ok(response)
+        }
+        (_, path) => Ok(HttpResponse::rejected(
+            404,
+            Status::NotFound,
+            &format!("No such endpoint {}", path),
+        )),
+    }
+}
+
+/// Serves one HTTP connection until the client closes it or asks for it to be closed. Each
+/// request becomes a Blue request run through `execute_command`, so it takes the same path through
+/// the store, WAL and replication as a Blue client's
+pub async fn handle_http_stream(
+    stream: asyncTcpStream,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let local = stream.local_addr()?;
+    let (stream, _) = tls::accept(stream, Protocol::Http).await?;
+    let max_body = limits.max_value_size.saturating_mul(2) + MAX_BODY_OVERHEAD;
+    let context = Context::new(store, wal, cluster, role, limits, peer, local);
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request = match read_request(&mut stream, max_body).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // The rest of the stream can't be trusted to start with a request, so it is dropped
+            Err(code) => {
+                let response = HttpResponse::rejected(code, Status::InvalidArgument, reason(code));
+                return write_response(&mut stream, &response, true).await;
+            }
+        };
+        info!("{} {}", request.method, request.path);
+        let response = match route(&context, &request).await {
+            Ok(response) => response,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                HttpResponse::rejected(400, Status::InvalidArgument, &e.to_string())
+            }
+            // Blue closes the connection when a request fails, so the same is done here
+            Err(e) => {
+                error!("{} {} failed: {}", request.method, request.path, e);
+                let status = status_of(&e);
+                let response = HttpResponse::rejected(status_code(status), status, &e.to_string());
+                return write_response(&mut stream, &response, true).await;
+            }
+        };
+        write_response(&mut stream, &response, request.close).await?;
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+/// Accepts HTTP connections, serving each alongside Blue's own listener
+pub async fn run_http_listener(
+    listener: TcpListener,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Incoming HTTP connection from {}", addr);
+        let store = Arc::clone(&store);
+        let wal = Arc::clone(&wal);
+        let cluster = Arc::clone(&cluster);
+        let role = Arc::clone(&role);
+        let limits = Arc::clone(&limits);
+        tokio::spawn(async move {
+            if let Err(e) = handle_http_stream(stream, store, wal, cluster, role, limits).await {
+                error!("HTTP connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::{self, ErrorKind};
+
+use super::super::ipc::message;
+
+/// Largest keys and values the leader accepts. Checked before a write reaches the WAL so that
+/// followers never see a record the leader would have refused.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Limits {
+    pub fn check_key(&self, key: &[u8]) -> io::Result<()> {
+        if key.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Key must not be empty",
+            ));
+        }
+        if key.len() > self.max_key_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Key is {} bytes, larger than the {} byte limit",
+                    key.len(),
+                    self.max_key_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_set(&self, set: &message::Set) -> io::Result<()> {
+        self.check_key(&set.key)?;
+        if set.value.len() > self.max_value_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Value is {} bytes, larger than the {} byte limit",
+                    set.value.len(),
+                    self.max_value_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
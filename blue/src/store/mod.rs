@@ -1,6 +1,20 @@
 pub mod args;
+pub mod backup;
 pub mod cluster;
+pub mod compression;
+pub mod data_dir;
 pub mod deserialize;
+pub mod encryption;
+pub mod engine;
+pub mod expire;
+pub mod grpc;
 pub mod handler;
+pub mod http;
+pub mod limits;
+pub mod multi;
+pub mod resp;
+pub mod restore;
+pub mod scan;
 pub mod serialize;
 pub mod wal;
+pub mod watch;
@@ -0,0 +1,210 @@
+use std::io;
+
+use log::info;
+use tokio::io::AsyncWrite;
+
+use super::super::ipc::chunk::{async_send_chunks, split_results};
+use super::super::ipc::message;
+use super::super::ipc::message::request::Command;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::Status;
+use super::super::ipc::sender::async_send_message;
+use super::super::ipc::status;
+use super::super::ipc::tls::async_connect;
+use super::cluster::Cluster;
+use super::engine::{Entry, StorageEngine};
+use super::expire::{now_millis, stamp_expiration};
+use super::handler::keep_value;
+use super::limits::Limits;
+use super::wal::WriteAheadLog;
+
+/// Sends a response carrying per-key results, followed by the values that didn't fit in it
+async fn send_results<W>(
+    stream: &mut W,
+    mut response: message::Response,
+    mut results: Vec<message::KeyResult>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let values = split_results(&mut results);
+    response.results = results;
+    async_send_message(response, stream).await?;
+    for value in values {
+        async_send_chunks(&value, stream).await?;
+    }
+    Ok(())
+}
+
+fn unknown_key(key: Vec<u8>) -> message::KeyResult {
+    message::KeyResult {
+        message: format!("Unknown key '{}'", String::from_utf8_lossy(&key)),
+        key,
+        status: Status::NotFound as i32,
+        ..Default::default()
+    }
+}
+
+/// Looks up every key under one lock. Keys that aren't found are reported in their results, the
+/// request as a whole still succeeds
+pub async fn multi_get_handler<W>(
+    stream: &mut W,
+    multi_get: message::MultiGet,
+    request_id: u64,
+    store: &dyn StorageEngine,
+    sequence: u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Getting {} keys", multi_get.keys.len());
+    let now = now_millis();
+    let mut results = Vec::with_capacity(multi_get.keys.len());
+    let mut found = 0;
+    for key in multi_get.keys {
+        match store.get(&key)?.filter(|entry| !entry.is_expired(now)) {
+            Some(entry) => {
+                found += 1;
+                results.push(message::KeyResult {
+                    key,
+                    status: Status::Ok as i32,
+                    value: entry.value,
+                    ..Default::default()
+                });
+            }
+            None => results.push(unknown_key(key)),
+        }
+    }
+    let response = message::Response {
+        request_id,
+        sequence,
+        ..status::ok(format!("Found {} of {} keys", found, results.len()))
+    };
+    send_results(stream, response, results).await
+}
+
+/// Fills in the value of a set that keeps its value, looking at sets earlier in the batch before
+/// the store as they are written first
+fn keep_batch_value(
+    set: &mut message::Set,
+    accepted: &[message::Set],
+    store: &dyn StorageEngine,
+) -> io::Result<()> {
+    if set.keep_value {
+        if let Some(earlier) = accepted.iter().rev().find(|s| s.key == set.key) {
+            set.value = earlier.value.clone();
+            set.keep_value = false;
+        }
+    }
+    keep_value(set, store)
+}
+
+/// Writes every set that passes the leader's limits with a single WAL append, then replicates
+/// them as a single batch. Sets that don't pass are reported in their results and left out
+pub async fn multi_set_handler<W>(
+    stream: &mut W,
+    multi_set: message::MultiSet,
+    request_id: u64,
+    store: &mut dyn StorageEngine,
+    wal: &mut WriteAheadLog,
+    cluster: &Cluster,
+    limits: &Limits,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Storing {} keys", multi_set.sets.len());
+    let mut results = Vec::with_capacity(multi_set.sets.len());
+    let mut accepted = Vec::new();
+    for mut set in multi_set.sets {
+        match keep_batch_value(&mut set, &accepted, store)
+            .and_then(|()| limits.check_set(&set))
+            .and_then(|()| stamp_expiration(&mut set))
+        {
+            Ok(()) => {
+                results.push(message::KeyResult {
+                    key: set.key.clone(),
+                    status: Status::Ok as i32,
+                    ..Default::default()
+                });
+                accepted.push(set);
+            }
+            Err(e) => results.push(message::KeyResult {
+                key: set.key,
+                status: status::status_of(&e) as i32,
+                message: e.to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+    let message = format!("Wrote {} of {} keys", accepted.len(), results.len());
+    if accepted.is_empty() {
+        let response = message::Response {
+            request_id,
+            ..status::ok(message)
+        };
+        return send_results(stream, response, results).await;
+    }
+
+    let records: Vec<message::WalRecord> = accepted
+        .iter()
+        .map(|set| message::WalRecord {
+            operation: Some(Operation::Set(set.clone())),
+            ..Default::default()
+        })
+        .collect();
+    let sequence = wal.append_messages(&records)?;
+    let last_sequence = sequence + accepted.len() as u64 - 1;
+    for set in &accepted {
+        store.put(
+            &set.key,
+            Entry {
+                value: set.value.clone(),
+                expires_at: set.expires_at,
+            },
+        )?;
+    }
+    store.commit(last_sequence)?;
+    let response = message::Response {
+        request_id,
+        sequence: last_sequence,
+        ..status::ok(message)
+    };
+    send_results(stream, response, results).await?;
+    cluster.replicate_batch(&accepted, sequence).await?;
+    info!(
+        "Replicated sets #{} to #{} as one batch",
+        sequence, last_sequence
+    );
+    Ok(())
+}
+
+/// Applies a batch of sets from the leader, which has already been appended to the WAL
+pub async fn replicate_batch_handler(
+    replicate_batch: &message::ReplicateBatch,
+    store: &mut dyn StorageEngine,
+) -> io::Result<()> {
+    for set in &replicate_batch.sets {
+        info!(
+            "Storing {} ({} bytes)",
+            String::from_utf8_lossy(&set.key),
+            set.value.len()
+        );
+        store.apply(&message::WalRecord {
+            operation: Some(Operation::Set(set.clone())),
+            ..Default::default()
+        })?;
+    }
+
+    let mut stream = async_connect(&replicate_batch.leader_addr).await?;
+    let msg = message::Request {
+        command: Some(Command::ReplicateResponse(message::ReplicateResponse {
+            success: true,
+            sequence: replicate_batch.sequence + replicate_batch.sets.len() as u64 - 1,
+        })),
+        ..Default::default()
+    };
+    async_send_message(msg, &mut stream).await?;
+
+    Ok(())
+}
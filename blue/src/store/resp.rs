@@ -0,0 +1,935 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+use log::{error, info};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream as asyncTcpStream};
+use tokio::sync::Mutex;
+
+use super::super::ipc::message;
+use super::super::ipc::message::request::Command;
+use super::super::ipc::message::Status;
+use super::super::ipc::receiver::async_read_message;
+use super::super::ipc::tls::{self, Protocol};
+use super::cluster::{Cluster, NodeRole};
+use super::engine::StorageEngine;
+use super::expire::now_millis;
+use super::handler::{execute, execute_command, Context};
+use super::limits::Limits;
+use super::wal::WriteAheadLog;
+
+// Longest line accepted outside of a bulk string, such as an inline command
+static MAX_LINE: u64 = 64 * 1024;
+// Most arguments accepted in one command
+static MAX_ARGUMENTS: usize = 1024 * 1024;
+// Number of hash slots Redis Cluster clients spread keys over
+static SLOTS: u16 = 16384;
+// Keys fetched per page by commands that walk the whole store, such as KEYS
+static PAGE_SIZE: u32 = 1000;
+static DEFAULT_SCAN_COUNT: u32 = 10;
+// Version reported to clients that check it before using newer commands
+static REDIS_VERSION: &str = "7.0.0";
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+/// Reads a line without its line ending. Commands end lines with CRLF, but people typing inline
+/// commands may only send LF. None at the end of the stream
+async fn read_line<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line too long or truncated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(bytes: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+/// Reads one command, either an array of bulk strings or an inline command of words separated by
+/// spaces. None at the end of the stream
+async fn read_command<R>(reader: &mut R, max_bulk: usize) -> io::Result<Option<Vec<Vec<u8>>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|c| c.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    }
+
+    let count = parse_length(&line[1..])?.max(0) as usize;
+    if count > MAX_ARGUMENTS {
+        return Err(protocol_error("too many arguments"));
+    }
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let header = read_line(reader)
+            .await?
+            .ok_or_else(|| protocol_error("command is truncated"))?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let length = parse_length(&header[1..])?;
+        if length < 0 || length as usize > max_bulk {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let mut arg = vec![0u8; length as usize + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not followed by CRLF"));
+        }
+        arg.truncate(length as usize);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn simple(out: &mut Vec<u8>, message: &str) {
+    out.extend_from_slice(format!("+{}\r\n", message).as_bytes());
+}
+
+/// Errors have to fit on one line, so line breaks in Blue's messages are replaced
+fn error(out: &mut Vec<u8>, message: &str) {
+    out.extend_from_slice(format!("-{}\r\n", message.replace(&['\r', '\n'][..], " ")).as_bytes());
+}
+
+fn integer(out: &mut Vec<u8>, n: i64) {
+    out.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+}
+
+fn bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn array(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(format!("*{}\r\n", len).as_bytes());
+}
+
+fn wrong_arity(out: &mut Vec<u8>, name: &str) {
+    error(
+        out,
+        &format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        ),
+    );
+}
+
+fn not_an_integer(out: &mut Vec<u8>) {
+    error(out, "ERR value is not an integer or out of range");
+}
+
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// CRC16/XMODEM, which Redis Cluster hashes keys with
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, &byte| {
+        let mut crc = crc ^ ((byte as u16) << 8);
+        for _ in 0..8 {
+            crc = match crc & 0x8000 != 0 {
+                true => (crc << 1) ^ 0x1021,
+                false => crc << 1,
+            };
+        }
+        crc
+    })
+}
+
+/// The hash slot Redis Cluster clients expect a key to live in. A key with a non-empty hash tag,
+/// such as `{user1000}.following`, is hashed by the tag alone
+fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&c| c == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        rest.iter()
+            .position(|&c| c == b'}')
+            .filter(|&len| len > 0)
+            .map(|len| &rest[..len])
+    });
+    crc16(tag.unwrap_or(key)) % SLOTS
+}
+
+/// Matches a byte against the pattern element at the start of `pattern`, which is anything but a
+/// `*`. Returns whether it matched and how long the element is
+fn match_element(pattern: &[u8], c: u8) -> (bool, usize) {
+    match pattern[0] {
+        b'?' => (true, 1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c, 2),
+        b'[' => {
+            let negate = pattern.get(1) == Some(&b'^');
+            let mut i = match negate {
+                true => 2,
+                false => 1,
+            };
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (low, high) = match pattern[i] <= pattern[i + 2] {
+                        true => (pattern[i], pattern[i + 2]),
+                        false => (pattern[i + 2], pattern[i]),
+                    };
+                    matched |= low <= c && c <= high;
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // A class that is never closed runs to the end of the pattern
+            ((matched != negate), (i + 1).min(pattern.len()))
+        }
+        literal => (literal == c, 1),
+    }
+}
+
+/// Whether a key matches a Redis glob pattern of `*`, `?`, `[a-z]`, `[^a-z]` and `\` escapes.
+/// Only the most recent `*` is ever backtracked to, so matching takes at most
+/// `pattern.len() * key.len()` steps however many stars there are
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Positions in the pattern and key just after the last `*`
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if p < pattern.len() {
+            let (matched, len) = match_element(&pattern[p..], key[k]);
+            if matched {
+                p += len;
+                k += 1;
+                continue;
+            }
+        }
+        match star {
+            // Let the star swallow one more byte and try again
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// The part of a pattern every matching key starts with, which bounds the Blue scan behind it
+fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    pattern
+        .iter()
+        .take_while(|c| !b"*?[\\".contains(c))
+        .copied()
+        .collect()
+}
+
+/// One RESP connection. Each command becomes one or more Blue requests run through `execute`, so
+/// they take the same paths through the store, WAL and replication as Blue's own clients
+struct RespSession {
+    context: Context,
+    // 2 or 3, switched with HELLO
+    protocol: i64,
+    // SCAN cursors are numbers, Blue's are keys. Each page hands out a new number for the key it
+    // stopped at
+    cursors: HashMap<u64, Vec<u8>>,
+    next_cursor: u64,
+}
+
+impl RespSession {
+    fn null(&self, out: &mut Vec<u8>) {
+        match self.protocol {
+            3 => out.extend_from_slice(b"_\r\n"),
+            _ => out.extend_from_slice(b"$-1\r\n"),
+        }
+    }
+
+    /// Starts a map, which RESP2 only has as a flat array of keys and values
+    fn map(&self, out: &mut Vec<u8>, len: usize) {
+        match self.protocol {
+            3 => out.extend_from_slice(format!("%{}\r\n", len).as_bytes()),
+            _ => array(out, len * 2),
+        }
+    }
+
+    async fn request(&self, command: Command) -> io::Result<message::Response> {
+        execute_command(&self.context, command).await
+    }
+
+    async fn scan_page(&self, scan: message::Scan) -> io::Result<message::ScanResponse> {
+        let bytes = execute(
+            &self.context,
+            message::Request {
+                command: Some(Command::Scan(scan)),
+                ..Default::default()
+            },
+        )
+        .await?;
+        async_read_message::<message::ScanResponse, _>(&mut bytes.as_slice()).await
+    }
+
+    /// Every key matching a pattern, fetched a page at a time
+    async fn matching_keys(&self, pattern: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let prefix = literal_prefix(pattern);
+        let mut keys = Vec::new();
+        let mut cursor = Vec::new();
+        loop {
+            let page = self
+                .scan_page(message::Scan {
+                    prefix: prefix.clone(),
+                    limit: PAGE_SIZE,
+                    cursor,
+                    ..Default::default()
+                })
+                .await?;
+            keys.extend(
+                page.records
+                    .into_iter()
+                    .map(|record| record.key)
+                    .filter(|key| glob_match(pattern, key)),
+            );
+            if page.next_cursor.is_empty() {
+                return Ok(keys);
+            }
+            cursor = page.next_cursor;
+        }
+    }
+
+    /// Describes a failed Blue response. Writes sent to a follower are redirected to the leader
+    /// the way Redis Cluster does, if the leader has a RESP listener
+    async fn failure(&self, out: &mut Vec<u8>, response: &message::Response, key: &[u8]) {
+        if response.status != Status::NotLeader as i32 {
+            return error(out, &format!("ERR {}", response.message));
+        }
+        match self.context.leader_resp_addr().await.as_str() {
+            "" => error(out, "READONLY You can't write against a read only replica."),
+            leader => error(out, &format!("MOVED {} {}", key_slot(key), leader)),
+        }
+    }
+
+    /// Runs one command. False once the client has asked to close the connection
+    async fn dispatch(&mut self, args: Vec<Vec<u8>>, out: &mut Vec<u8>) -> io::Result<bool> {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let args = &args[1..];
+        match name.as_str() {
+            "PING" => match args {
+                [] => simple(out, "PONG"),
+                [message] => bulk(out, message),
+                _ => wrong_arity(out, &name),
+            },
+            "ECHO" => match args {
+                [message] => bulk(out, message),
+                _ => wrong_arity(out, &name),
+            },
+            "QUIT" => {
+                simple(out, "OK");
+                return Ok(false);
+            }
+            "HELLO" => self.hello(args, out),
+            "SELECT" => match args {
+                [db] if db.as_slice() == b"0" => simple(out, "OK"),
+                [_] => error(out, "ERR DB index is out of range"),
+                _ => wrong_arity(out, &name),
+            },
+            // Connection names and the like aren't kept, but clients set them when they connect
+            "CLIENT" => simple(out, "OK"),
+            "COMMAND" => array(out, 0),
+            "INFO" => {
+                let role = match self.context.role() {
+                    NodeRole::Leader => "master",
+                    NodeRole::Follower => "slave",
+                };
+                let info = format!(
+                    "# Server\r\nredis_version:{}\r\nblue_version:{}\r\n\r\n# Replication\r\nrole:{}\r\n",
+                    REDIS_VERSION,
+                    env!("CARGO_PKG_VERSION"),
+                    role
+                );
+                match self.protocol {
+                    3 => out.extend_from_slice(
+                        format!("={}\r\ntxt:{}\r\n", info.len() + 4, info).as_bytes(),
+                    ),
+                    _ => bulk(out, info.as_bytes()),
+                }
+            }
+            "GET" => match args {
+                [key] => self.get(key, out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "SET" => match args {
+                [key, value, options @ ..] => self.set(key, value, options, out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "SETEX" | "PSETEX" => match args {
+                [key, ttl, value] => match parse_integer(ttl) {
+                    Some(ttl) if ttl > 0 => {
+                        let ttl_ms = match name.as_str() {
+                            "SETEX" => ttl.saturating_mul(1000),
+                            _ => ttl,
+                        };
+                        self.set_with_ttl(key, value, ttl_ms as u64, out).await?
+                    }
+                    Some(_) => error(
+                        out,
+                        &format!(
+                            "ERR invalid expire time in '{}' command",
+                            name.to_lowercase()
+                        ),
+                    ),
+                    None => not_an_integer(out),
+                },
+                _ => wrong_arity(out, &name),
+            },
+            "MSET" => match args.len() {
+                n if n > 0 && n % 2 == 0 => self.mset(args, out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "MGET" => match args {
+                [] => wrong_arity(out, &name),
+                keys => self.mget(keys, out).await?,
+            },
+            "EXISTS" => match args {
+                [] => wrong_arity(out, &name),
+                keys => self.exists(keys, out).await?,
+            },
+            "DEL" | "UNLINK" => match args {
+                [] => wrong_arity(out, &name),
+                keys => self.del(keys, out).await?,
+            },
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => match args {
+                [key, time] => match parse_integer(time) {
+                    Some(time) => {
+                        let ttl_ms = match name.as_str() {
+                            "EXPIRE" => time.saturating_mul(1000),
+                            "PEXPIRE" => time,
+                            "EXPIREAT" => time
+                                .saturating_mul(1000)
+                                .saturating_sub(now_millis() as i64),
+                            _ => time.saturating_sub(now_millis() as i64),
+                        };
+                        self.expire(key, ttl_ms, out).await?
+                    }
+                    None => not_an_integer(out),
+                },
+                _ => wrong_arity(out, &name),
+            },
+            "PERSIST" => match args {
+                [key] => self.persist(key, out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "TTL" | "PTTL" => match args {
+                [key] => self.ttl(key, name == "PTTL", out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "TYPE" => match args {
+                [key] => {
+                    let response = self.request(get(key)).await?;
+                    match Status::from_i32(response.status) {
+                        Some(Status::Ok) => simple(out, "string"),
+                        Some(Status::NotFound) => simple(out, "none"),
+                        _ => self.failure(out, &response, key).await,
+                    }
+                }
+                _ => wrong_arity(out, &name),
+            },
+            "SCAN" => match args {
+                [cursor, options @ ..] => self.scan(cursor, options, out).await?,
+                _ => wrong_arity(out, &name),
+            },
+            "KEYS" => match args {
+                [pattern] => {
+                    let keys = self.matching_keys(pattern).await?;
+                    array(out, keys.len());
+                    for key in keys {
+                        bulk(out, &key);
+                    }
+                }
+                _ => wrong_arity(out, &name),
+            },
+            "DBSIZE" => match args {
+                [] => integer(out, self.matching_keys(b"*").await?.len() as i64),
+                _ => wrong_arity(out, &name),
+            },
+            _ => error(
+                out,
+                &format!("ERR unknown command '{}'", name.to_lowercase()),
+            ),
+        }
+        Ok(true)
+    }
+
+    /// Switches protocol version and describes the server. Authentication isn't supported, so
+    /// the AUTH and SETNAME options are ignored
+    fn hello(&mut self, args: &[Vec<u8>], out: &mut Vec<u8>) {
+        if let Some(version) = args.first() {
+            match parse_integer(version) {
+                Some(version) if version == 2 || version == 3 => self.protocol = version,
+                Some(_) => return error(out, "NOPROTO unsupported protocol version"),
+                None => {
+                    return error(
+                        out,
+                        "ERR Protocol version is not an integer or out of range",
+                    )
+                }
+            }
+        }
+        let role = match self.context.role() {
+            NodeRole::Leader => b"master".as_ref(),
+            NodeRole::Follower => b"replica".as_ref(),
+        };
+        self.map(out, 7);
+        bulk(out, b"server");
+        bulk(out, b"blue");
+        bulk(out, b"version");
+        bulk(out, REDIS_VERSION.as_bytes());
+        bulk(out, b"proto");
+        integer(out, self.protocol);
+        bulk(out, b"id");
+        integer(out, 0);
+        bulk(out, b"mode");
+        bulk(out, b"standalone");
+        bulk(out, b"role");
+        bulk(out, role);
+        bulk(out, b"modules");
+        array(out, 0);
+    }
+
+    async fn get(&self, key: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let response = self.request(get(key)).await?;
+        match Status::from_i32(response.status) {
+            Some(Status::Ok) => bulk(out, &response.value),
+            Some(Status::NotFound) => self.null(out),
+            _ => self.failure(out, &response, key).await,
+        }
+        Ok(())
+    }
+
+    /// SET with the EX and PX options. The options that depend on what is already stored, such
+    /// as NX and GET, aren't supported
+    async fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        options: &[Vec<u8>],
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let mut ttl_ms = 0;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = String::from_utf8_lossy(option).to_uppercase();
+            let ttl = match option.as_str() {
+                "EX" | "PX" => options.next().map(|ttl| parse_integer(ttl)),
+                _ => {
+                    error(out, &format!("ERR option '{}' is not supported", option));
+                    return Ok(());
+                }
+            };
+            ttl_ms = match ttl {
+                Some(Some(ttl)) if ttl > 0 && option == "EX" => ttl.saturating_mul(1000),
+                Some(Some(ttl)) if ttl > 0 => ttl,
+                Some(Some(_)) => {
+                    error(out, "ERR invalid expire time in 'set' command");
+                    return Ok(());
+                }
+                Some(None) => {
+                    not_an_integer(out);
+                    return Ok(());
+                }
+                None => {
+                    error(out, "ERR syntax error");
+                    return Ok(());
+                }
+            };
+        }
+        self.set_with_ttl(key, value, ttl_ms as u64, out).await
+    }
+
+    async fn set_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl_ms: u64,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let response = self
+            .request(Command::Set(message::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                ttl_ms,
+                ..Default::default()
+            }))
+            .await?;
+        match response.success {
+            true => simple(out, "OK"),
+            false => self.failure(out, &response, key).await,
+        }
+        Ok(())
+    }
+
+    /// Stores every pair as one batch. Redis sets all of them or none, so any key rejected by
+    /// the leader's limits fails the command, although the others are still written
+    async fn mset(&self, args: &[Vec<u8>], out: &mut Vec<u8>) -> io::Result<()> {
+        let sets = args
+            .chunks(2)
+            .map(|pair| message::Set {
+                key: pair[0].clone(),
+                value: pair[1].clone(),
+                ..Default::default()
+            })
+            .collect();
+        let response = self
+            .request(Command::MultiSet(message::MultiSet { sets }))
+            .await?;
+        if !response.success {
+            self.failure(out, &response, &args[0]).await;
+            return Ok(());
+        }
+        match response
+            .results
+            .iter()
+            .find(|result| result.status != Status::Ok as i32)
+        {
+            Some(rejected) => error(out, &format!("ERR {}", rejected.message)),
+            None => simple(out, "OK"),
+        }
+        Ok(())
+    }
+
+    async fn multi_get(&self, keys: &[Vec<u8>]) -> io::Result<message::Response> {
+        self.request(Command::MultiGet(message::MultiGet {
+            keys: keys.to_vec(),
+        }))
+        .await
+    }
+
+    async fn mget(&self, keys: &[Vec<u8>], out: &mut Vec<u8>) -> io::Result<()> {
+        let response = self.multi_get(keys).await?;
+        if !response.success {
+            self.failure(out, &response, &keys[0]).await;
+            return Ok(());
+        }
+        array(out, response.results.len());
+        for result in &response.results {
+            match result.status == Status::Ok as i32 {
+                true => bulk(out, &result.value),
+                false => self.null(out),
+            }
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, keys: &[Vec<u8>], out: &mut Vec<u8>) -> io::Result<()> {
+        let response = self.multi_get(keys).await?;
+        match response.success {
+            true => integer(
+                out,
+                response
+                    .results
+                    .iter()
+                    .filter(|result| result.status == Status::Ok as i32)
+                    .count() as i64,
+            ),
+            false => self.failure(out, &response, &keys[0]).await,
+        }
+        Ok(())
+    }
+
+    /// Deletes keys one at a time, replying with how many existed
+    async fn del(&self, keys: &[Vec<u8>], out: &mut Vec<u8>) -> io::Result<()> {
+        let mut deleted = 0;
+        for key in keys {
+            let response = self
+                .request(Command::Delete(message::Delete { key: key.clone() }))
+                .await?;
+            match Status::from_i32(response.status) {
+                Some(Status::Ok) => deleted += 1,
+                Some(Status::NotFound) => (),
+                _ => {
+                    self.failure(out, &response, key).await;
+                    return Ok(());
+                }
+            }
+        }
+        integer(out, deleted);
+        Ok(())
+    }
+
+    /// Gives a key a new time to live without touching its value. A time in the past deletes the
+    /// key, as it does in Redis
+    async fn expire(&self, key: &[u8], ttl_ms: i64, out: &mut Vec<u8>) -> io::Result<()> {
+        let command = match ttl_ms > 0 {
+            true => Command::Set(message::Set {
+                key: key.to_vec(),
+                ttl_ms: ttl_ms as u64,
+                keep_value: true,
+                ..Default::default()
+            }),
+            false => Command::Delete(message::Delete { key: key.to_vec() }),
+        };
+        let response = self.request(command).await?;
+        match Status::from_i32(response.status) {
+            Some(Status::Ok) => integer(out, 1),
+            Some(Status::NotFound) => integer(out, 0),
+            _ => self.failure(out, &response, key).await,
+        }
+        Ok(())
+    }
+
+    async fn persist(&self, key: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let response = self.request(get(key)).await?;
+        match Status::from_i32(response.status) {
+            Some(Status::Ok) if response.expires_at != 0 => (),
+            Some(Status::Ok) | Some(Status::NotFound) => {
+                integer(out, 0);
+                return Ok(());
+            }
+            _ => {
+                self.failure(out, &response, key).await;
+                return Ok(());
+            }
+        }
+        let response = self
+            .request(Command::Set(message::Set {
+                key: key.to_vec(),
+                keep_value: true,
+                ..Default::default()
+            }))
+            .await?;
+        match Status::from_i32(response.status) {
+            Some(Status::Ok) => integer(out, 1),
+            Some(Status::NotFound) => integer(out, 0),
+            _ => self.failure(out, &response, key).await,
+        }
+        Ok(())
+    }
+
+    /// Time left before a key expires. -2 if it doesn't exist, -1 if it never expires
+    async fn ttl(&self, key: &[u8], millis: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        let response = self.request(get(key)).await?;
+        match Status::from_i32(response.status) {
+            Some(Status::Ok) if response.expires_at == 0 => integer(out, -1),
+            Some(Status::Ok) => {
+                let left = response.expires_at.saturating_sub(now_millis());
+                match millis {
+                    true => integer(out, left as i64),
+                    false => integer(out, ((left + 500) / 1000) as i64),
+                }
+            }
+            Some(Status::NotFound) => integer(out, -2),
+            _ => self.failure(out, &response, key).await,
+        }
+        Ok(())
+    }
+
+    /// One page of keys. Like Redis, a page may come back empty, or with fewer keys than COUNT
+    /// once MATCH has filtered it, before the cursor reaches 0
+    async fn scan(
+        &mut self,
+        cursor: &[u8],
+        options: &[Vec<u8>],
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let after = match parse_integer(cursor) {
+            Some(0) => Vec::new(),
+            Some(cursor) => match self.cursors.remove(&(cursor as u64)) {
+                Some(after) => after,
+                None => {
+                    error(out, "ERR invalid cursor");
+                    return Ok(());
+                }
+            },
+            None => {
+                error(out, "ERR invalid cursor");
+                return Ok(());
+            }
+        };
+
+        let mut pattern = b"*".to_vec();
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = match options.next() {
+                Some(value) => value,
+                None => {
+                    error(out, "ERR syntax error");
+                    return Ok(());
+                }
+            };
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                "MATCH" => pattern = value.clone(),
+                "COUNT" => match parse_integer(value) {
+                    Some(n) if n > 0 => count = n.min(PAGE_SIZE as i64) as u32,
+                    Some(_) => {
+                        error(out, "ERR syntax error");
+                        return Ok(());
+                    }
+                    None => {
+                        not_an_integer(out);
+                        return Ok(());
+                    }
+                },
+                // Every key holds a string
+                "TYPE" if value.eq_ignore_ascii_case(b"string") => (),
+                "TYPE" => pattern.clear(),
+                _ => {
+                    error(out, "ERR syntax error");
+                    return Ok(());
+                }
+            }
+        }
+
+        let page = self
+            .scan_page(message::Scan {
+                prefix: literal_prefix(&pattern),
+                limit: count,
+                cursor: after,
+                ..Default::default()
+            })
+            .await?;
+        let keys: Vec<Vec<u8>> = page
+            .records
+            .into_iter()
+            .map(|record| record.key)
+            .filter(|key| glob_match(&pattern, key))
+            .collect();
+        let next = match page.next_cursor.is_empty() || pattern.is_empty() {
+            true => 0,
+            false => {
+                self.next_cursor += 1;
+                self.cursors.insert(self.next_cursor, page.next_cursor);
+                self.next_cursor
+            }
+        };
+        array(out, 2);
+        bulk(out, next.to_string().as_bytes());
+        array(out, keys.len());
+        for key in keys {
+            bulk(out, &key);
+        }
+        Ok(())
+    }
+}
+
+fn get(key: &[u8]) -> Command {
+    Command::Get(message::Get {
+        key: key.to_vec(),
+        ..Default::default()
+    })
+}
+
+/// Serves one RESP connection until the client leaves. A command that fails inside Blue is
+/// answered with an error and closes the connection, as it would for a Blue client
+pub async fn handle_resp_stream(
+    stream: asyncTcpStream,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let local = stream.local_addr()?;
+    let (stream, _) = tls::accept(stream, Protocol::Resp).await?;
+    // Anything larger would be rejected anyway, so it isn't worth reading
+    let max_bulk = limits.max_key_size.max(limits.max_value_size);
+    let mut session = RespSession {
+        context: Context::new(store, wal, cluster, role, limits, peer, local),
+        protocol: 2,
+        cursors: HashMap::new(),
+        next_cursor: 0,
+    };
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut out = Vec::new();
+        let args = match read_command(&mut stream, max_bulk).await {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                error(&mut out, &format!("ERR {}", e));
+                stream.write_all(&out).await?;
+                stream.flush().await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let open = match session.dispatch(args, &mut out).await {
+            Ok(open) => open,
+            Err(e) => {
+                out.clear();
+                error(&mut out, &format!("ERR {}", e));
+                stream.write_all(&out).await?;
+                stream.flush().await?;
+                return Err(e);
+            }
+        };
+        stream.write_all(&out).await?;
+        stream.flush().await?;
+        if !open {
+            return Ok(());
+        }
+    }
+}
+
+/// Accepts RESP connections, serving each alongside Blue's own listener
+pub async fn run_resp_listener(
+    listener: TcpListener,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    cluster: Arc<Mutex<Cluster>>,
+    role: Arc<NodeRole>,
+    limits: Arc<Limits>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Incoming RESP connection from {}", addr);
+        let store = Arc::clone(&store);
+        let wal = Arc::clone(&wal);
+        let cluster = Arc::clone(&cluster);
+        let role = Arc::clone(&role);
+        let limits = Arc::clone(&limits);
+        tokio::spawn(async move {
+            if let Err(e) = handle_resp_stream(stream, store, wal, cluster, role, limits).await {
+                error!("RESP connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use super::super::ipc::message;
+use super::super::ipc::message::Compression;
+use super::backup::read_backup;
+use super::data_dir::{store_path, wal_path};
+use super::deserialize::deserialize_store;
+use super::encryption::Keyring;
+use super::engine::map::MapEngine;
+use super::engine::{Entry, StorageEngine};
+use super::wal::{segment_path, segment_sequences, SegmentEntry, SegmentReader, WriteAheadLog};
+
+/// The point in a node's history that a restore rebuilds the store as of
+#[derive(Debug, Clone, Copy)]
+pub enum RestorePoint {
+    /// Everything up to and including this WAL sequence
+    Sequence(u64),
+    /// Everything appended at or before this many milliseconds since the Unix epoch
+    Time(u64),
+}
+
+#[derive(Debug)]
+pub struct Restored {
+    /// Last WAL sequence whose effect the restored store holds
+    pub sequence: u64,
+    /// Sequence of the snapshot the restore started from, if one was used
+    pub snapshot_sequence: Option<u64>,
+    /// Number of WAL records applied on top of the snapshot
+    pub replayed: usize,
+    pub records: usize,
+}
+
+fn wal_segments(wal_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let segments: Vec<PathBuf> = segment_sequences(wal_dir)?
+        .into_iter()
+        .map(|first_sequence| segment_path(wal_dir, first_sequence))
+        .collect();
+    match segments.is_empty() {
+        true => Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{:?} holds no WAL segments", wal_dir),
+        )),
+        false => Ok(segments),
+    }
+}
+
+/// Visits every entry of a WAL in order, without changing it, until `visit` returns false. A
+/// partial record at the very end, as left by a crash, ends the log like it would on startup.
+fn for_each_entry(
+    segments: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    visit: &mut dyn FnMut(SegmentEntry) -> io::Result<bool>,
+) -> io::Result<()> {
+    for (i, path) in segments.iter().enumerate() {
+        for item in SegmentReader::open(path, keyring.cloned())? {
+            let entry = match item {
+                Ok(entry) => entry,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && i == segments.len() - 1 => {
+                    warn!("Ignoring a partial record at the end of {:?}", path);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if !visit(entry)? {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The last sequence at or before the restore point. `first_sequence` is where the WAL starts
+fn resolve(
+    segments: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    first_sequence: u64,
+    point: RestorePoint,
+) -> io::Result<u64> {
+    let mut resolved = first_sequence - 1;
+    let mut last = first_sequence - 1;
+    for_each_entry(segments, keyring, &mut |entry| {
+        last = entry.sequence;
+        let before = match point {
+            RestorePoint::Sequence(sequence) => entry.sequence <= sequence,
+            // Records logged before timestamps were kept have none and can only be older
+            RestorePoint::Time(time) => entry.appended_at <= time,
+        };
+        if before {
+            resolved = entry.sequence;
+        }
+        Ok(before)
+    })?;
+    match point {
+        RestorePoint::Sequence(sequence) if sequence > last => Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("The WAL ends at #{}, before #{}", last, sequence),
+        )),
+        RestorePoint::Sequence(sequence) => Ok(sequence),
+        RestorePoint::Time(_) => Ok(resolved),
+    }
+}
+
+/// Reads a snapshot file, or the store a backup directory holds as of its manifest's sequence
+fn read_snapshot(path: &Path, keyring: Option<&Arc<Keyring>>) -> io::Result<message::Store> {
+    if path.is_dir() {
+        return Ok(read_backup(path, keyring)?.1);
+    }
+    deserialize_store(path, keyring.map(Arc::as_ref)).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Could not decode snapshot {:?}: {}", path, e),
+        )
+    })
+}
+
+/// The snapshot with the latest sequence at or before `sequence`
+fn choose_snapshot(
+    snapshots: &[PathBuf],
+    keyring: Option<&Arc<Keyring>>,
+    sequence: u64,
+) -> io::Result<Option<message::Store>> {
+    let mut chosen: Option<message::Store> = None;
+    for path in snapshots {
+        let store = read_snapshot(path, keyring)?;
+        if store.sequence > sequence {
+            info!(
+                "Skipping snapshot {:?}, it is at #{} which is after #{}",
+                path, store.sequence, sequence
+            );
+            continue;
+        }
+        if chosen.as_ref().is_none_or(|c| c.sequence < store.sequence) {
+            chosen = Some(store);
+        }
+    }
+    Ok(chosen)
+}
+
+/// Rebuilds the store of a node as it was at a point in its history and writes it, with the WAL
+/// that led up to that point, to `out_dir` laid out as a data directory. A node started on that
+/// data directory with the map engine carries on from there.
+///
+/// The restore starts from the latest of `snapshots` at or before the point and replays the WAL
+/// on top, so the WAL only has to reach back as far as that snapshot. A backup directory serves
+/// as a snapshot as of its manifest's sequence.
+///
+/// The keyring decrypts encrypted segments and snapshots. The restored data directory is
+/// encrypted with it too, so a node started on it needs the same key file.
+pub fn restore(
+    wal_dir: &Path,
+    snapshots: &[PathBuf],
+    point: RestorePoint,
+    out_dir: &Path,
+    keyring: Option<Arc<Keyring>>,
+) -> io::Result<Restored> {
+    let segments = wal_segments(wal_dir)?;
+    let first_sequence = segment_sequences(wal_dir)?[0];
+    let sequence = resolve(&segments, keyring.as_ref(), first_sequence, point)?;
+    let snapshot = choose_snapshot(snapshots, keyring.as_ref(), sequence)?;
+    let start = snapshot.as_ref().map(|s| s.sequence + 1).unwrap_or(1);
+    if first_sequence > start {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "The WAL starts at #{} so restoring needs a snapshot at or after #{}",
+                first_sequence,
+                first_sequence - 1
+            ),
+        ));
+    }
+
+    if out_dir.exists() && fs::read_dir(out_dir)?.next().is_some() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} is not empty", out_dir),
+        ));
+    }
+    fs::create_dir_all(out_dir)?;
+    let mut store = MapEngine::open(&store_path(out_dir), keyring.clone(), Compression::None)?;
+    // Restoring to before the WAL starts leaves nothing of it to copy, so the new WAL picks up
+    // straight after the snapshot
+    let mut wal = WriteAheadLog::new_at(
+        &wal_path(out_dir),
+        std::cmp::min(first_sequence, sequence + 1),
+        keyring.clone(),
+    )?;
+
+    if let Some(snapshot) = &snapshot {
+        info!("Starting from the snapshot at #{}", snapshot.sequence);
+        for record in &snapshot.records {
+            store.put(
+                &record.key,
+                Entry {
+                    value: record.value.clone(),
+                    expires_at: record.expires_at,
+                },
+            )?;
+        }
+    }
+    let mut replayed = 0;
+    for_each_entry(&segments, keyring.as_ref(), &mut |entry| {
+        if entry.sequence > sequence {
+            return Ok(false);
+        }
+        // The restored node keeps the history the original had, including what the snapshot
+        // already covers
+        wal.append_message_at(&entry.record, entry.appended_at)?;
+        if entry.sequence >= start {
+            store.apply(&entry.record)?;
+            replayed += 1;
+        }
+        Ok(true)
+    })?;
+    store.commit(sequence)?;
+    let records = store.snapshot()?.records.len();
+    Ok(Restored {
+        sequence,
+        snapshot_sequence: snapshot.map(|s| s.sequence),
+        replayed,
+        records,
+    })
+}
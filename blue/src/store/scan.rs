@@ -0,0 +1,108 @@
+use std::io;
+use std::ops::Bound;
+
+use log::info;
+use tokio::io::AsyncWrite;
+
+use super::super::ipc::chunk::CHUNK_SIZE;
+use super::super::ipc::message;
+use super::super::ipc::sender::async_send_message;
+use super::engine::StorageEngine;
+use super::expire::now_millis;
+
+static DEFAULT_SCAN_LIMIT: usize = 100;
+static MAX_SCAN_LIMIT: usize = 1000;
+// A page ends early once its keys and values add up to this many bytes, so that it always fits in
+// one message
+static MAX_SCAN_BYTES: usize = 1024 * 1024;
+
+/// Values too large to send in one message are left out of a page, only their size is returned
+fn to_key_value(key: &[u8], value: &[u8]) -> message::KeyValue {
+    match value.len() > CHUNK_SIZE {
+        true => message::KeyValue {
+            key: key.to_vec(),
+            value: Vec::new(),
+            value_size: value.len() as u64,
+        },
+        false => message::KeyValue {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            value_size: 0,
+        },
+    }
+}
+
+fn lower_bound(scan: &message::Scan) -> Bound<&[u8]> {
+    let start = std::cmp::max(&scan.start, &scan.prefix);
+    match scan.cursor.is_empty() || scan.cursor < *start {
+        true => Bound::Included(start),
+        false => Bound::Excluded(&scan.cursor),
+    }
+}
+
+fn upper_bound(scan: &message::Scan) -> Bound<&[u8]> {
+    match scan.end.is_empty() {
+        true => Bound::Unbounded,
+        false => Bound::Excluded(&scan.end),
+    }
+}
+
+/// Returns one page of live records in key order between the scan's bounds
+pub fn scan(
+    store: &dyn StorageEngine,
+    scan: &message::Scan,
+    now: u64,
+) -> io::Result<message::ScanResponse> {
+    let limit = match scan.limit as usize {
+        0 => DEFAULT_SCAN_LIMIT,
+        n => n.min(MAX_SCAN_LIMIT),
+    };
+
+    let mut records: Vec<message::KeyValue> = Vec::new();
+    let mut bytes = 0;
+    let mut more = false;
+    store.scan(lower_bound(scan), upper_bound(scan), &mut |key, entry| {
+        if !key.starts_with(&scan.prefix) {
+            return false;
+        }
+        if entry.is_expired(now) {
+            return true;
+        }
+        let record = to_key_value(key, &entry.value);
+        let size = record.key.len() + record.value.len();
+        // Stop at the first record that doesn't fit so it is known there is another page
+        if records.len() == limit || (!records.is_empty() && bytes + size > MAX_SCAN_BYTES) {
+            more = true;
+            return false;
+        }
+        bytes += size;
+        records.push(record);
+        true
+    })?;
+    let next_cursor = match more {
+        true => records.last().map(|r| r.key.clone()).unwrap_or_default(),
+        false => Vec::new(),
+    };
+    Ok(message::ScanResponse {
+        records,
+        next_cursor,
+        ..Default::default()
+    })
+}
+
+pub async fn scan_handler<W>(
+    stream: &mut W,
+    request: message::Scan,
+    request_id: u64,
+    store: &dyn StorageEngine,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!("Scanning {:?}", request);
+    let response = message::ScanResponse {
+        request_id,
+        ..scan(store, &request, now_millis())?
+    };
+    async_send_message(response, stream).await
+}
@@ -1,14 +1,16 @@
-use std::fs;
-use std::io;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 use prost::Message;
 
 use super::super::ipc::message;
+use super::super::ipc::message::Compression;
+use super::compression::{compress_file, STATS};
+use super::encryption::{seal_file, Keyring};
 
 pub fn serialize_store(store: &message::Store) -> Vec<u8> {
-    let mut buf = Vec::new();
-    buf.reserve(store.encoded_len());
+    let mut buf = Vec::with_capacity(store.encoded_len());
     store.encode(&mut buf).unwrap();
     buf
 }
@@ -26,8 +28,53 @@ pub fn serialize_message_with_len<M: Message>(message: M) -> io::Result<Vec<u8>>
     Ok(buf)
 }
 
-pub fn persist_store(store: &mut message::Store, path: &Path) -> io::Result<()> {
-    let bytes = serialize_store(store);
-    fs::write(&path, &bytes)?;
-    Ok(())
+/// Makes new files and renames in the directory holding `path` durable
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Replaces the file at `path` so that a crash at any point leaves either the old contents or the
+/// new ones in full: the bytes go to a temporary file which is synced and then renamed over it
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Where the snapshot replaced by the last `persist_store` is kept
+pub fn previous_store_path(path: &Path) -> PathBuf {
+    path.with_extension("prev")
+}
+
+/// Writes a store atomically, keeping the snapshot it replaces to fall back on should the new
+/// one ever turn out unreadable. Given a keyring the store is encrypted with its active key, so
+/// a commit after a key rotation re-encrypts the whole store with the new key. Compression, if
+/// on, comes before encryption as encrypted bytes don't compress
+pub fn persist_store(
+    store: &mut message::Store,
+    path: &Path,
+    keyring: Option<&Keyring>,
+    compression: Compression,
+) -> io::Result<()> {
+    let bytes = compress_file(compression, serialize_store(store), &STATS.snapshot)?;
+    let bytes = seal_file(keyring, bytes)?;
+    if path.exists() {
+        // Linked under a temporary name first so that the previous snapshot is replaced in one
+        // step too
+        let previous = previous_store_path(path);
+        let link = previous.with_extension("prev.tmp");
+        if link.exists() {
+            fs::remove_file(&link)?;
+        }
+        fs::hard_link(path, &link)?;
+        fs::rename(&link, &previous)?;
+    }
+    write_atomic(path, &bytes)
 }
@@ -1,96 +1,1323 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use log::debug;
-use prost::Message;
+use log::{debug, error, info, warn};
+use prost::{decode_length_delimiter, encode_length_delimiter, length_delimiter_len, Message};
+use tokio::sync::{broadcast, Mutex};
 
+use super::super::ipc::chunk::{chunks, ChunkCollector, CHUNK_SIZE};
 use super::super::ipc::message;
+use super::super::ipc::message::wal_entry::OperationType;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::Compression;
+use super::compression::{compress_set, decompress_set, STATS};
+use super::encryption::{
+    wal_frame_context, Keyring, CIPHER_NONE, CIPHER_XCHACHA20_POLY1305,
+    CIPHER_XCHACHA20_POLY1305_BOUND,
+};
+use super::engine::StorageEngine;
+use super::expire::now_millis;
 
-static WAL_VERSION: u8 = 1;
+static MAGIC: &[u8; 4] = b"BLUE";
+static WAL_VERSION: u8 = 3;
+// Segments from before the header recorded a cipher are never encrypted and can still be read
+static WAL_VERSION_WITHOUT_CIPHER: u8 = 2;
+// The first format, a single file rather than segments. Only read to convert it
+static LEGACY_WAL_VERSION: u8 = 1;
 static PROTO_BUF_VERSION: u8 = 3;
+// Magic, WAL version, Protocol Buffers version and cipher
+static HEADER_SIZE: u64 = 7;
+// Size at which the active segment is closed and a new one started
+static MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// Bytes of log between entries in a segment's sparse index
+static INDEX_INTERVAL: u64 = 64 * 1024;
+// Sequence and offset, both 8 little endian bytes
+static INDEX_ENTRY_SIZE: usize = 16;
+// Number of appended records buffered for watchers before slow ones start lagging
+static EVENT_CAPACITY: usize = 1024;
 
 type Sequence = u64;
-pub type WalItem = (Sequence, message::Set);
+pub type WalItem = (Sequence, message::WalRecord);
+// Sequence of an entry in a segment and its offset
+pub type IndexEntry = (Sequence, u64);
 
+/// Reads one record written with `encode_length_delimited_to_vec`: a varint length followed by the
+/// protobuf message. Returns `None` if the reader is already at the end.
+pub fn read_length_delimited<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = Vec::with_capacity(10);
+    loop {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte)? == 0 {
+            return match len_buf.is_empty() {
+                true => Ok(None),
+                false => Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Record ends inside its length",
+                )),
+            };
+        }
+        len_buf.push(byte[0]);
+        // The high bit is set on every byte of a varint but the last
+        if byte[0] & 0x80 == 0 || len_buf.len() == 10 {
+            break;
+        }
+    }
+    let len = decode_length_delimiter(len_buf.as_slice())?;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Seals and opens the frames of an encrypted segment, keeping track of the offset the next frame
+/// starts at. Each frame is bound to the segment and its offset, so that a frame moved within or
+/// between segments fails to open. Segments sealed before frames were bound are still read.
+struct FrameCipher {
+    keyring: Arc<Keyring>,
+    // First sequence of the segment, `None` if its frames aren't bound
+    segment: Option<Sequence>,
+    offset: u64,
+}
+
+impl FrameCipher {
+    /// The cipher for the frames from `offset` on of a segment with `cipher` in its header, or
+    /// `None` if the segment isn't encrypted
+    fn for_segment(
+        keyring: Option<&Arc<Keyring>>,
+        cipher: u8,
+        first_sequence: Sequence,
+        offset: u64,
+    ) -> Option<FrameCipher> {
+        match cipher == CIPHER_NONE {
+            true => None,
+            false => keyring.map(|keyring| FrameCipher {
+                keyring: keyring.clone(),
+                segment: Some(first_sequence).filter(|_| cipher == CIPHER_XCHACHA20_POLY1305_BOUND),
+                offset,
+            }),
+        }
+    }
+
+    fn seal(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let segment = self.segment.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot append to a segment sealed without associated data",
+            )
+        })?;
+        let sealed = self
+            .keyring
+            .seal(bytes, &wal_frame_context(segment, self.offset))?;
+        self.offset += (length_delimiter_len(sealed.len()) + sealed.len()) as u64;
+        Ok(sealed)
+    }
+
+    fn open(&mut self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let context = self
+            .segment
+            .map(|segment| wal_frame_context(segment, self.offset));
+        self.offset += (length_delimiter_len(sealed.len()) + sealed.len()) as u64;
+        self.keyring.open(sealed, context.as_deref())
+    }
+}
+
+/// Writes an encoded message preceded by its varint length, encrypting it first in an encrypted
+/// segment. Returns the number of bytes written.
+fn write_frame<W: Write>(
+    writer: &mut W,
+    bytes: Vec<u8>,
+    cipher: Option<&mut FrameCipher>,
+) -> io::Result<u64> {
+    let bytes = match cipher {
+        Some(cipher) => cipher.seal(&bytes)?,
+        None => bytes,
+    };
+    let mut framed = Vec::with_capacity(bytes.len() + 10);
+    encode_length_delimiter(bytes.len(), &mut framed)?;
+    framed.extend(bytes);
+    writer.write_all(&framed)?;
+    Ok(framed.len() as u64)
+}
+
+/// Reads what `write_frame` wrote. Returns `None` if the reader is already at the end.
+fn read_frame<R: Read>(
+    reader: &mut R,
+    cipher: Option<&mut FrameCipher>,
+) -> io::Result<Option<Vec<u8>>> {
+    match (read_length_delimited(reader)?, cipher) {
+        (Some(bytes), Some(cipher)) => Ok(Some(cipher.open(&bytes)?)),
+        (bytes, _) => Ok(bytes),
+    }
+}
+
+fn write_chunks<W: Write>(
+    writer: &mut W,
+    value: &[u8],
+    mut cipher: Option<&mut FrameCipher>,
+) -> io::Result<u64> {
+    let mut written = 0;
+    for chunk in chunks(value) {
+        written += write_frame(writer, chunk.encode_to_vec(), cipher.as_deref_mut())?;
+    }
+    Ok(written)
+}
+
+fn read_chunks<R: Read>(
+    reader: &mut R,
+    mut cipher: Option<&mut FrameCipher>,
+) -> io::Result<Vec<u8>> {
+    let mut collector = ChunkCollector::new(usize::MAX);
+    loop {
+        let buf = read_frame(reader, cipher.as_deref_mut())?.ok_or_else(|| {
+            io::Error::new(ErrorKind::UnexpectedEof, "Record ends inside its value")
+        })?;
+        if collector.push(message::Chunk::decode(buf.as_slice())?) {
+            return collector.finish();
+        }
+    }
+}
+
+/// A copy of a set holding everything but a value too large for one message
+fn chunked_header(set: &message::Set) -> message::Set {
+    message::Set {
+        key: set.key.clone(),
+        value: Vec::new(),
+        chunked: true,
+        ..*set
+    }
+}
+
+/// Writes a record in the length delimited format used by the bitcask data files. A set whose
+/// value is too large for one message is written with the value following in `Chunk`s. Returns
+/// the number of bytes written.
+pub fn write_record<W: Write>(writer: &mut W, record: &message::WalRecord) -> io::Result<u64> {
+    match &record.operation {
+        Some(Operation::Set(set)) if set.value.len() > CHUNK_SIZE => {
+            let bytes = message::WalRecord {
+                operation: Some(Operation::Set(chunked_header(set))),
+                ..Default::default()
+            }
+            .encode_length_delimited_to_vec();
+            writer.write_all(&bytes)?;
+            Ok(bytes.len() as u64 + write_chunks(writer, &set.value, None)?)
+        }
+        _ => {
+            let bytes = record.encode_length_delimited_to_vec();
+            writer.write_all(&bytes)?;
+            Ok(bytes.len() as u64)
+        }
+    }
+}
+
+/// Reads a record written by `write_record`, reassembling a chunked value. Returns `None` if the
+/// reader is already at the end.
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<message::WalRecord>> {
+    let buf = match read_length_delimited(reader)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let mut record = message::WalRecord::decode(buf.as_slice())?;
+    if let Some(Operation::Set(set)) = &mut record.operation {
+        if set.chunked {
+            set.value = read_chunks(reader, None)?;
+            set.chunked = false;
+        }
+    }
+    Ok(Some(record))
+}
+
+/// Writes a record wrapped in the WAL's `WalEntry` envelope, returning the number of bytes written.
+/// A set's value is compressed first if compression is on
+fn write_entry<W: Write>(
+    writer: &mut W,
+    sequence: Sequence,
+    appended_at: u64,
+    record: &message::WalRecord,
+    mut cipher: Option<&mut FrameCipher>,
+    compression: Compression,
+) -> io::Result<u64> {
+    let compressed;
+    let record = match &record.operation {
+        Some(Operation::Set(set)) if compression != Compression::None => {
+            let mut set = set.clone();
+            compress_set(&mut set, compression, &STATS.wal)?;
+            compressed = message::WalRecord {
+                operation: Some(Operation::Set(set)),
+                ..Default::default()
+            };
+            &compressed
+        }
+        _ => record,
+    };
+    let (operation_type, payload, value) = match &record.operation {
+        Some(Operation::Set(set)) if set.value.len() > CHUNK_SIZE => (
+            OperationType::Set,
+            chunked_header(set).encode_to_vec(),
+            Some(&set.value),
+        ),
+        Some(Operation::Set(set)) => (OperationType::Set, set.encode_to_vec(), None),
+        Some(Operation::Delete(delete)) => (OperationType::Delete, delete.encode_to_vec(), None),
+        Some(Operation::Expire(expire)) => (OperationType::Expire, expire.encode_to_vec(), None),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot log a record without an operation",
+            ))
+        }
+    };
+    let entry = message::WalEntry {
+        sequence,
+        operation_type: operation_type as i32,
+        payload,
+        chunked: value.is_some(),
+        appended_at,
+    };
+    let written = write_frame(writer, entry.encode_to_vec(), cipher.as_deref_mut())?;
+    match value {
+        Some(value) => Ok(written + write_chunks(writer, value, cipher)?),
+        None => Ok(written),
+    }
+}
+
+/// Reads the next entry's envelope, leaving any chunks of its value unread
+fn read_envelope<R: Read>(
+    reader: &mut R,
+    cipher: Option<&mut FrameCipher>,
+) -> io::Result<Option<message::WalEntry>> {
+    match read_frame(reader, cipher)? {
+        Some(buf) => Ok(Some(message::WalEntry::decode(buf.as_slice())?)),
+        None => Ok(None),
+    }
+}
+
+/// Decodes an entry's payload back into the record that was logged
+fn read_payload<R: Read>(
+    reader: &mut R,
+    entry: message::WalEntry,
+    cipher: Option<&mut FrameCipher>,
+) -> io::Result<WalItem> {
+    let payload = entry.payload.as_slice();
+    let operation = match OperationType::from_i32(entry.operation_type) {
+        Some(OperationType::Set) => {
+            let mut set = message::Set::decode(payload)?;
+            if entry.chunked {
+                set.value = read_chunks(reader, cipher)?;
+                set.chunked = false;
+            }
+            decompress_set(&mut set, usize::MAX)?;
+            Operation::Set(set)
+        }
+        Some(OperationType::Delete) => Operation::Delete(message::Delete::decode(payload)?),
+        Some(OperationType::Expire) => Operation::Expire(message::Expire::decode(payload)?),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unknown operation type {} at sequence #{}",
+                    entry.operation_type, entry.sequence
+                ),
+            ))
+        }
+    };
+    Ok((
+        entry.sequence,
+        message::WalRecord {
+            operation: Some(operation),
+            ..Default::default()
+        },
+    ))
+}
+
+/// Reads past an entry's chunks without keeping them
+fn skip_payload<R: Read>(
+    reader: &mut R,
+    entry: &message::WalEntry,
+    cipher: Option<&mut FrameCipher>,
+) -> io::Result<()> {
+    if entry.chunked {
+        read_chunks(reader, cipher)?;
+    }
+    Ok(())
+}
+
+/// Reads a segment's header and checks that it can be read with the keyring the WAL has
+fn read_header(
+    file: &mut File,
+    path: &Path,
+    keyring: Option<&Keyring>,
+) -> io::Result<SegmentHeader> {
+    let header = parse_header(file)?;
+    if &header.magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid magic number in WAL segment {:?}", path),
+        ));
+    }
+    if !header.is_supported() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "WAL segment {:?} is version {} but only versions {} and {} can be read",
+                path, header.wal_version, WAL_VERSION_WITHOUT_CIPHER, WAL_VERSION
+            ),
+        ));
+    }
+    if header.is_encrypted() && keyring.is_none() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "WAL segment {:?} is encrypted but no key file was given",
+                path
+            ),
+        ));
+    }
+    Ok(header)
+}
+
+fn parse_header<R: Read>(reader: &mut R) -> io::Result<SegmentHeader> {
+    let mut header = [0u8; HEADER_SIZE as usize - 1];
+    reader.read_exact(&mut header)?;
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(&header[..4]);
+    let cipher = match header[4] == WAL_VERSION_WITHOUT_CIPHER {
+        true => CIPHER_NONE,
+        false => {
+            let mut cipher = [0u8; 1];
+            reader.read_exact(&mut cipher)?;
+            cipher[0]
+        }
+    };
+    Ok(SegmentHeader {
+        magic,
+        wal_version: header[4],
+        proto_buf_version: header[5],
+        cipher,
+    })
+}
+
+/// The fields at the start of every WAL segment, as found on disk
 #[derive(Debug, Clone)]
-pub struct WriteAheadLog {
-    path: PathBuf,
-    pub next_sequence: u64, // Next sequence number to be appended
+pub struct SegmentHeader {
+    pub magic: [u8; 4],
+    pub wal_version: u8,
+    pub proto_buf_version: u8,
+    /// How records are encrypted, one of the `encryption::CIPHER_*` ids
+    pub cipher: u8,
 }
 
-impl<'a> WriteAheadLog {
-    pub fn new(path: &Path) -> io::Result<WriteAheadLog> {
-        let magic = b"BLUE";
-        let header = [WAL_VERSION, PROTO_BUF_VERSION];
-        let sequence = 1u64.to_le_bytes();
-        let mut file = File::create(path)?;
-        // Do the below in one write call to minimize sys calls
-        file.write_all(magic)?;
-        file.write_all(&header)?;
-        file.write_all(&sequence)?;
-        Ok(WriteAheadLog {
-            path: path.to_path_buf(),
-            next_sequence: 1u64,
+impl SegmentHeader {
+    /// Whether this build can read the segment, given the key if it is encrypted
+    pub fn is_supported(&self) -> bool {
+        &self.magic == MAGIC
+            && (self.wal_version == WAL_VERSION || self.wal_version == WAL_VERSION_WITHOUT_CIPHER)
+            && (self.cipher == CIPHER_NONE
+                || self.cipher == CIPHER_XCHACHA20_POLY1305
+                || self.cipher == CIPHER_XCHACHA20_POLY1305_BOUND)
+    }
+
+    /// Number of bytes the header takes up, where the first record starts
+    pub fn size(&self) -> u64 {
+        match self.wal_version == WAL_VERSION_WITHOUT_CIPHER {
+            true => HEADER_SIZE - 1,
+            false => HEADER_SIZE,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher != CIPHER_NONE
+    }
+}
+
+/// Reads a segment's header without checking it
+pub fn read_segment_header(path: &Path) -> io::Result<SegmentHeader> {
+    parse_header(&mut File::open(path)?)
+}
+
+/// First sequence of a segment, from its file name
+fn segment_first_sequence(path: &Path) -> Option<Sequence> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+pub fn segment_path(dir: &Path, first_sequence: Sequence) -> PathBuf {
+    dir.join(format!("{:020}.log", first_sequence))
+}
+
+pub fn index_path(dir: &Path, first_sequence: Sequence) -> PathBuf {
+    dir.join(format!("{:020}.index", first_sequence))
+}
+
+/// First sequences of the segments in a WAL directory, oldest first
+pub fn segment_sequences(dir: &Path) -> io::Result<Vec<Sequence>> {
+    let mut first_sequences = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("log") {
+            if let Some(first) = segment_first_sequence(&path) {
+                first_sequences.push(first);
+            }
+        }
+    }
+    first_sequences.sort_unstable();
+    Ok(first_sequences)
+}
+
+fn encode_index_entry(sequence: Sequence, offset: u64) -> Vec<u8> {
+    let mut bytes = sequence.to_le_bytes().to_vec();
+    bytes.extend(offset.to_le_bytes());
+    bytes
+}
+
+/// Reads a segment's index. A partly written last entry is ignored.
+pub fn read_index(path: &Path) -> io::Result<Vec<IndexEntry>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(bytes
+        .chunks_exact(INDEX_ENTRY_SIZE)
+        .map(|entry| {
+            let mut sequence = [0u8; 8];
+            let mut offset = [0u8; 8];
+            sequence.copy_from_slice(&entry[..8]);
+            offset.copy_from_slice(&entry[8..]);
+            (u64::from_le_bytes(sequence), u64::from_le_bytes(offset))
         })
+        .collect())
+}
+
+/// Cipher new segments are started with
+fn cipher_for(keyring: Option<&Keyring>) -> u8 {
+    match keyring {
+        Some(_) => CIPHER_XCHACHA20_POLY1305_BOUND,
+        None => CIPHER_NONE,
+    }
+}
+
+/// One file of the WAL, named by the first sequence it holds
+#[derive(Debug, Clone)]
+struct Segment {
+    first_sequence: Sequence,
+    // Where the first record starts
+    header_size: u64,
+    // How its records are encrypted, one of the `encryption::CIPHER_*` ids
+    cipher: u8,
+    // Sequence and offset of an entry roughly every `INDEX_INTERVAL` bytes, in order
+    index: Vec<IndexEntry>,
+}
+
+impl Segment {
+    /// Offset of the last indexed entry at or before `sequence`
+    fn offset_for(&self, sequence: Sequence) -> u64 {
+        match self.index.partition_point(|(s, _)| *s <= sequence) {
+            0 => self.header_size,
+            n => self.index[n - 1].1,
+        }
+    }
+}
+
+/// Streams the records of a WAL in sequence order without loading the whole log. Created by
+/// `WriteAheadLog::iter_from`, it stops at the last record appended before it was created.
+pub struct WalIter {
+    dir: PathBuf,
+    reader: BufReader<File>,
+    // Segments still to be read after the current one
+    segments: std::vec::IntoIter<Sequence>,
+    from: Sequence,
+    end: Sequence,
+    keyring: Option<Arc<Keyring>>,
+    // Only set if the current segment is encrypted
+    cipher: Option<FrameCipher>,
+}
+
+impl WalIter {
+    fn read_next(&mut self) -> io::Result<Option<WalItem>> {
+        loop {
+            let entry = match read_envelope(&mut self.reader, self.cipher.as_mut())? {
+                Some(entry) => entry,
+                None => match self.segments.next() {
+                    Some(first_sequence) => {
+                        let path = segment_path(&self.dir, first_sequence);
+                        let mut file = File::open(&path)?;
+                        let header = read_header(&mut file, &path, self.keyring.as_deref())?;
+                        self.cipher = FrameCipher::for_segment(
+                            self.keyring.as_ref(),
+                            header.cipher,
+                            first_sequence,
+                            header.size(),
+                        );
+                        self.reader = BufReader::new(file);
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            if entry.sequence >= self.end {
+                return Ok(None);
+            }
+            if entry.sequence >= self.from {
+                return read_payload(&mut self.reader, entry, self.cipher.as_mut()).map(Some);
+            }
+            skip_payload(&mut self.reader, &entry, self.cipher.as_mut())?;
+        }
     }
-    pub fn open(path: &Path) -> io::Result<WriteAheadLog> {
+}
+
+impl Iterator for WalIter {
+    type Item = io::Result<WalItem>;
+
+    fn next(&mut self) -> Option<io::Result<WalItem>> {
+        if self.from >= self.end {
+            return None;
+        }
+        match self.read_next() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.end = 0;
+                None
+            }
+            Err(e) => {
+                // Stop after reporting the error
+                self.end = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reads the records of a single segment file in order, along with the offset each starts at.
+/// Unlike `WriteAheadLog::open` this never changes the file, so it is safe to point at the WAL of
+/// a node that is running or at a copy taken for debugging. A partly written last record is
+/// returned as an `UnexpectedEof` error.
+pub struct SegmentReader {
+    reader: BufReader<File>,
+    // Where the last record read, or the one that failed to read, starts
+    offset: u64,
+    done: bool,
+    // Only set if the segment is encrypted
+    cipher: Option<FrameCipher>,
+}
+
+/// A record as it is kept in a segment
+#[derive(Debug, Clone)]
+pub struct SegmentEntry {
+    pub offset: u64,
+    pub appended_at: u64,
+    pub sequence: Sequence,
+    pub record: message::WalRecord,
+}
+
+impl SegmentReader {
+    /// Opens a segment, with the keyring to decrypt it should it be encrypted. An encrypted
+    /// segment must still have its name, as its records are bound to its first sequence
+    pub fn open(path: &Path, keyring: Option<Arc<Keyring>>) -> io::Result<SegmentReader> {
         let mut file = File::open(path)?;
-        let mut magic = [0u8; 4];
-        file.read_exact(&mut magic)?;
-        let metadata = file.metadata()?;
-        let size = metadata.len();
-        file.seek(SeekFrom::Start(size - 8))?;
-        let mut sequence_bytes = [0u8; 8];
-        file.read_exact(&mut sequence_bytes)?;
-        match &magic == b"BLUE" {
-            true => Ok(WriteAheadLog {
-                path: path.to_path_buf(),
-                next_sequence: u64::from_le_bytes(sequence_bytes),
-            }),
-            false => Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Invalid magic number in WAL",
-            )),
+        let header = read_header(&mut file, path, keyring.as_deref())?;
+        let first_sequence = match segment_first_sequence(path) {
+            Some(first_sequence) => first_sequence,
+            None if header.cipher == CIPHER_XCHACHA20_POLY1305_BOUND => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "WAL segment {:?} is encrypted but not named by its first sequence",
+                        path
+                    ),
+                ))
+            }
+            None => 0,
+        };
+        Ok(SegmentReader {
+            reader: BufReader::new(file),
+            offset: header.size(),
+            done: false,
+            cipher: FrameCipher::for_segment(
+                keyring.as_ref(),
+                header.cipher,
+                first_sequence,
+                header.size(),
+            ),
+        })
+    }
+
+    /// Offset of the last record returned, or of the record that failed to read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn read_next(&mut self) -> io::Result<Option<SegmentEntry>> {
+        self.offset = self.reader.stream_position()?;
+        let entry = match read_envelope(&mut self.reader, self.cipher.as_mut())? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let appended_at = entry.appended_at;
+        let (sequence, record) = read_payload(&mut self.reader, entry, self.cipher.as_mut())?;
+        Ok(Some(SegmentEntry {
+            offset: self.offset,
+            appended_at,
+            sequence,
+            record,
+        }))
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = io::Result<SegmentEntry>;
+
+    fn next(&mut self) -> Option<io::Result<SegmentEntry>> {
+        if self.done {
+            return None;
         }
+        let next = self.read_next().transpose();
+        // Stop after the last record or the first error
+        self.done = !matches!(next, Some(Ok(_)));
+        next
     }
-    pub fn append_message<M: Message>(&mut self, message: &M) -> io::Result<()> {
-        debug!("Appending msg to wal: {:?}", message);
-        let bytes = message.encode_length_delimited_to_vec();
-        let mut file = OpenOptions::new().append(true).open(&self.path)?;
-        file.write_all(&bytes)?;
-        self.next_sequence += 1;
-        file.write_all(&self.next_sequence.to_le_bytes())?;
-        Ok(())
+}
+
+/// Decodes a record of a version 1 WAL. The first leaders logged bare `Set`s and the first
+/// followers the `ReplicateSet`s they were sent, and later nodes `WalRecord`s, without changing
+/// the version. Bytes are only taken as a `WalRecord` or `ReplicateSet` if they encode back to
+/// the same bytes, which another message misread as one doesn't.
+fn decode_legacy_record(buf: &[u8]) -> io::Result<message::WalRecord> {
+    if let Ok(record) = message::WalRecord::decode(buf) {
+        if record.operation.is_some() && record.encode_to_vec() == buf {
+            return Ok(record);
+        }
+    }
+    if let Ok(replicate_set) = message::ReplicateSet::decode(buf) {
+        if replicate_set.set.is_some() && replicate_set.encode_to_vec() == buf {
+            return Ok(message::WalRecord {
+                operation: replicate_set.set.map(Operation::Set),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(message::WalRecord {
+        operation: Some(Operation::Set(message::Set::decode(buf)?)),
+        ..Default::default()
+    })
+}
+
+/// Reads every record of a version 1 WAL: a single file with a 6 byte header, then the first
+/// sequence as 8 little endian bytes, then each record followed by the sequence after it. Also
+/// returns the sequence after the last record. A record left partly written by a crash ends
+/// the log.
+pub fn read_legacy_log(path: &Path) -> io::Result<(Vec<WalItem>, Sequence)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; HEADER_SIZE as usize - 1];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC || header[4] != LEGACY_WAL_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} is not a version {} WAL", path, LEGACY_WAL_VERSION),
+        ));
+    }
+    let mut sequence_buf = [0u8; 8];
+    reader.read_exact(&mut sequence_buf)?;
+    let mut next_sequence = u64::from_le_bytes(sequence_buf);
+    let mut items = Vec::new();
+    loop {
+        let buf = match read_length_delimited(&mut reader) {
+            Ok(Some(buf)) => buf,
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                warn!("Ignoring a partial record at the end of WAL {:?}", path);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let mut record = decode_legacy_record(&buf)?;
+        if let Some(Operation::Set(set)) = &mut record.operation {
+            if set.chunked {
+                match read_chunks(&mut reader, None) {
+                    Ok(value) => set.value = value,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        warn!("Ignoring a partial record at the end of WAL {:?}", path);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+                set.chunked = false;
+            }
+        }
+        items.push((next_sequence, record));
+        next_sequence += 1;
+        // The record is complete even if a crash kept the sequence after it from being written
+        match reader.read_exact(&mut sequence_buf) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((items, next_sequence))
+}
+
+/// Converts a WAL kept in a single file, as nodes did before segments, into a segmented WAL at
+/// `dir`. A version 1 file is read record by record and logged anew, its records without the
+/// time they were appended. A version 2 file already holds what a segment does and becomes the
+/// first segment as it is. The WAL is built beside `dir` and only moved there once complete, so
+/// a conversion cut short by a crash is simply started over. Returns the next sequence.
+pub fn convert_legacy_log(
+    path: &Path,
+    dir: &Path,
+    keyring: Option<Arc<Keyring>>,
+) -> io::Result<Sequence> {
+    let tmp_dir = dir.with_extension("converting");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    let header = read_segment_header(path)?;
+    let wal = if header.wal_version == LEGACY_WAL_VERSION {
+        let (items, next_sequence) = read_legacy_log(path)?;
+        let first_sequence = next_sequence - items.len() as u64;
+        let mut wal = WriteAheadLog::new_at(&tmp_dir, first_sequence, keyring)?;
+        for (_, record) in items.iter() {
+            wal.append_message_at(record, 0)?;
+        }
+        wal
+    } else if header.wal_version == WAL_VERSION_WITHOUT_CIPHER {
+        // Single file WALs always started at the first sequence
+        fs::create_dir_all(&tmp_dir)?;
+        fs::copy(path, segment_path(&tmp_dir, 1))?;
+        WriteAheadLog::open(&tmp_dir, keyring)?
+    } else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "WAL {:?} is version {} but only versions {} and {} can be converted",
+                path, header.wal_version, LEGACY_WAL_VERSION, WAL_VERSION_WITHOUT_CIPHER
+            ),
+        ));
+    };
+    fs::rename(&tmp_dir, dir)?;
+    info!(
+        "Converted WAL {:?} to {:?}, next sequence {}",
+        path, dir, wal.next_sequence
+    );
+    Ok(wal.next_sequence)
+}
+
+/// The log of every write, kept as a directory of segments. Each segment is named by the first
+/// sequence it holds and has a sparse index from sequence to offset alongside it, so reading can
+/// start anywhere in the log without reading what comes before.
+///
+/// Given a keyring, new segments are encrypted: every frame of a record is sealed on its own so
+/// that the index can still point at any record. Segments written before a key rotation stay
+/// readable for as long as the key file keeps their key, until pruning removes them.
+#[derive(Debug, Clone)]
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    pub next_sequence: u64, // Next sequence number to be appended
+    // Oldest first. The last segment is the one being appended to
+    segments: Vec<Segment>,
+    active_size: u64,
+    // Offset of the active segment's last index entry
+    last_indexed: u64,
+    events: broadcast::Sender<WalItem>,
+    keyring: Option<Arc<Keyring>>,
+    // What set values are compressed with from now on. Records written with any other
+    // compression can still be read
+    compression: Compression,
+    // First sequence of each `WalPin` still held, with how many are held on it
+    pins: Arc<std::sync::Mutex<BTreeMap<Sequence, usize>>>,
+}
+
+/// Keeps the records from a sequence on from being pruned for as long as it is held, so that
+/// they can be read after the WAL's lock is released
+#[derive(Debug)]
+pub struct WalPin {
+    pins: Arc<std::sync::Mutex<BTreeMap<Sequence, usize>>>,
+    sequence: Sequence,
+}
+
+impl Drop for WalPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.sequence);
+            }
+        }
+    }
+}
+
+impl WriteAheadLog {
+    pub fn new(dir: &Path, keyring: Option<Arc<Keyring>>) -> io::Result<WriteAheadLog> {
+        WriteAheadLog::new_at(dir, 1, keyring)
+    }
+
+    /// Creates a WAL whose first record will have `next_sequence`, for a log that carries on
+    /// from where another left off
+    pub fn new_at(
+        dir: &Path,
+        next_sequence: Sequence,
+        keyring: Option<Arc<Keyring>>,
+    ) -> io::Result<WriteAheadLog> {
+        fs::create_dir_all(dir)?;
+        let mut wal = WriteAheadLog {
+            dir: dir.to_path_buf(),
+            next_sequence,
+            segments: Vec::new(),
+            active_size: 0,
+            last_indexed: 0,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            keyring,
+            compression: Compression::None,
+            pins: Arc::default(),
+        };
+        wal.start_segment()?;
+        Ok(wal)
     }
 
-    pub fn messages(self) -> io::Result<Vec<WalItem>> {
-        let mut file = File::open(&self.path)?;
-        let file_len = file.metadata().unwrap().len();
-        file.seek(SeekFrom::Start(6))?;
+    /// Opens an existing WAL. Sealed segments are known from their index alone, only the tail of
+    /// the active segment is read to find the next sequence. A record left partly written by a
+    /// crash is cut off so that appends carry on from the last complete record.
+    ///
+    /// If encryption has been turned on or off since the active segment was started, or the
+    /// segment was sealed without associated data, appends go to a new segment so that each
+    /// segment is sealed one way throughout.
+    pub fn open(dir: &Path, keyring: Option<Arc<Keyring>>) -> io::Result<WriteAheadLog> {
+        let first_sequences = segment_sequences(dir)?;
+        if first_sequences.is_empty() {
+            return WriteAheadLog::new(dir, keyring);
+        }
 
-        let mut msgs: Vec<WalItem> = Vec::new();
-        if self.next_sequence == 1 {
-            return Ok(msgs);
+        let mut segments = Vec::new();
+        let last = first_sequences.len() - 1;
+        for (i, first_sequence) in first_sequences.into_iter().enumerate() {
+            let path = segment_path(dir, first_sequence);
+            let header = read_header(&mut File::open(&path)?, &path, keyring.as_deref())?;
+            let mut segment = Segment {
+                first_sequence,
+                header_size: header.size(),
+                cipher: header.cipher,
+                index: read_index(&index_path(dir, first_sequence))?,
+            };
+            // Sealed segments with a lost index are read once to rebuild it
+            if segment.index.is_empty() && i != last {
+                warn!("Rebuilding index of WAL segment {}", first_sequence);
+                segment.index = WriteAheadLog::scan_segment(dir, &segment, keyring.as_ref())?.2;
+                WriteAheadLog::write_index(dir, first_sequence, &segment.index)?;
+            }
+            segments.push(segment);
+        }
+
+        let active = segments.last_mut().unwrap();
+        let (next_sequence, valid_len, tail_index) =
+            WriteAheadLog::scan_segment(dir, active, keyring.as_ref())?;
+        let path = segment_path(dir, active.first_sequence);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        if file.metadata()?.len() > valid_len {
+            warn!(
+                "Cutting off a partial record at the end of WAL segment {:?}",
+                path
+            );
+            file.set_len(valid_len)?;
+        }
+        // Drop index entries for anything that was cut off, and add any the crash kept from
+        // being written
+        let indexed = active.index.len();
+        active.index.retain(|(_, offset)| *offset < valid_len);
+        let mut changed = active.index.len() != indexed;
+        for (sequence, offset) in tail_index {
+            if active.index.last().is_none_or(|(s, _)| *s < sequence) {
+                active.index.push((sequence, offset));
+                changed = true;
+            }
+        }
+        if changed {
+            WriteAheadLog::write_index(dir, active.first_sequence, &active.index)?;
+        }
+        let last_indexed = active.index.last().map(|(_, o)| *o).unwrap_or(0);
+        let next_sequence = next_sequence.unwrap_or(active.first_sequence);
+        let was_encrypted = active.cipher != CIPHER_NONE;
+        let restart = active.cipher != cipher_for(keyring.as_deref());
+        if restart && next_sequence == active.first_sequence {
+            // Nothing was appended to it yet, so it is simply replaced
+            segments.pop();
         }
+        let mut wal = WriteAheadLog {
+            dir: dir.to_path_buf(),
+            next_sequence,
+            segments,
+            active_size: valid_len,
+            last_indexed,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            keyring,
+            compression: Compression::None,
+            pins: Arc::default(),
+        };
+        if restart {
+            match was_encrypted == wal.keyring.is_some() {
+                true => info!("The active WAL segment was sealed without associated data"),
+                false => info!(
+                    "Encryption was turned {} since the active WAL segment was started",
+                    match wal.keyring.is_some() {
+                        true => "on",
+                        false => "off",
+                    }
+                ),
+            }
+            wal.start_segment()?;
+        }
+        Ok(wal)
+    }
 
+    /// Reads a segment from its last index entry, returning the sequence after its last complete
+    /// record, the length of the segment up to that record and index entries for what was read
+    fn scan_segment(
+        dir: &Path,
+        segment: &Segment,
+        keyring: Option<&Arc<Keyring>>,
+    ) -> io::Result<(Option<Sequence>, u64, Vec<IndexEntry>)> {
+        let start = segment.offset_for(Sequence::MAX);
+        let mut cipher =
+            FrameCipher::for_segment(keyring, segment.cipher, segment.first_sequence, start);
+        let mut file = File::open(segment_path(dir, segment.first_sequence))?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(file);
+        let mut next_sequence = None;
+        let mut valid_len = start;
+        let mut index = Vec::new();
+        let mut last_indexed = 0;
         loop {
-            let mut sequence_buf = [0u8; 8];
-            file.read_exact(&mut sequence_buf)?;
-            let sequence = u64::from_le_bytes(sequence_buf);
-            let mut len_buf = [0u8; 1];
-            file.read_exact(&mut len_buf)?;
-            let len = u8::from_le_bytes(len_buf);
-            let mut msg_buf = vec![0u8; len as usize];
-            Read::by_ref(&mut file).read_exact(&mut msg_buf)?;
-            let msg = message::Set::decode(&mut msg_buf.as_slice())?;
-            msgs.push((sequence, msg));
-            let pos = file.stream_position()?;
-            if (file_len - pos) == 8 {
-                break;
+            let entry = match read_envelope(&mut reader, cipher.as_mut()) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            match skip_payload(&mut reader, &entry, cipher.as_mut()) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            if index.is_empty() || valid_len - last_indexed >= INDEX_INTERVAL {
+                index.push((entry.sequence, valid_len));
+                last_indexed = valid_len;
+            }
+            next_sequence = Some(entry.sequence + 1);
+            valid_len = reader.stream_position()?;
+        }
+        Ok((next_sequence, valid_len, index))
+    }
+
+    fn write_index(dir: &Path, first_sequence: Sequence, index: &[IndexEntry]) -> io::Result<()> {
+        let bytes: Vec<u8> = index
+            .iter()
+            .flat_map(|(sequence, offset)| encode_index_entry(*sequence, *offset))
+            .collect();
+        let path = index_path(dir, first_sequence);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Closes the active segment and starts a new one at the next sequence
+    fn start_segment(&mut self) -> io::Result<()> {
+        let first_sequence = self.next_sequence;
+        let mut file = File::create(segment_path(&self.dir, first_sequence))?;
+        let cipher = cipher_for(self.keyring.as_deref());
+        let mut header = MAGIC.to_vec();
+        header.extend([WAL_VERSION, PROTO_BUF_VERSION, cipher]);
+        file.write_all(&header)?;
+        File::create(index_path(&self.dir, first_sequence))?;
+        info!("Started WAL segment {}", first_sequence);
+        self.segments.push(Segment {
+            first_sequence,
+            header_size: HEADER_SIZE,
+            cipher,
+            index: Vec::new(),
+        });
+        self.active_size = HEADER_SIZE;
+        self.last_indexed = 0;
+        Ok(())
+    }
+
+    pub fn append_message(&mut self, message: &message::WalRecord) -> io::Result<()> {
+        self.append_message_at(message, now_millis())
+    }
+
+    /// Appends a record keeping the time it was first appended, for records copied from
+    /// another log
+    pub fn append_message_at(
+        &mut self,
+        message: &message::WalRecord,
+        appended_at: u64,
+    ) -> io::Result<()> {
+        self.append_messages_at(std::slice::from_ref(message), appended_at)
+            .map(|_| ())
+    }
+
+    /// Appends several records with a single write, giving them consecutive sequences. Returns the
+    /// sequence of the first. The batch is kept in one segment, even if that takes it past the
+    /// usual size
+    pub fn append_messages(&mut self, messages: &[message::WalRecord]) -> io::Result<Sequence> {
+        self.append_messages_at(messages, now_millis())
+    }
+
+    fn append_messages_at(
+        &mut self,
+        messages: &[message::WalRecord],
+        appended_at: u64,
+    ) -> io::Result<Sequence> {
+        debug!("Appending {} msgs to wal: {:?}", messages.len(), messages);
+        if self.active_size >= MAX_SEGMENT_SIZE {
+            self.start_segment()?;
+        }
+        let first_sequence = self.next_sequence;
+        let active = self.segments.last_mut().unwrap();
+        let mut cipher = FrameCipher::for_segment(
+            self.keyring.as_ref(),
+            active.cipher,
+            active.first_sequence,
+            self.active_size,
+        );
+        let mut batch = Vec::new();
+        let mut index = Vec::new();
+        let mut last_indexed = self.last_indexed;
+        for (message, sequence) in messages.iter().zip(first_sequence..) {
+            let offset = self.active_size + batch.len() as u64;
+            write_entry(
+                &mut batch,
+                sequence,
+                appended_at,
+                message,
+                cipher.as_mut(),
+                self.compression,
+            )?;
+            if active.index.is_empty() && index.is_empty()
+                || offset - last_indexed >= INDEX_INTERVAL
+            {
+                index.push((sequence, offset));
+                last_indexed = offset;
             }
         }
+        OpenOptions::new()
+            .append(true)
+            .open(segment_path(&self.dir, active.first_sequence))?
+            .write_all(&batch)?;
+        self.active_size += batch.len() as u64;
+        self.last_indexed = last_indexed;
+        // The index is written after the records so it never points past the end of the segment
+        if !index.is_empty() {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(index_path(&self.dir, active.first_sequence))?;
+            let entries: Vec<u8> = index
+                .iter()
+                .flat_map(|&(sequence, offset)| encode_index_entry(sequence, offset))
+                .collect();
+            file.write_all(&entries)?;
+            active.index.extend(index);
+        }
+        for message in messages {
+            // Sending only fails when nobody is watching
+            let _ = self.events.send((self.next_sequence, message.clone()));
+            self.next_sequence += 1;
+        }
+        Ok(first_sequence)
+    }
+
+    /// Subscribes to every record appended from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<WalItem> {
+        self.events.subscribe()
+    }
+
+    /// Keys the WAL is encrypted with, which the node's other files are encrypted with too
+    pub fn keyring(&self) -> Option<&Arc<Keyring>> {
+        self.keyring.as_ref()
+    }
+
+    /// Compresses the values of sets appended from now on. The node's snapshots and backups are
+    /// compressed the same way
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// First sequence still held by the WAL
+    pub fn first_sequence(&self) -> Sequence {
+        self.segments[0].first_sequence
+    }
+
+    /// Streams every record from `sequence` onwards that had been appended when this was called
+    pub fn iter_from(&self, sequence: Sequence) -> io::Result<WalIter> {
+        let sequence = std::cmp::max(sequence, 1);
+        if sequence < self.first_sequence() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Sequence #{} has been removed from the WAL, which now starts at #{}",
+                    sequence,
+                    self.first_sequence()
+                ),
+            ));
+        }
+        let i = self
+            .segments
+            .partition_point(|s| s.first_sequence <= sequence)
+            .saturating_sub(1);
+        let segment = &self.segments[i];
+        let path = segment_path(&self.dir, segment.first_sequence);
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(segment.offset_for(sequence)))?;
+        let remaining: Vec<Sequence> = self.segments[i + 1..]
+            .iter()
+            .map(|s| s.first_sequence)
+            .collect();
+        Ok(WalIter {
+            dir: self.dir.clone(),
+            reader: BufReader::new(file),
+            segments: remaining.into_iter(),
+            from: sequence,
+            end: self.next_sequence,
+            keyring: self.keyring.clone(),
+            cipher: FrameCipher::for_segment(
+                self.keyring.as_ref(),
+                segment.cipher,
+                segment.first_sequence,
+                segment.offset_for(sequence),
+            ),
+        })
+    }
+
+    /// Deletes every segment and starts over with an empty one at `next_sequence`, for a node
+    /// whose store was replaced rather than built from these records
+    pub fn reset(&mut self, next_sequence: Sequence) -> io::Result<()> {
+        for segment in self.segments.drain(..) {
+            fs::remove_file(segment_path(&self.dir, segment.first_sequence))?;
+            let _ = fs::remove_file(index_path(&self.dir, segment.first_sequence));
+        }
+        self.next_sequence = next_sequence;
+        self.start_segment()
+    }
+
+    /// Keeps the records from `sequence` on until the pin is dropped
+    pub fn pin(&self, sequence: Sequence) -> WalPin {
+        *self.pins.lock().unwrap().entry(sequence).or_insert(0) += 1;
+        WalPin {
+            pins: Arc::clone(&self.pins),
+            sequence,
+        }
+    }
+
+    /// Deletes every sealed segment whose records all have a sequence at or before `sequence`,
+    /// short of any a `WalPin` holds on to. Returns the number of segments deleted.
+    pub fn remove_segments_through(&mut self, sequence: Sequence) -> io::Result<usize> {
+        let sequence = match self.pins.lock().unwrap().keys().next() {
+            Some(pinned) => sequence.min(pinned.saturating_sub(1)),
+            None => sequence,
+        };
+        let mut removed = 0;
+        // The active segment is never removed
+        while self.segments.len() > 1 && self.segments[1].first_sequence <= sequence + 1 {
+            let segment = self.segments.remove(0);
+            fs::remove_file(segment_path(&self.dir, segment.first_sequence))?;
+            let _ = fs::remove_file(index_path(&self.dir, segment.first_sequence));
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+/// Deletes the WAL segments that the storage engine's own files already cover
+pub async fn prune(
+    store: &Mutex<Box<dyn StorageEngine>>,
+    wal: &Mutex<WriteAheadLog>,
+) -> io::Result<usize> {
+    let covered = store.lock().await.snapshot_sequence();
+    wal.lock().await.remove_segments_through(covered)
+}
+
+pub async fn run_pruner(
+    interval: Duration,
+    store: Arc<Mutex<Box<dyn StorageEngine>>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+) {
+    info!("Starting WAL pruner");
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match prune(&store, &wal).await {
+            Ok(0) => (),
+            Ok(n) => info!("Removed {} WAL segments", n),
+            Err(e) => error!("Failed to prune WAL: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a version 1 WAL as the first followers did: the `ReplicateSet`s they were sent,
+    /// each followed by the sequence after it
+    fn write_follower_log(path: &Path, first_sequence: Sequence, sets: &[message::Set]) {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[LEGACY_WAL_VERSION, PROTO_BUF_VERSION]);
+        bytes.extend_from_slice(&first_sequence.to_le_bytes());
+        for (i, set) in sets.iter().enumerate() {
+            let sequence = first_sequence + i as u64;
+            let replicate_set = message::ReplicateSet {
+                leader_addr: "127.0.0.1:7878".to_string(),
+                sequence,
+                set: Some(set.clone()),
+            };
+            bytes.extend_from_slice(&replicate_set.encode_length_delimited_to_vec());
+            bytes.extend_from_slice(&(sequence + 1).to_le_bytes());
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn converts_follower_legacy_log() {
+        let root = std::env::temp_dir().join(format!("blue-wal-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("wal.log");
+        let dir = root.join("wal");
+        let sets: Vec<message::Set> = [("a", "1"), ("b", "2"), ("a", "3")]
+            .iter()
+            .map(|(key, value)| message::Set {
+                key: key.as_bytes().to_vec(),
+                value: value.as_bytes().to_vec(),
+                write_to_wal: true,
+                ..Default::default()
+            })
+            .collect();
+        write_follower_log(&path, 1, &sets);
 
-        Ok(msgs)
+        let next_sequence = convert_legacy_log(&path, &dir, None).unwrap();
+        assert_eq!(next_sequence, 4);
+        let wal = WriteAheadLog::open(&dir, None).unwrap();
+        let items: Vec<WalItem> = wal
+            .iter_from(1)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let logged: Vec<(Sequence, message::Set)> = items
+            .into_iter()
+            .map(|(sequence, record)| match record.operation {
+                Some(Operation::Set(set)) => (sequence, set),
+                operation => panic!("Expected a set, got {:?}", operation),
+            })
+            .collect();
+        assert_eq!(
+            logged,
+            sets.into_iter()
+                .enumerate()
+                .map(|(i, set)| (i as u64 + 1, set))
+                .collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(&root).unwrap();
     }
 }
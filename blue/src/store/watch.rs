@@ -0,0 +1,147 @@
+use std::io;
+
+use log::{info, warn};
+use tokio::io::AsyncWrite;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use super::super::ipc::chunk::{async_send_chunks, take_large_value};
+use super::super::ipc::message;
+use super::super::ipc::message::wal_record::Operation;
+use super::super::ipc::message::{EventType, Status};
+use super::super::ipc::sender::async_send_message;
+use super::super::ipc::status;
+use super::wal::{WalItem, WriteAheadLog};
+
+fn matches(watch: &message::Watch, key: &[u8]) -> bool {
+    match watch.prefix {
+        true => key.starts_with(&watch.key),
+        false => key == watch.key,
+    }
+}
+
+pub fn to_event(item: &WalItem) -> Option<message::WatchEvent> {
+    let (sequence, record) = item;
+    let event = match record.operation.as_ref()? {
+        Operation::Set(set) => message::WatchEvent {
+            sequence: *sequence,
+            event_type: EventType::Set as i32,
+            key: set.key.clone(),
+            value: set.value.clone(),
+            expires_at: set.expires_at,
+            chunked: false,
+        },
+        Operation::Delete(delete) => message::WatchEvent {
+            sequence: *sequence,
+            event_type: EventType::Delete as i32,
+            key: delete.key.clone(),
+            ..Default::default()
+        },
+        Operation::Expire(expire) => message::WatchEvent {
+            sequence: *sequence,
+            event_type: EventType::Expire as i32,
+            key: expire.key.clone(),
+            expires_at: expire.expires_at,
+            ..Default::default()
+        },
+    };
+    Some(event)
+}
+
+/// Sends every item at or after `next_sequence` that the watch is interested in, advancing
+/// `next_sequence` past each item so nothing is delivered twice
+async fn send_events<W>(
+    stream: &mut W,
+    watch: &message::Watch,
+    items: &[WalItem],
+    next_sequence: &mut u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for item in items {
+        if item.0 < *next_sequence {
+            continue;
+        }
+        *next_sequence = item.0 + 1;
+        if let Some(mut event) = to_event(item) {
+            if matches(watch, &event.key) {
+                let value = take_large_value(&mut event.value);
+                event.chunked = value.is_some();
+                async_send_message(event, stream).await?;
+                if let Some(value) = value {
+                    async_send_chunks(&value, stream).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends the changes logged from `next_sequence` onwards, streaming them from the WAL
+async fn replay<W>(
+    stream: &mut W,
+    watch: &message::Watch,
+    wal: &Mutex<WriteAheadLog>,
+    next_sequence: &mut u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let items = wal.lock().await.iter_from(*next_sequence)?;
+    for item in items {
+        send_events(stream, watch, &[item?], next_sequence).await?;
+    }
+    Ok(())
+}
+
+/// Streams changes to the watched key(s) until the client goes away. The connection is dedicated
+/// to the watch once it starts.
+pub async fn watch_handler<W>(
+    stream: &mut W,
+    watch: message::Watch,
+    wal: &Mutex<WriteAheadLog>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    info!(
+        "Watching key={} prefix={}",
+        String::from_utf8_lossy(&watch.key),
+        watch.prefix
+    );
+    // Subscribe before reading history so no record falls between the two
+    let (mut events, mut next_sequence, first_sequence) = {
+        let wal = wal.lock().await;
+        let next_sequence = match watch.from_sequence {
+            0 => wal.next_sequence,
+            from => from,
+        };
+        (wal.subscribe(), next_sequence, wal.first_sequence())
+    };
+    if next_sequence < first_sequence {
+        let response = status::failure(
+            Status::OutOfRange,
+            format!(
+                "Sequence #{} has been removed from the WAL, which now starts at #{}",
+                next_sequence, first_sequence
+            ),
+        );
+        return async_send_message(response, stream).await;
+    }
+    let response = status::ok(format!("Watching from sequence #{}", next_sequence));
+    async_send_message(response, stream).await?;
+
+    replay(stream, &watch, wal, &mut next_sequence).await?;
+
+    loop {
+        match events.recv().await {
+            Ok(item) => send_events(stream, &watch, &[item], &mut next_sequence).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Watcher lagged by {} records, replaying from WAL", skipped);
+                replay(stream, &watch, wal, &mut next_sequence).await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}